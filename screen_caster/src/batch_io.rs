@@ -0,0 +1,99 @@
+use std::io;
+use std::net::SocketAddr;
+use crate::protocol;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+
+/// This module batches the datagrams sent and received on a UDP socket, to save system calls at high packet rates.
+/// On Linux a batch is sent with a single sendmmsg and received with a single recvmmsg, on the other systems
+/// the datagrams of a batch are sent and received one at a time by the callers.
+/// The server sends the datagrams of a client the pacer lets go together in a batch, the client receives all the datagrams waiting on its socket at once.
+
+/// Largest number of datagrams sent or received with a single system call.
+pub const MAX_BATCH: usize = 32;
+
+/// Send a batch of datagrams to the same address, returning how many have been sent, possibly fewer than given.
+#[cfg(target_os = "linux")]
+pub fn send_batch<S: AsRawFd>(socket: &S, datagrams: &[&[u8]], address: SocketAddr) -> io::Result<usize> {
+    let address = socket2::SockAddr::from(address);
+    let mut iovecs = datagrams.iter().map(|datagram| libc::iovec {
+        iov_base: datagram.as_ptr() as *mut libc::c_void,
+        iov_len: datagram.len(),
+    }).collect::<Vec<libc::iovec>>();
+    let mut messages = iovecs.iter_mut().map(|iovec| {
+        // SAFETY: mmsghdr is a plain C struct, for which all zeroes is a valid value
+        let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        message.msg_hdr.msg_name = address.as_ptr() as *mut libc::c_void;
+        message.msg_hdr.msg_namelen = address.len();
+        message.msg_hdr.msg_iov = iovec;
+        message.msg_hdr.msg_iovlen = 1;
+        message
+    }).collect::<Vec<libc::mmsghdr>>();
+    // SAFETY: the messages point to the address and to the datagrams, which outlive the call
+    let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), messages.len() as libc::c_uint, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// The RecvBatch keeps the buffers the datagrams of a batch are received into, reused across receptions.
+pub struct RecvBatch {
+    buffers: Vec<[u8; protocol::MAX_DATAGRAM_SIZE]>,
+    lengths: Vec<usize>,
+    received: usize,
+}
+
+impl RecvBatch {
+    pub fn new(size: usize) -> Self {
+        RecvBatch {
+            buffers: vec![[0; protocol::MAX_DATAGRAM_SIZE]; size.max(1)],
+            lengths: vec![0; size.max(1)],
+            received: 0,
+        }
+    }
+
+    /// Return the datagrams received by the last reception.
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.buffers.iter().zip(self.lengths.iter()).take(self.received).map(|(buffer, length)| &buffer[..*length])
+    }
+
+    /// Receive a single datagram with the given function, for the sockets that can't receive a batch.
+    pub fn recv_one(&mut self, recv: impl FnOnce(&mut [u8]) -> io::Result<usize>) -> io::Result<usize> {
+        self.received = 0;
+        self.lengths[0] = recv(&mut self.buffers[0])?;
+        self.received = 1;
+        Ok(1)
+    }
+
+    /// Receive the datagrams waiting on the socket, waiting for the first one at most for the read timeout of the socket.
+    /// Return how many have been received.
+    #[cfg(target_os = "linux")]
+    pub fn recv_from_socket<S: AsRawFd>(&mut self, socket: &S) -> io::Result<usize> {
+        self.received = 0;
+        let mut iovecs = self.buffers.iter_mut().map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        }).collect::<Vec<libc::iovec>>();
+        let mut messages = iovecs.iter_mut().map(|iovec| {
+            // SAFETY: mmsghdr is a plain C struct, for which all zeroes is a valid value
+            let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        }).collect::<Vec<libc::mmsghdr>>();
+        // MSG_WAITFORONE blocks only until the first datagram is received, then takes the ones already waiting
+        // SAFETY: the messages point to the buffers, which outlive the call
+        let received = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), messages.len() as libc::c_uint, libc::MSG_WAITFORONE, std::ptr::null_mut())
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (length, message) in self.lengths.iter_mut().zip(messages.iter()).take(received as usize) {
+            *length = message.msg_len as usize;
+        }
+        self.received = received as usize;
+        Ok(self.received)
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::broadcast::{self, error::{RecvError, TryRecvError}};
+
+/// This module contains the ClientQueue, the bounded queue between the fan-out of the StreamingServer and the task sending the stream to a client.
+/// The fan-out publishes every packet once on a broadcast channel, the Fanout, and the task of each client reads it through its own ClientQueue:
+/// the channel keeps the last queue_size packets, so a client that can't keep up lags behind and the OverflowPolicy is applied:
+/// - DropOldest: the oldest packets are dropped, the client goes on with the ones still in the channel
+/// - DropUntilKeyframe: the packets are dropped until the next keyframe, so that the client resumes with a decodable stream
+/// - Disconnect: the client is disconnected
+///
+/// The packets dropped are counted, to be shown in the roster, and the clients disconnected because of their queue are reported to the GUI
+/// like the ones evicted for missing their heartbeats. A slow client thus never slows down the others nor makes the server run out of memory.
+/// A queue can start with a backlog of packets sent before the ones of the channel, see the join_cache module.
+
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// OverflowPolicy enum used to decide what to do when the queue of a client is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropUntilKeyframe,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_until_keyframe" => Ok(OverflowPolicy::DropUntilKeyframe),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::DropOldest => write!(f, "drop_oldest"),
+            OverflowPolicy::DropUntilKeyframe => write!(f, "drop_until_keyframe"),
+            OverflowPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// QueuedPacket struct contains a data packet published by the fan-out, whether it contains the start of a keyframe and whether it carries the PAT.
+pub struct QueuedPacket {
+    pub packet: Vec<u8>,
+    pub keyframe: bool,
+    pub pat: bool,
+}
+
+/// Sending side of the broadcast channel the fan-out publishes the packets on.
+pub type Fanout = broadcast::Sender<Arc<QueuedPacket>>;
+
+/// Create the Fanout, keeping the given number of packets for the clients lagging behind.
+pub fn fanout(queue_size: usize) -> Fanout {
+    broadcast::channel(queue_size.max(1)).0
+}
+
+/// QueueEvent enum contains the result of waiting for the next packet of a ClientQueue.
+/// - Overflow: the queue is full and the policy is to disconnect the client
+/// - Closed: the fan-out has ended
+pub enum QueueEvent {
+    Packet(Arc<QueuedPacket>),
+    Overflow,
+    Closed,
+}
+
+/// QueueStats struct contains the counters of a ClientQueue, read by the server while the task of the client uses the queue.
+#[derive(Default)]
+pub struct QueueStats {
+    len: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl QueueStats {
+    /// Return the number of packets waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of packets dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ClientQueue {
+    rx: broadcast::Receiver<Arc<QueuedPacket>>,
+    backlog: VecDeque<Arc<QueuedPacket>>,
+    policy: OverflowPolicy,
+    waiting_keyframe: bool,
+    stats: Arc<QueueStats>,
+}
+
+impl ClientQueue {
+    /// Subscribe to the fan-out: the client receives the packets published from now on.
+    pub fn subscribe(fanout: &Fanout, policy: OverflowPolicy) -> Self {
+        ClientQueue {
+            rx: fanout.subscribe(),
+            backlog: VecDeque::new(),
+            policy,
+            waiting_keyframe: false,
+            stats: Arc::new(QueueStats::default()),
+        }
+    }
+
+    /// Return the queue with the given packets to be sent before the ones published on the fan-out.
+    pub fn with_backlog(mut self, packets: impl IntoIterator<Item = Arc<QueuedPacket>>) -> Self {
+        self.backlog.extend(packets);
+        self.stats.len.store(self.backlog.len(), Ordering::Relaxed);
+        self
+    }
+
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.stats.clone()
+    }
+
+    /// Wait for the next packet to send, applying the policy if the client lagged behind.
+    pub async fn next(&mut self) -> QueueEvent {
+        if let Some(event) = self.next_in_backlog() {
+            return event;
+        }
+        loop {
+            let received = self.rx.recv().await;
+            if let Some(event) = self.on_received(received) {
+                return event;
+            }
+        }
+    }
+
+    /// Return the next packet to send if one is already queued, without waiting, so that the packets queued can be sent together.
+    pub fn try_next(&mut self) -> Option<QueueEvent> {
+        if let Some(event) = self.next_in_backlog() {
+            return Some(event);
+        }
+        loop {
+            let received = match self.rx.try_recv() {
+                Ok(packet) => Ok(packet),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Lagged(dropped)) => Err(RecvError::Lagged(dropped)),
+                Err(TryRecvError::Closed) => Err(RecvError::Closed),
+            };
+            if let Some(event) = self.on_received(received) {
+                return Some(event);
+            }
+        }
+    }
+
+    // Take the next packet of the backlog, if any.
+    fn next_in_backlog(&mut self) -> Option<QueueEvent> {
+        let packet = self.backlog.pop_front()?;
+        self.stats.len.store(self.backlog.len() + self.rx.len(), Ordering::Relaxed);
+        Some(QueueEvent::Packet(packet))
+    }
+
+    // Apply the policy to what has been received from the channel, None if the packet has been dropped.
+    fn on_received(&mut self, received: Result<Arc<QueuedPacket>, RecvError>) -> Option<QueueEvent> {
+        match received {
+            Ok(packet) => {
+                self.stats.len.store(self.rx.len(), Ordering::Relaxed);
+                if self.waiting_keyframe && !packet.keyframe {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                self.waiting_keyframe = false;
+                Some(QueueEvent::Packet(packet))
+            }
+            Err(RecvError::Lagged(dropped)) => {
+                self.stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+                match self.policy {
+                    OverflowPolicy::DropOldest => None,
+                    OverflowPolicy::DropUntilKeyframe => {
+                        self.waiting_keyframe = true;
+                        None
+                    }
+                    OverflowPolicy::Disconnect => Some(QueueEvent::Overflow),
+                }
+            }
+            Err(RecvError::Closed) => Some(QueueEvent::Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE_SIZE: usize = 4;
+
+    // Publish the packets of the given numbers, the ones in keyframes starting a keyframe.
+    fn publish(fanout: &Fanout, packets: std::ops::Range<u8>, keyframes: &[u8]) {
+        for index in packets {
+            let _ = fanout.send(Arc::new(QueuedPacket { packet: vec![index], keyframe: keyframes.contains(&index), pat: false }));
+        }
+    }
+
+    // Return the numbers of the packets queued, None if the client has been disconnected.
+    fn drain(queue: &mut ClientQueue) -> Option<Vec<u8>> {
+        let mut received = Vec::new();
+        while let Some(event) = queue.try_next() {
+            match event {
+                QueueEvent::Packet(packet) => received.push(packet.packet[0]),
+                QueueEvent::Overflow | QueueEvent::Closed => return None,
+            }
+        }
+        Some(received)
+    }
+
+    #[test]
+    fn drop_oldest_goes_on_with_the_newest_packets() {
+        let fanout = fanout(QUEUE_SIZE);
+        let mut queue = ClientQueue::subscribe(&fanout, OverflowPolicy::DropOldest);
+        publish(&fanout, 0..10, &[]);
+        assert_eq!(queue.stats().len(), 0);
+        assert_eq!(drain(&mut queue), Some(vec![6, 7, 8, 9]));
+        assert_eq!(queue.stats().dropped(), 6);
+    }
+
+    #[test]
+    fn drop_until_keyframe_resumes_with_a_keyframe() {
+        let fanout = fanout(QUEUE_SIZE);
+        let mut queue = ClientQueue::subscribe(&fanout, OverflowPolicy::DropUntilKeyframe);
+        publish(&fanout, 0..10, &[0, 7]);
+        assert_eq!(drain(&mut queue), Some(vec![7, 8, 9]));
+        assert_eq!(queue.stats().dropped(), 7);
+        // Once resumed the packets are queued again
+        publish(&fanout, 10..12, &[]);
+        assert_eq!(drain(&mut queue), Some(vec![10, 11]));
+        assert_eq!(queue.stats().dropped(), 7);
+    }
+
+    #[test]
+    fn disconnect_ends_the_client_only() {
+        let fanout = fanout(QUEUE_SIZE);
+        let mut slow = ClientQueue::subscribe(&fanout, OverflowPolicy::Disconnect);
+        let mut fast = ClientQueue::subscribe(&fanout, OverflowPolicy::Disconnect);
+        let mut received = Vec::new();
+        for index in 0..10 {
+            publish(&fanout, index..index + 1, &[]);
+            received.extend(drain(&mut fast).unwrap());
+        }
+        assert_eq!(drain(&mut slow), None);
+        assert_eq!(slow.stats().dropped(), 6);
+        // The client keeping up gets every packet, and the fan-out goes on after the other one is gone
+        drop(slow);
+        publish(&fanout, 10..11, &[]);
+        received.extend(drain(&mut fast).unwrap());
+        assert_eq!(received, (0..11).collect::<Vec<u8>>());
+        assert_eq!(fast.stats().dropped(), 0);
+    }
+
+    #[test]
+    fn backlog_comes_before_the_packets_published() {
+        let fanout = fanout(QUEUE_SIZE);
+        let backlog = (0..3).map(|index| Arc::new(QueuedPacket { packet: vec![index], keyframe: index == 0, pat: index == 0 }));
+        let mut queue = ClientQueue::subscribe(&fanout, OverflowPolicy::DropUntilKeyframe).with_backlog(backlog);
+        assert_eq!(queue.stats().len(), 3);
+        publish(&fanout, 3..5, &[]);
+        assert_eq!(drain(&mut queue), Some(vec![0, 1, 2, 3, 4]));
+        assert_eq!(queue.stats().len(), 0);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use crate::protocol::{self, ControlMessage, PacketHeader, PacketType, ENCRYPTED_FLAG, HEADER_SIZE};
+
+/// This module implements the authenticated encryption of a PIN protected session.
+/// The 256 bit key is derived from the session PIN, or from the longer key the streamer may generate instead, with Argon2id,
+/// so that the streamer and the viewers share it without exchanging it on the network. The salt is drawn at random for every session
+/// and announced in clear in the "CASTING" reply to "PROBE" (see the probe module): a table of keys computed in advance is useless,
+/// and guessing the PIN of a session from the captured traffic costs a memory-hard derivation for every attempt.
+/// Every datagram is sealed with ChaCha20-Poly1305:
+/// the header stays in clear (with the ENCRYPTED_FLAG set in the type byte) and is authenticated as associated data,
+/// the payload is replaced by a random nonce followed by the ciphertext and the authentication tag.
+/// Control messages sent by the clients are framed with a control header and sealed as well, so that a client proves
+/// to know the PIN by sending a "START" request the server is able to open, and the PIN never crosses the network: the requests that
+/// can't be opened are answered with "REFUSED", while the client counts and drops the datagrams failing the authentication.
+/// Sealed control messages carry the id of their sender in the stream id field and a counter in the sequence field:
+/// the receiver keeps a window of the counters seen from every sender and drops the copies of a message replayed on the network.
+///
+/// ENCRYPTED DATAGRAM LAYOUT
+///
+/// +----------------------+---------+---------------------+---------+
+/// | header (type | 0x80) |  nonce  |     ciphertext      |   tag   |
+/// |       16 byte        | 12 byte | payload length byte | 16 byte |
+/// +----------------------+---------+---------------------+---------+
+
+pub const SALT_SIZE: usize = 16;
+// Argon2id cost: 19 MiB of memory and 2 passes, about 50 ms on a desktop machine
+const KEY_MEMORY_KIB: u32 = 19 * 1024;
+const KEY_PASSES: u32 = 2;
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Bytes added to a datagram when it is sealed.
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+/// Number of control messages a sender may get ahead of an older one still accepted, e.g. because it has been reordered.
+const REPLAY_WINDOW: u32 = 64;
+
+/// Salt of the key of a protected session.
+pub type Salt = [u8; SALT_SIZE];
+
+/// Draw the salt of a new session.
+pub fn new_salt() -> Salt {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Format a salt as hexadecimal digits, as announced in the "salt" parameter of "CASTING".
+pub fn format_salt(salt: &Salt) -> String {
+    salt.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Parse a salt announced by a server, returning None if it is not made up of SALT_SIZE bytes in hexadecimal digits.
+pub fn parse_salt(text: &str) -> Option<Salt> {
+    let text = text.trim();
+    if text.len() != 2 * SALT_SIZE || !text.is_ascii() {
+        return None;
+    }
+    let mut salt = [0u8; SALT_SIZE];
+    for (i, byte) in salt.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(salt)
+}
+
+// ReplayWindow struct contains the newest counter received from a sender and a bitmap of the REPLAY_WINDOW counters before it,
+// the bit i being set if the counter newest - i has been received.
+struct ReplayWindow {
+    newest: u32,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new(first: u32) -> Self {
+        ReplayWindow { newest: first, seen: 1 }
+    }
+
+    // Record a counter, returning false if it has already been received or is too old to tell.
+    fn accept(&mut self, counter: u32) -> bool {
+        if protocol::sequence_before(self.newest, counter) {
+            let ahead = counter.wrapping_sub(self.newest);
+            self.seen = if ahead < REPLAY_WINDOW { (self.seen << ahead) | 1 } else { 1 };
+            self.newest = counter;
+            return true;
+        }
+        let behind = self.newest.wrapping_sub(counter);
+        if behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// SessionCipher struct contains the cipher initialized with the key derived from the session PIN and salt,
+/// the counter numbering the control messages it seals and the windows of the control messages it opened, by sender.
+pub struct SessionCipher {
+    cipher: ChaCha20Poly1305,
+    salt: Salt,
+    sender_id: u32,
+    sent: AtomicU32,
+    received: Mutex<HashMap<u32, ReplayWindow>>,
+}
+
+impl SessionCipher {
+    pub fn new(pin: &str, salt: &Salt) -> Self {
+        let params = Params::new(KEY_MEMORY_KIB, KEY_PASSES, 1, Some(KEY_SIZE)).expect("Invalid key derivation parameters");
+        let mut key = [0u8; KEY_SIZE];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(pin.as_bytes(), salt, &mut key)
+            .expect("Failed to derive the session key");
+        SessionCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            salt: *salt,
+            sender_id: protocol::new_stream_id(),
+            sent: AtomicU32::new(0),
+            received: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn salt(&self) -> &Salt {
+        &self.salt
+    }
+
+    /// Encrypt the payload of a framed datagram, authenticating its header.
+    pub fn seal(&self, packet: &[u8]) -> Vec<u8> {
+        let mut header = packet[..HEADER_SIZE].to_vec();
+        header[3] |= ENCRYPTED_FLAG;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher
+            .encrypt(&nonce, Payload { msg: &packet[HEADER_SIZE..], aad: &header })
+            .expect("Failed to encrypt the packet");
+
+        let mut sealed = header;
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt an encrypted datagram, returning it as if it had been sent in clear.
+    /// Returns None if the datagram is not encrypted or fails the authentication, e.g. because it was sealed with another PIN.
+    pub fn open(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if !protocol::is_encrypted(packet) || packet.len() < HEADER_SIZE + NONCE_SIZE {
+            return None;
+        }
+        let header = &packet[..HEADER_SIZE];
+        let nonce = Nonce::from_slice(&packet[HEADER_SIZE..HEADER_SIZE + NONCE_SIZE]);
+        let plaintext = self.cipher
+            .decrypt(nonce, Payload { msg: &packet[HEADER_SIZE + NONCE_SIZE..], aad: header })
+            .ok()?;
+
+        let mut opened = header.to_vec();
+        opened[3] &= !ENCRYPTED_FLAG;
+        opened.extend_from_slice(&plaintext);
+        Some(opened)
+    }
+
+    /// Seal a control message sent by the given sender, numbering it with the next value of the counter.
+    pub fn seal_control(&self, sender: u32, message: &ControlMessage) -> Vec<u8> {
+        let counter = self.sent.fetch_add(1, Ordering::Relaxed);
+        self.seal(&PacketHeader::new(PacketType::Control, sender, counter, 0).encode(message.to_text().as_bytes()))
+    }
+
+    /// Tell whether an opened control message is received for the first time, recording it.
+    /// The first message of a sender opens its window, so the windows are only created by messages sealed with the session key.
+    pub fn is_fresh(&self, header: &PacketHeader) -> bool {
+        let mut received = self.received.lock().unwrap();
+        match received.get_mut(&header.stream_id) {
+            Some(window) => window.accept(header.sequence),
+            None => {
+                received.insert(header.stream_id, ReplayWindow::new(header.sequence));
+                true
+            }
+        }
+    }
+
+    // Open a sealed control datagram, returning its header and message, unless it is a copy of one already received.
+    fn open_control(&self, packet: &[u8]) -> Result<(PacketHeader, ControlMessage), Rejection> {
+        let opened = self.open(packet).ok_or(Rejection::Unauthenticated)?;
+        let (header, _) = PacketHeader::decode(&opened).ok_or(Rejection::Unauthenticated)?;
+        let (_, message) = protocol::decode_control(&opened).ok_or(Rejection::Unauthenticated)?;
+        if !self.is_fresh(&header) {
+            return Err(Rejection::Replayed);
+        }
+        Ok((header, message))
+    }
+}
+
+/// Rejection enum tells why a control message received in a protected session has been dropped.
+/// - Unauthenticated: the message is not sealed with the session key, or is sealed in an open session
+/// - Replayed: the message has already been received, it is a copy sent again by someone on the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Unauthenticated,
+    Replayed,
+}
+
+/// Seal a datagram if the session is encrypted, otherwise return it as it is.
+pub fn seal_packet(packet: Vec<u8>, cipher: Option<&SessionCipher>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal(&packet),
+        None => packet,
+    }
+}
+
+/// Build a request sent by a client to the server: plain text in an open session, a sealed control datagram in a protected one.
+/// Every sealed request is numbered on its own, so a request sent again has to be built again.
+pub fn encode_request(message: &ControlMessage, cipher: Option<&SessionCipher>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal_control(cipher.sender_id, message),
+        None => message.to_text().into_bytes(),
+    }
+}
+
+/// Parse a request received by the server. In a protected session only the requests sealed with the session key and received
+/// for the first time are accepted, in an open session only the plain text ones.
+pub fn decode_request(data: &[u8], cipher: Option<&SessionCipher>) -> Result<ControlMessage, Rejection> {
+    match cipher {
+        Some(cipher) => cipher.open_control(data).map(|(_, message)| message),
+        None if protocol::is_encrypted(data) => Err(Rejection::Unauthenticated),
+        None => Ok(ControlMessage::parse(&String::from_utf8_lossy(data))),
+    }
+}
+
+/// Build a control datagram sent by the server to a client, sealed and numbered in a protected session.
+pub fn encode_reply(stream_id: u32, message: &ControlMessage, cipher: Option<&SessionCipher>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal_control(stream_id, message),
+        None => protocol::encode_control(stream_id, message),
+    }
+}
+
+/// Parse a control datagram received by a client. In a protected session the replies have to be sealed with the session key
+/// and received for the first time: a "REFUSED" in clear, which the server sends to the clients that failed the authentication,
+/// could be sent by anyone on the network, so it is not trusted here, see refusal_hint.
+pub fn decode_reply(packet: &[u8], cipher: Option<&SessionCipher>) -> Option<(u32, ControlMessage)> {
+    match cipher {
+        Some(cipher) => cipher.open_control(packet).ok().map(|(header, message)| (header.stream_id, message)),
+        None => protocol::decode_control(packet),
+    }
+}
+
+/// Return the reason of a "REFUSED" received in clear in a protected session. It does not end the handshake, since it can be forged,
+/// but it tells the client why no sealed reply came, e.g. a wrong PIN.
+pub fn refusal_hint(packet: &[u8]) -> Option<String> {
+    protocol::decode_control(packet)
+        .filter(|(_, message)| message.command == "REFUSED")
+        .and_then(|(_, message)| message.param("reason").map(|reason| reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replayed_requests_are_dropped() {
+        let salt = new_salt();
+        let client = SessionCipher::new("123456", &salt);
+        let server = SessionCipher::new("123456", &salt);
+        let request = encode_request(&ControlMessage::new("STOP"), Some(&client));
+
+        assert_eq!(decode_request(&request, Some(&server)).map(|message| message.command), Ok("STOP".to_string()));
+        assert_eq!(decode_request(&request, Some(&server)), Err(Rejection::Replayed));
+        // A new request is numbered on its own
+        assert!(decode_request(&encode_request(&ControlMessage::new("STOP"), Some(&client)), Some(&server)).is_ok());
+    }
+
+    #[test]
+    fn replay_window_accepts_reordered_counters_once() {
+        let mut window = ReplayWindow::new(u32::MAX - 1);
+        assert!(window.accept(2));
+        assert!(window.accept(u32::MAX));
+        assert!(!window.accept(u32::MAX));
+        assert!(window.accept(0));
+        assert!(!window.accept(u32::MAX - 1));
+        assert!(window.accept(2 + REPLAY_WINDOW));
+        // Too old to tell whether it has been received
+        assert!(!window.accept(1));
+    }
+
+    #[test]
+    fn sessions_with_other_salts_do_not_share_keys() {
+        let client = SessionCipher::new("123456", &new_salt());
+        let server = SessionCipher::new("123456", &new_salt());
+        let request = encode_request(&ControlMessage::new("START"), Some(&client));
+        assert_eq!(decode_request(&request, Some(&server)), Err(Rejection::Unauthenticated));
+    }
+
+    #[test]
+    fn refusals_in_clear_are_not_trusted_in_a_protected_session() {
+        let cipher = SessionCipher::new("123456", &new_salt());
+        let refusal = protocol::encode_control(7, &ControlMessage::new("REFUSED").with_param("reason", "pin"));
+        assert!(decode_reply(&refusal, Some(&cipher)).is_none());
+        assert_eq!(refusal_hint(&refusal).as_deref(), Some("pin"));
+
+        let sealed = encode_reply(7, &ControlMessage::new("REFUSED").with_param("reason", "banned"), Some(&cipher));
+        assert_eq!(decode_reply(&sealed, Some(&cipher)).map(|(_, message)| message.command), Some("REFUSED".to_string()));
+    }
+
+    #[test]
+    fn salts_round_trip() {
+        let salt = new_salt();
+        assert_eq!(parse_salt(&format_salt(&salt)), Some(salt));
+        assert_eq!(parse_salt("00ff"), None);
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::protocol::{self, ControlMessage};
+use crate::utils::{self, NetworkConfig};
+
+/// This module implements the discovery of the casting sessions active on the LAN.
+/// While casting, the StreamingServer runs a BeaconSender which broadcasts a "BEACON" control datagram every BEACON_INTERVAL
+/// to the discovery port, over IPv4 and to the all-nodes IPv6 group (ff02::1), announcing the name of the machine, the port the server listens on, the resolution of the stream
+/// and whether a PIN is required:
+///
+/// BEACON
+/// name=workstation
+/// pin=true
+/// port=8080
+/// resolution=1920x1080
+///
+/// The connect screen runs a DiscoveryListener, which collects the beacons and lists the sessions heard in the last SESSION_TIMEOUT.
+/// A server bound to an address of a single family only announces itself over that family, while a dual-stack one may be listed twice,
+/// once per address the beacons came from.
+/// Beacons are never encrypted, since they have to be readable before knowing the PIN.
+/// When the server is bound to a loopback address the beacons are sent to the same address instead of being broadcast,
+/// so that the discovery can be tried with two instances on the same machine.
+/// The discovery port is bound with SO_REUSEADDR (and SO_REUSEPORT where it exists), once per family, so that more instances on the same machine
+/// can listen at once: each of them gets the broadcast beacons, while a beacon sent to the loopback address reaches only one.
+/// Either family may be missing on the host: the sender and the listener work as long as one of them can be used.
+
+/// Interval between two beacons sent by the server.
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+/// Time after which a session that stopped sending beacons is removed from the list.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(3);
+// Group the beacons are sent to over IPv6, the link-local all-nodes group
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, (DiscoveredSession, Instant)>>>;
+
+/// DiscoveredSession struct contains the information announced by a streamer in its beacons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredSession {
+    pub name: String,
+    pub address: SocketAddr,
+    pub resolution: String,
+    pub pin_required: bool,
+}
+
+/// The BeaconSender announces a casting session until it is stopped.
+pub struct BeaconSender {
+    running: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl BeaconSender {
+    /// Start announcing the session, failing only if the beacons can't be sent over any family.
+    pub fn start(config: &NetworkConfig, stream_id: u32, resolution: Option<(u32, u32)>, pin_required: bool) -> io::Result<Self> {
+        let broadcast = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), config.discovery_port);
+        let all_nodes = SocketAddr::new(IpAddr::V6(ALL_NODES), config.discovery_port);
+        let destinations = match config.bind_address {
+            Some(ip) if ip.is_loopback() => vec![SocketAddr::new(ip, config.discovery_port)],
+            Some(IpAddr::V4(_)) => vec![broadcast],
+            Some(IpAddr::V6(_)) => vec![all_nodes],
+            None => vec![broadcast, all_nodes],
+        };
+        let mut sockets = Vec::new();
+        let mut error = None;
+        for destination in destinations {
+            match beacon_socket(config, destination) {
+                Ok(socket) => sockets.push((socket, destination)),
+                Err(err) => error = Some(err),
+            }
+        }
+        if sockets.is_empty() {
+            return Err(error.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)));
+        }
+
+        let beacon = ControlMessage::new("BEACON")
+            .with_param("name", utils::host_name())
+            .with_param("port", config.server_port)
+            .with_param("resolution", resolution.map_or("?".to_string(), |(width, height)| format!("{width}x{height}")))
+            .with_param("pin", pin_required);
+        let packet = protocol::encode_control(stream_id, &beacon);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        let handle = thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                for (socket, destination) in &sockets {
+                    let _ = socket.send_to(&packet, destination);
+                }
+                thread::sleep(BEACON_INTERVAL);
+            }
+        });
+
+        Ok(BeaconSender {
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn stop(mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+/// The DiscoveryListener collects the beacons received on the discovery port until it is dropped.
+pub struct DiscoveryListener {
+    sessions: Sessions,
+    running: Arc<AtomicBool>,
+}
+
+impl DiscoveryListener {
+    /// Start listening on the discovery port over IPv4 and IPv6, failing only if neither can be bound.
+    pub fn start(config: &NetworkConfig) -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        let mut error = None;
+        for ip in [IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)] {
+            match bind_listener(ip, config.discovery_port) {
+                Ok(socket) => sockets.push(socket),
+                Err(err) => error = Some(err),
+            }
+        }
+        if sockets.is_empty() {
+            return Err(error.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)));
+        }
+
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        for socket in sockets {
+            socket.set_read_timeout(Some(BEACON_INTERVAL))?;
+            let sessions_clone = sessions.clone();
+            let running_clone = running.clone();
+            thread::spawn(move || DiscoveryListener::listen(socket, sessions_clone, running_clone));
+        }
+
+        Ok(DiscoveryListener {
+            sessions,
+            running,
+        })
+    }
+
+    /// Return the sessions heard in the last SESSION_TIMEOUT, sorted by name.
+    pub fn sessions(&self) -> Vec<DiscoveredSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, last_seen)| last_seen.elapsed() < SESSION_TIMEOUT);
+        let mut list = sessions.values().map(|(session, _)| session.clone()).collect::<Vec<DiscoveredSession>>();
+        list.sort_by(|a, b| a.name.cmp(&b.name).then(a.address.cmp(&b.address)));
+        list
+    }
+
+    // Collect the beacons received on a socket until the listener is dropped.
+    fn listen(socket: UdpSocket, sessions: Sessions, running: Arc<AtomicBool>) {
+        let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+        while running.load(Ordering::Relaxed) {
+            let (bytes_received, source) = match socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(_) => continue,
+            };
+            let message = match protocol::decode_control(&buffer[..bytes_received]) {
+                Some((_, message)) if message.command == "BEACON" => message,
+                _ => continue,
+            };
+            let port = match message.param("port").and_then(|port| port.parse::<u16>().ok()) {
+                Some(port) => port,
+                None => continue,
+            };
+
+            // The source keeps its scope id, which a link-local IPv6 address can't be reached without
+            let mut address = source;
+            address.set_port(port);
+            let session = DiscoveredSession {
+                name: message.param("name").unwrap_or("?").to_string(),
+                address,
+                resolution: message.param("resolution").unwrap_or("?").to_string(),
+                pin_required: message.param("pin") == Some("true"),
+            };
+            sessions.lock().unwrap().insert(session.address, (session, Instant::now()));
+        }
+    }
+}
+
+impl Drop for DiscoveryListener {
+    fn drop(&mut self) {
+        // The threads exit at the next read timeout, releasing the discovery port
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+// Open the socket sending the beacons to the given destination, bound to the configured address of its family if any.
+fn beacon_socket(config: &NetworkConfig, destination: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::new(config.bind_ip_for(destination.ip()), 0))?;
+    if destination.ip() == IpAddr::V4(Ipv4Addr::BROADCAST) {
+        socket.set_broadcast(true)?;
+    }
+    Ok(socket)
+}
+
+// Bind the socket receiving the beacons of a family, given by its unspecified address, sharing the discovery port
+// with the other instances running on the same machine.
+fn bind_listener(ip: IpAddr, port: u16) -> io::Result<UdpSocket> {
+    let address = SocketAddr::new(ip, port);
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+    if ip.is_ipv6() {
+        // IPv4 beacons are received by the IPv4 socket
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    // Broadcast and multicast datagrams are only delivered to sockets bound to the unspecified address
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listeners_share_the_discovery_port() {
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let first = bind_listener(unspecified, 0).unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(bind_listener(unspecified, port).is_ok());
+        // The IPv6 socket does not take the IPv4 port
+        if let Ok(_first_v6) = bind_listener(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port) {
+            assert!(bind_listener(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).is_ok());
+        }
+    }
+
+    #[test]
+    fn beacons_are_heard_over_ipv6() {
+        let port = bind_listener(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).unwrap().local_addr().unwrap().port();
+        let config = NetworkConfig { bind_address: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)), discovery_port: port, ..utils::read_network_config() };
+        let listener = DiscoveryListener::start(&config).unwrap();
+        let sender = BeaconSender::start(&config, 1, Some((1280, 720)), false).unwrap();
+
+        let start = Instant::now();
+        while listener.sessions().is_empty() && start.elapsed() < 2 * BEACON_INTERVAL {
+            thread::sleep(Duration::from_millis(20));
+        }
+        sender.stop();
+        let sessions = listener.sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].address, SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), config.server_port));
+        assert_eq!(sessions[0].resolution, "1280x720");
+    }
+}
@@ -20,17 +20,41 @@ pub enum InputError {
     #[error("The provided value is not a valid name.")]
     NotAName,
 
-    #[error("The provided value is not a valid IP address.")]
+    #[error("The provided value is not a valid IP address or host name.")]
     NotAnIp,
 
-    #[error("The provided ip value is not in the same LAN.")]
-    NotInSameLan,
+    #[error("The provided address is neither in the LAN nor in an allowed subnet.")]
+    NotInAllowedSubnet,
+
+    #[error("The provided host name could not be resolved.")]
+    Unresolved,
+
+    #[error("The host could not be reached.")]
+    Unreachable,
+
+    #[error("The host refused the connection.")]
+    Refused,
+
+    #[error("The host is not casting.")]
+    NotCasting,
 
     #[error("The provided value has multiple matches.")]
     MultipleMatches,
 
     #[error("No value provided.")]
     NoValue,
+
+    #[error("The provided PIN is wrong.")]
+    WrongPin,
+
+    #[error("The session is not protected by a PIN.")]
+    NotProtected,
+
+    #[error("The host runs an incompatible version.")]
+    IncompatibleVersion,
+
+    #[error("The stream of the host can't be played.")]
+    UnsupportedStream,
 }
 
 pub trait Banner<'a> {
@@ -41,33 +65,24 @@ pub trait Banner<'a> {
         content: Column<'a, Self::ExtMessage>,
         close_message: Self::ExtMessage,
     ) -> Element<'a, Self::ExtMessage> {
-        let error_text;
-        match message{
-            InputError::NameAlreadyPresent => {
-                error_text = "Name is already present";
-            },
-            InputError::IpAlreadyPresent => {
-                error_text = "Ip is already present";
-            },
-            InputError::IdNotFound => {
-                error_text = "Id not found";
-            },
-            InputError::NotAName => {
-                error_text = "Not a name";
-            },
-            InputError::NotAnIp => {
-                error_text = "Not an IP address";
-            },
-            InputError::NotInSameLan => {
-                error_text = "Not in the same LAN";
-            },
-            InputError::MultipleMatches => {
-                error_text = "Multiple matches";
-            },
-            InputError::NoValue => {
-                error_text = "No value provided";
-            },
-        }
+        let error_text = match message {
+            InputError::NameAlreadyPresent => "Name is already present",
+            InputError::IpAlreadyPresent => "Ip is already present",
+            InputError::IdNotFound => "Id not found",
+            InputError::NotAName => "Not a name",
+            InputError::NotAnIp => "Not an IP address or host name",
+            InputError::NotInAllowedSubnet => "Not in the LAN nor in an allowed subnet",
+            InputError::Unresolved => "Host name not found",
+            InputError::Unreachable => "Host unreachable",
+            InputError::Refused => "Connection refused by the host",
+            InputError::NotCasting => "The host is not casting",
+            InputError::MultipleMatches => "Multiple matches",
+            InputError::NoValue => "No value provided",
+            InputError::WrongPin => "Wrong PIN",
+            InputError::NotProtected => "The session is not protected by a PIN",
+            InputError::IncompatibleVersion => "The host runs an incompatible version of the application",
+            InputError::UnsupportedStream => "The stream of the host can't be played",
+        };
         let overlay = Container::new(
             Row::new()
                 .spacing(10)
@@ -109,8 +124,8 @@ impl iced::widget::container::StyleSheet for BannerStyle {
         }
     }
 }
-impl Into<Box<dyn iced::widget::container::StyleSheet<Style=Theme>>> for BannerStyle {
-    fn into(self) -> Box<dyn iced::widget::container::StyleSheet<Style=Theme>> {
-        Box::new(BannerStyle)
+impl From<BannerStyle> for Box<dyn iced::widget::container::StyleSheet<Style=Theme>> {
+    fn from(style: BannerStyle) -> Self {
+        Box::new(style)
     }
 }
\ No newline at end of file
@@ -0,0 +1,176 @@
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::crypto;
+use crate::protocol::{self, ControlMessage, PacketHeader, PacketType};
+use crate::streaming_server::StreamingServer;
+use crate::utils;
+use crate::rate_control;
+use crate::rtp::{TS_PACKET_SIZE, TS_SYNC_BYTE};
+use crate::session::{self, SessionDescriptor};
+
+/// This module contains a benchmark of the fan-out of the StreamingServer, run with "screen_caster --bench-fanout [clients] [seconds] [chunks per second]".
+/// A relay session is fed with synthetic chunks at a fixed rate, as if they were received from an upstream server,
+/// and serves them to the given number of clients on loopback (BENCH_CLIENTS by default). Each client sends "START" and the heartbeats
+/// like a StreamingClient and receives the data packets: each chunk is made of TS packets, and the time it has been produced
+/// is written after the header of the first one to measure the latency.
+/// At the end the packets delivered, the latency percentiles, the threads of the process and the CPU time used are printed.
+/// The server binds to the loopback address unless another one is configured with SCREEN_CASTER_BIND: the clients reach it on the loopback
+/// address of its family, so that IPv6 and dual-stack sessions can be measured too.
+
+pub const BENCH_FLAG: &str = "--bench-fanout";
+const BENCH_CLIENTS: usize = 50;
+const BENCH_SECONDS: u64 = 10;
+// 500 chunks of 7 TS packets per second, about 5 Mbit/s like the best target of the encoder
+const BENCH_CHUNK_RATE: u64 = 500;
+const CHUNK_SIZE: usize = 7 * TS_PACKET_SIZE;
+// Offset of the time a chunk has been produced, after the header of its first TS packet
+const TIMESTAMP_OFFSET: usize = 4;
+// Time given to the last packets to reach the clients once the feed has ended
+const DRAIN_TIME: Duration = Duration::from_millis(500);
+// Clock ticks per second of the CPU times in /proc/self/stat
+const CLOCK_TICKS: f64 = 100.0;
+
+// Reception counters of a benchmark client.
+struct ClientResult {
+    received: u64,
+    latencies_us: Vec<u64>,
+}
+
+/// Run the benchmark with the command line arguments following BENCH_FLAG.
+pub fn run(args: &[String]) {
+    let clients = args.first().and_then(|arg| arg.parse().ok()).unwrap_or(BENCH_CLIENTS);
+    let seconds = args.get(1).and_then(|arg| arg.parse().ok()).unwrap_or(BENCH_SECONDS);
+    let chunk_rate = args.get(2).and_then(|arg| arg.parse().ok()).filter(|rate| *rate > 0).unwrap_or(BENCH_CHUNK_RATE);
+    if env::var("SCREEN_CASTER_BIND").is_err() {
+        env::set_var("SCREEN_CASTER_BIND", "127.0.0.1");
+    }
+    let config = utils::read_network_config();
+    let server_ip = match config.bind_ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let server_address = SocketAddr::new(server_ip, config.server_port);
+
+    let (upstream_tx, upstream_rx) = channel::<Vec<u8>>();
+    let mut server = StreamingServer::new();
+    server.start_relay(vec![upstream_rx], SessionDescriptor::new(utils::host_name(), None, rate_control::TARGET_LADDER[0].framerate));
+
+    let epoch = Instant::now();
+    let feed_duration = Duration::from_secs(seconds);
+    let deadline = feed_duration + DRAIN_TIME + Duration::from_secs(1);
+    // The clients and the feeder wait for each other, so that all the clients are connected before the first chunk
+    let ready = Arc::new(Barrier::new(clients + 1));
+    let handles = (0..clients).map(|_| {
+        let ready = ready.clone();
+        thread::spawn(move || bench_client(server_address, epoch, deadline, ready))
+    }).collect::<Vec<_>>();
+    ready.wait();
+
+    let (cpu_before, _) = process_usage();
+    let feed_start = Instant::now();
+    let mut sent = 0u64;
+    let mut threads_during = 0;
+    while feed_start.elapsed() < feed_duration {
+        let mut chunk = vec![0xA5; CHUNK_SIZE];
+        for packet in chunk.chunks_mut(TS_PACKET_SIZE) {
+            packet[..TIMESTAMP_OFFSET].copy_from_slice(&[TS_SYNC_BYTE, 0x01, 0x00, 0x10]);
+        }
+        chunk[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 8].copy_from_slice(&(epoch.elapsed().as_micros() as u64).to_be_bytes());
+        if upstream_tx.send(chunk).is_err() {
+            break;
+        }
+        sent += 1;
+        if sent == chunk_rate {
+            threads_during = process_usage().1;
+        }
+        let next = Duration::from_micros(sent * 1_000_000 / chunk_rate);
+        if let Some(wait) = next.checked_sub(feed_start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+    thread::sleep(DRAIN_TIME);
+    let (cpu_after, _) = process_usage();
+    let elapsed = feed_start.elapsed();
+
+    let results = handles.into_iter().filter_map(|h| h.join().ok()).collect::<Vec<ClientResult>>();
+    drop(upstream_tx);
+    server.stop();
+
+    let received = results.iter().map(|result| result.received).sum::<u64>();
+    let expected = sent * clients as u64;
+    let mut latencies = results.into_iter().flat_map(|result| result.latencies_us).collect::<Vec<u64>>();
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies.get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1))).copied().unwrap_or(0);
+
+    println!("Fan-out a {clients} client per {seconds} s, {chunk_rate} chunk/s da {CHUNK_SIZE} byte");
+    println!("Pacchetti consegnati: {received} / {expected} ({:.2}%)", received as f64 * 100.0 / expected.max(1) as f64);
+    println!("Latenza: p50 {} us, p99 {} us, max {} us", percentile(0.5), percentile(0.99), latencies.last().copied().unwrap_or(0));
+    println!("Thread del processo durante il cast: {threads_during}");
+    println!("Tempo CPU: {:.2} s su {:.2} s ({:.1}% di un core)", cpu_after - cpu_before, elapsed.as_secs_f64(),
+        (cpu_after - cpu_before) * 100.0 / elapsed.as_secs_f64());
+}
+
+// Connect to the server like a StreamingClient with an open session and no FEC, then receive until the deadline.
+fn bench_client(server_address: SocketAddr, epoch: Instant, deadline: Duration, ready: Arc<Barrier>) -> ClientResult {
+    let socket = UdpSocket::bind(SocketAddr::new(server_address.ip(), 0)).expect("Impossibile aprire il socket del client");
+    let _ = socket.set_read_timeout(Some(Duration::from_millis(100)));
+    let port = socket.local_addr().map(|address| address.port()).unwrap_or(0);
+    let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+
+    let start = crypto::encode_request(&ControlMessage::new("START").with_param("fec", 0).with_param("port", port).with_param("version", session::SESSION_VERSION), None);
+    let mut connected = false;
+    for _ in 0..50 {
+        let _ = socket.send_to(&start, server_address);
+        if let Ok((n, _)) = socket.recv_from(&mut buffer) {
+            if crypto::decode_reply(&buffer[..n], None).is_some_and(|(_, reply)| reply.command == "OK") {
+                connected = true;
+                break;
+            }
+        }
+    }
+    ready.wait();
+
+    let mut result = ClientResult { received: 0, latencies_us: Vec::new() };
+    let heartbeat = crypto::encode_request(&ControlMessage::new("HEARTBEAT"), None);
+    let mut last_heartbeat = Instant::now();
+    while connected && epoch.elapsed() < deadline {
+        if last_heartbeat.elapsed() >= protocol::HEARTBEAT_INTERVAL {
+            let _ = socket.send_to(&heartbeat, server_address);
+            last_heartbeat = Instant::now();
+        }
+        let n = match socket.recv_from(&mut buffer) {
+            Ok((n, _)) => n,
+            Err(_) => continue,
+        };
+        if let Some((header, payload)) = PacketHeader::decode(&buffer[..n]) {
+            if header.packet_type == PacketType::Data && payload.len() >= TIMESTAMP_OFFSET + 8 {
+                let produced = u64::from_be_bytes(payload[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 8].try_into().unwrap());
+                result.latencies_us.push((epoch.elapsed().as_micros() as u64).saturating_sub(produced));
+                result.received += 1;
+            }
+        }
+    }
+
+    let stop = crypto::encode_request(&ControlMessage::new("STOP").with_param("port", port), None);
+    let _ = socket.send_to(&stop, server_address);
+    result
+}
+
+/// Return the CPU time used by the process so far, in seconds, and its number of threads. Only available on Linux.
+pub fn process_usage() -> (f64, usize) {
+    let cpu = fs::read_to_string("/proc/self/stat").ok().and_then(|stat| {
+        // The fields after the name of the process, which is in parentheses, are separated by spaces: utime and stime are the 12th and 13th
+        let fields = stat.rsplit_once(')')?.1.split_whitespace().map(String::from).collect::<Vec<String>>();
+        Some((fields.get(11)?.parse::<f64>().ok()? + fields.get(12)?.parse::<f64>().ok()?) / CLOCK_TICKS)
+    }).unwrap_or(0.0);
+    let threads = fs::read_to_string("/proc/self/status").ok().and_then(|status| {
+        status.lines().find_map(|line| line.strip_prefix("Threads:")).and_then(|threads| threads.trim().parse().ok())
+    }).unwrap_or(0);
+    (cpu, threads)
+}
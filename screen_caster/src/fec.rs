@@ -0,0 +1,264 @@
+use std::collections::{HashMap, VecDeque};
+use crate::protocol::sequence_before;
+
+/// This module implements the forward error correction (FEC) layer of the video stream.
+/// The server groups consecutive data packets and, after every group, sends a parity packet containing the XOR
+/// of their payloads. The client keeps the recently received payloads and, when a parity packet arrives and exactly
+/// one packet of its group is missing, rebuilds the missing payload and hands it to the SequenceTracker.
+/// The size of the groups is negotiated during the START handshake: the client asks for a group size with the
+/// "fec" parameter and the server replies with the accepted one, 0 meaning that FEC is disabled.
+/// Each client task of the StreamingServer encodes the groups of its own client; the clients connected with TCP never ask for FEC.
+///
+/// PARITY PAYLOAD LAYOUT (the sequence number in the header is the one of the first packet of the group)
+///
+/// +-------------+---------------------+------------------------------+
+/// | group count | XOR of the lengths  | XOR of the payloads (padded) |
+/// |   1 byte    |       2 byte        |      max payload length      |
+/// +-------------+---------------------+------------------------------+
+
+pub const DEFAULT_FEC_GROUP: u8 = 8;
+pub const MIN_FEC_GROUP: u8 = 2;
+pub const MAX_FEC_GROUP: u8 = 32;
+/// Bytes a parity payload carries before the XOR of the payloads of its group.
+pub const PARITY_HEADER_SIZE: usize = 3;
+
+/// Number of data payloads kept by the FecDecoder to rebuild missing packets.
+const DECODER_HISTORY: usize = 256;
+
+/// Return the group size accepted by the server for the one requested by a client.
+/// A missing, invalid or zero request disables FEC, otherwise the request is clamped to the supported range.
+pub fn negotiate_group(requested: Option<&str>) -> u8 {
+    match requested.and_then(|value| value.parse::<u8>().ok()) {
+        Some(0) | None => 0,
+        Some(group) => group.clamp(MIN_FEC_GROUP, MAX_FEC_GROUP),
+    }
+}
+
+/// XOR the source bytes into the destination, growing the destination if needed.
+fn xor_into(destination: &mut Vec<u8>, source: &[u8]) {
+    if destination.len() < source.len() {
+        destination.resize(source.len(), 0);
+    }
+    for (d, s) in destination.iter_mut().zip(source) {
+        *d ^= s;
+    }
+}
+
+/// The FecEncoder is used by the server to compute a parity packet every group_size data packets.
+pub struct FecEncoder {
+    group_size: u8,
+    first_sequence: u32,
+    count: u8,
+    length_xor: u16,
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new(group_size: u8) -> Self {
+        FecEncoder {
+            group_size,
+            first_sequence: 0,
+            count: 0,
+            length_xor: 0,
+            parity: Vec::new(),
+        }
+    }
+
+    /// Add a data packet to the current group.
+    /// When the group is complete returns the sequence number of its first packet and the parity payload.
+    pub fn push(&mut self, sequence: u32, payload: &[u8]) -> Option<(u32, Vec<u8>)> {
+        if self.group_size == 0 {
+            return None;
+        }
+        // Groups are made up of consecutive packets, start a new one if a packet has been skipped
+        if self.count > 0 && sequence != self.first_sequence.wrapping_add(self.count as u32) {
+            self.count = 0;
+        }
+        if self.count == 0 {
+            self.first_sequence = sequence;
+            self.length_xor = 0;
+            self.parity.clear();
+        }
+
+        xor_into(&mut self.parity, payload);
+        self.length_xor ^= payload.len() as u16;
+        self.count += 1;
+
+        if self.count < self.group_size {
+            return None;
+        }
+
+        let mut parity_payload = Vec::with_capacity(PARITY_HEADER_SIZE + self.parity.len());
+        parity_payload.push(self.count);
+        parity_payload.extend_from_slice(&self.length_xor.to_be_bytes());
+        parity_payload.extend_from_slice(&self.parity);
+        self.count = 0;
+        Some((self.first_sequence, parity_payload))
+    }
+}
+
+/// The FecDecoder is used by the client to rebuild a lost data packet from the parity packet of its group.
+/// Payloads are forgotten in the order they arrived, since sequence numbers wrap around and can't be sorted.
+pub struct FecDecoder {
+    received: HashMap<u32, Vec<u8>>,
+    arrivals: VecDeque<u32>,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        FecDecoder {
+            received: HashMap::new(),
+            arrivals: VecDeque::new(),
+            recovered: 0,
+        }
+    }
+
+    /// Store a received data payload, forgetting the oldest ones.
+    pub fn on_data(&mut self, sequence: u32, payload: &[u8]) {
+        self.remember(sequence, payload.to_vec());
+    }
+
+    fn remember(&mut self, sequence: u32, payload: Vec<u8>) {
+        if self.received.insert(sequence, payload).is_none() {
+            self.arrivals.push_back(sequence);
+        }
+        while self.arrivals.len() > DECODER_HISTORY {
+            if let Some(oldest) = self.arrivals.pop_front() {
+                self.received.remove(&oldest);
+            }
+        }
+    }
+
+    /// Process a parity packet. If exactly one packet of its group is missing, returns its sequence number and payload.
+    pub fn on_parity(&mut self, first_sequence: u32, payload: &[u8]) -> Option<(u32, Vec<u8>)> {
+        if payload.len() < PARITY_HEADER_SIZE {
+            return None;
+        }
+        // The packets of the group may have already been forgotten
+        match self.arrivals.front() {
+            Some(&oldest) if !sequence_before(first_sequence, oldest) => {}
+            _ => return None,
+        }
+
+        let count = payload[0] as u32;
+        let mut length = u16::from_be_bytes([payload[1], payload[2]]);
+        let mut rebuilt = payload[PARITY_HEADER_SIZE..].to_vec();
+        let mut missing = None;
+
+        for sequence in (0..count).map(|offset| first_sequence.wrapping_add(offset)) {
+            match self.received.get(&sequence) {
+                Some(data) => {
+                    xor_into(&mut rebuilt, data);
+                    length ^= data.len() as u16;
+                }
+                None if missing.is_none() => missing = Some(sequence),
+                // More than one packet is missing, the group can't be rebuilt
+                None => return None,
+            }
+        }
+
+        let sequence = missing?;
+        if length as usize > rebuilt.len() {
+            return None;
+        }
+        rebuilt.truncate(length as usize);
+        self.remember(sequence, rebuilt.clone());
+        self.recovered += 1;
+        Some((sequence, rebuilt))
+    }
+
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+    use crate::lossy_socket::LossySocket;
+    use crate::protocol::{PacketHeader, PacketType, MAX_DATAGRAM_SIZE};
+
+    #[test]
+    fn rebuilds_a_packet_of_a_group_across_the_wrap() {
+        let mut encoder = FecEncoder::new(4);
+        let mut decoder = FecDecoder::new();
+        let first = u32::MAX - 1;
+        let mut parity = None;
+        for offset in 0..4 {
+            let sequence = first.wrapping_add(offset);
+            let payload = vec![offset as u8; 10 + offset as usize];
+            parity = encoder.push(sequence, &payload);
+            // The packet numbered 0 is lost
+            if sequence != 0 {
+                decoder.on_data(sequence, &payload);
+            }
+        }
+
+        let (parity_sequence, parity_payload) = parity.unwrap();
+        assert_eq!(parity_sequence, first);
+        assert_eq!(decoder.on_parity(parity_sequence, &parity_payload), Some((0, vec![2; 12])));
+    }
+
+    #[test]
+    fn rebuilds_the_packets_dropped_by_a_lossy_socket() {
+        const GROUPS: u32 = 200;
+        const GROUP_SIZE: u8 = 8;
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let address = receiver.local_addr().unwrap();
+        // The datagrams are read while they are sent, so that the kernel doesn't drop them besides the LossySocket
+        let reader = thread::spawn(move || {
+            let mut datagrams = Vec::new();
+            let mut buffer = [0; MAX_DATAGRAM_SIZE];
+            while let Ok(size) = receiver.recv(&mut buffer) {
+                datagrams.push(buffer[..size].to_vec());
+            }
+            datagrams
+        });
+
+        let socket = LossySocket::new(UdpSocket::bind("127.0.0.1:0").unwrap(), 10);
+        let mut encoder = FecEncoder::new(GROUP_SIZE);
+        let payload = |sequence: u32| vec![sequence as u8; 100 + sequence as usize % 50];
+        for sequence in 0..GROUPS * GROUP_SIZE as u32 {
+            socket.send_to(&PacketHeader::new(PacketType::Data, 1, sequence, 0).encode(&payload(sequence)), address).unwrap();
+            if let Some((first_sequence, parity)) = encoder.push(sequence, &payload(sequence)) {
+                socket.send_to(&PacketHeader::new(PacketType::Parity, 1, first_sequence, 0).encode(&parity), address).unwrap();
+            }
+        }
+
+        let mut decoder = FecDecoder::new();
+        let mut received = HashSet::new();
+        let mut rebuilt = 0;
+        for datagram in reader.join().unwrap() {
+            let (header, body) = PacketHeader::decode(&datagram).unwrap();
+            match header.packet_type {
+                PacketType::Data => {
+                    decoder.on_data(header.sequence, body);
+                    received.insert(header.sequence);
+                }
+                PacketType::Parity => {
+                    // A group can be rebuilt if exactly one of its data packets has been dropped
+                    let group = (header.sequence..header.sequence + GROUP_SIZE as u32).collect::<Vec<u32>>();
+                    let missing = group.iter().filter(|sequence| !received.contains(sequence)).copied().collect::<Vec<u32>>();
+                    let recovered = decoder.on_parity(header.sequence, body);
+                    match missing[..] {
+                        [sequence] => {
+                            assert_eq!(recovered, Some((sequence, payload(sequence))));
+                            rebuilt += 1;
+                        }
+                        _ => assert_eq!(recovered, None),
+                    }
+                }
+                PacketType::Control => unreachable!(),
+            }
+        }
+        // About a third of the groups lose exactly one packet at a loss of 10%
+        assert!(rebuilt > 0);
+        assert_eq!(decoder.recovered(), rebuilt);
+    }
+}
@@ -1,5 +1,5 @@
 use iced::widget::{Button, Column, Container, PickList, Row, Scrollable, Space, Svg, Text, TextInput};
-use iced::{Alignment, Element, Length, Application, Command, Settings, Theme, Subscription, alignment::Horizontal};
+use iced::{Alignment, Element, Length, Application, Command, Settings, Theme, Subscription, alignment::Horizontal, theme};
 use crate::utils;
 use std::sync::{Arc, Mutex};
 use global_hotkey::GlobalHotKeyManager;
@@ -8,8 +8,14 @@ use crate::hotkeys::{AppState, parse_key_code, HotkeyMessage};
 use std::process::{Command as Command2, Stdio};
 use std::collections::HashMap;
 use crate::streaming_client::{StreamingClient, VideoPlayerMessage};
-use crate::streamers_table::{StreamersTable, StreamersTableMessage};
+use crate::streamers_table::{StreamersTable, StreamersTableMessage, RecordStyle};
+use crate::streaming_server::ClientSnapshot;
+use crate::discovery::{self, DiscoveredSession, DiscoveryListener};
+use crate::transport::TransportKind;
 use crate::error_banner::{Banner, InputError};
+use crate::probe;
+use std::thread;
+use iced::futures::channel::oneshot;
 use native_dialog::FileDialog;
 
 struct ConnectInputErrorBanner;
@@ -51,7 +57,17 @@ pub enum Message {
     SaveDirectory,
     GoToStreamersTable,
     StreamersTableMessage(StreamersTableMessage),
-    CloseBanner
+    CloseBanner,
+    KickViewer(String),
+    BanViewer(String),
+    TogglePin,
+    GenerateKey,
+    CopySessionPin,
+    PinInputChanged(String),
+    RefreshDiscoveredSessions,
+    DiscoveredSessionClicked(DiscoveredSession),
+    SelectTransport(TransportKind),
+    ProbeFinished(Result<probe::ProbedServer, InputError>),
 }
 
 /// AppStateEnum enum used to manage the application state
@@ -68,6 +84,7 @@ pub enum AppStateEnum {
     SelectScreen,
     ChangeListStreamers,
     ConnectInputError(InputError),
+    Probing,
 }
 
 /// ScreenCaster struct
@@ -96,6 +113,12 @@ pub struct ScreenCaster {
     screen_index: usize,
     share_mode: ShareMode,
     selected_directory: String,
+    session_pin: Option<String>,
+    pin_input: String,
+    discovery: Option<DiscoveryListener>,
+    discovered_sessions: Vec<DiscoveredSession>,
+    server_port: Option<u16>,
+    transport: TransportKind,
 }
 
 /// ShareMode enum used to manage the share mode
@@ -149,6 +172,12 @@ impl Application for ScreenCaster {
                 screen_index: 1,
                 share_mode: ShareMode::Fullscreen,
                 selected_directory: save_path,
+                session_pin: None,
+                pin_input: String::new(),
+                discovery: None,
+                discovered_sessions: Vec::new(),
+                server_port: None,
+                transport: TransportKind::Auto,
             },
             Command::none(),
         )
@@ -165,6 +194,9 @@ impl Application for ScreenCaster {
             Message::GoBackHome => {
                 self.state = AppStateEnum::Home;
                 app_state.is_sharing = false; 
+                // Stop listening for the casting sessions announced on the LAN
+                self.discovery = None;
+                self.discovered_sessions.clear();
             }
             Message::GoToChangeHotKeys => {
                 self.state = AppStateEnum::ChangeHotKeys
@@ -179,6 +211,27 @@ impl Application for ScreenCaster {
                     self.streamers_map.insert(name, ip);
                 });
                 app_state.is_sharing = false; 
+                if self.discovery.is_none() {
+                    self.discovery = DiscoveryListener::start(&utils::read_network_config()).ok();
+                }
+            }
+            // Update the list of the casting sessions announced on the LAN
+            Message::RefreshDiscoveredSessions => {
+                // The discovery port may still be held by a previous listener, try again
+                if self.discovery.is_none() {
+                    self.discovery = DiscoveryListener::start(&utils::read_network_config()).ok();
+                }
+                self.discovered_sessions = self.discovery.as_ref().map_or(Vec::new(), |discovery| discovery.sessions());
+            }
+            Message::DiscoveredSessionClicked(session) => {
+                self.ip_address = session.address.ip().to_string();
+                self.input_state = self.ip_address.clone();
+                self.server_port = Some(session.address.port());
+                self.streamers_suggestions.clear();
+            }
+            // Transport used by the next connection
+            Message::SelectTransport(transport) => {
+                self.transport = transport;
             }
             Message::GoToSettings => {
                 self.state = AppStateEnum::Settings;
@@ -191,6 +244,7 @@ impl Application for ScreenCaster {
             }
             Message::SuggestionClicked((suggestion, ip)) => {
                 self.ip_address = ip;
+                self.server_port = None;
                 self.input_state = suggestion;
                 self.streamers_suggestions.clear();
 
@@ -208,9 +262,10 @@ impl Application for ScreenCaster {
                     .map(|(key, ip)| (key.clone(), ip.clone()))
                     .collect();
                 self.ip_address.clear();
+                self.server_port = None;
 
             }
-            // Test the ip input and if valid try to connect
+            // Resolve the host typed or picked, then probe it on a separate thread before connecting, see the probe module
             Message::TryConnect => {
                     if !self.input_state.is_empty() {
                         let matching = self.streamers_map.iter()
@@ -224,16 +279,16 @@ impl Application for ScreenCaster {
                                     1 =>  self.ip_address = matching[0].clone(),
                                     _ => {},
                                 }
-                                match utils::is_ip_in_lan(&self.ip_address) {
-                                    Ok(_) => { 
-                                        self.state = AppStateEnum::Watching;
-                                        self.streaming_client = Some(StreamingClient::new(self.ip_address.clone(), self.selected_directory.clone()));
-                                        
-                                        return Command::perform(async {}, |_| Message::Connecting)},
-                                    Err(e) => {
-                                        self.state = AppStateEnum::ConnectInputError(e);
-                                    }
-                                }
+                                self.state = AppStateEnum::Probing;
+                                let host = self.ip_address.clone();
+                                // The server port is the configured one, unless a different one has been announced by the server
+                                let port = self.server_port.unwrap_or_else(|| utils::read_network_config().server_port);
+                                let transport = self.transport;
+                                let (tx, rx) = oneshot::channel();
+                                thread::spawn(move || {
+                                    let _ = tx.send(probe::find_server(&host, port, transport));
+                                });
+                                return Command::perform(async move { rx.await.unwrap_or(Err(InputError::Unreachable)) }, Message::ProbeFinished);
                             },
                             _ => {
                                 self.state = AppStateEnum::ConnectInputError(InputError::MultipleMatches);
@@ -243,10 +298,42 @@ impl Application for ScreenCaster {
                         self.state = AppStateEnum::ConnectInputError(InputError::NoValue);
                     }
             }
+            // Connect to the address found by the probe, unless the viewer left the connect screen in the meantime
+            Message::ProbeFinished(result) => {
+                if let AppStateEnum::Probing = self.state {
+                    match result {
+                        Ok(server) => {
+                            let pin = if self.pin_input.trim().is_empty() { None } else { Some(self.pin_input.trim().to_string()) };
+                            // A PIN typed for a session that is not protected would be sent in clear, the viewer has to know
+                            if pin.is_some() && server.salt.is_none() {
+                                self.state = AppStateEnum::ConnectInputError(InputError::NotProtected);
+                                return Command::none();
+                            }
+                            self.state = AppStateEnum::Watching;
+                            self.streaming_client = Some(StreamingClient::new(server.address, self.selected_directory.clone(), pin, server.salt, self.transport));
+                            return Command::perform(async {}, |_| Message::Connecting);
+                        }
+                        Err(e) => {
+                            self.state = AppStateEnum::ConnectInputError(e);
+                        }
+                    }
+                }
+            }
             // Update the video player: used to communicate with streaming_client
             Message::VideoPlayerMessage(message) => {
                 if let Some(sc) = &mut self.streaming_client {
-                    sc.update(message);
+                    // The server refused the PIN or the session can't be played: go back to the connect screen showing the error
+                    match sc.update(message) {
+                        Some(VideoPlayerMessage::WrongPin) => {
+                            self.streaming_client = None;
+                            self.state = AppStateEnum::ConnectInputError(InputError::WrongPin);
+                        }
+                        Some(VideoPlayerMessage::Incompatible(e)) => {
+                            self.streaming_client = None;
+                            self.state = AppStateEnum::ConnectInputError(e);
+                        }
+                        _ => {}
+                    }
                 }
             }
             // Stop incoming streaming
@@ -295,6 +382,30 @@ impl Application for ScreenCaster {
                 app_state.share_mode = mode;
                 self.share_mode = mode;
             }
+            // Generate a new PIN to protect the session, or remove it
+            Message::TogglePin => {
+                self.session_pin = match self.session_pin {
+                    Some(_) => None,
+                    None => Some(utils::generate_pin()),
+                };
+                app_state.session_pin = self.session_pin.clone();
+            }
+            // Protect the session with a longer key, meant to be copied and pasted rather than typed
+            Message::GenerateKey => {
+                self.session_pin = Some(utils::generate_key());
+                app_state.session_pin = self.session_pin.clone();
+            }
+            Message::CopySessionPin => {
+                if let Some(pin) = self.session_pin.clone() {
+                    return iced::clipboard::write(pin);
+                }
+            }
+            // A PIN is made up of digits, a key of the characters of utils::generate_key, typed or pasted in lower case too
+            Message::PinInputChanged(pin) => {
+                if pin.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                    self.pin_input = pin.to_ascii_uppercase();
+                }
+            }
             Message::BrowseDirectory => {
                 let selected_directory = FileDialog::new()
                     .show_open_single_dir()
@@ -439,6 +550,17 @@ impl Application for ScreenCaster {
             Message::CloseBanner => {
                 self.state = AppStateEnum::Connect;
             }
+            // Disconnect a viewer, optionally banning its IP for the rest of the session
+            Message::KickViewer(address) => {
+                if let Some(server) = app_state.streaming_server.as_ref() {
+                    server.kick_client(&address, false);
+                }
+            }
+            Message::BanViewer(address) => {
+                if let Some(server) = app_state.streaming_server.as_ref() {
+                    server.kick_client(&address, true);
+                }
+            }
         }
 
         Command::none()
@@ -451,7 +573,7 @@ impl Application for ScreenCaster {
             AppStateEnum::Home => self.view_home(),
             AppStateEnum::StartSharing => self.view_start_casting(),
             AppStateEnum::IsSharing => self.view_casting(),
-            AppStateEnum::Connect | AppStateEnum::ConnectInputError(_) => self.view_connect(),
+            AppStateEnum::Connect | AppStateEnum::ConnectInputError(_) | AppStateEnum::Probing => self.view_connect(),
             AppStateEnum::ChangeHotKeys => self.view_modify_hotkeys(),
             AppStateEnum::Watching => self.view_streaming(),
             AppStateEnum::SelectScreen => self.view_casting_settings(),
@@ -482,6 +604,9 @@ impl Application for ScreenCaster {
                 let mut app_state = self.app_state.lock().unwrap();
                 Subscription::batch(vec![app_state.subscription().map(Message::HotkeyMessage)])
             }
+            AppStateEnum::Connect | AppStateEnum::ConnectInputError(_) | AppStateEnum::Probing => {
+                iced::time::every(discovery::BEACON_INTERVAL).map(|_| Message::RefreshDiscoveredSessions)
+            }
             _ => {Subscription::none()}
         }
     }
@@ -575,6 +700,33 @@ impl ScreenCaster {
                             ),
                     ),
             )
+            .push(
+                Row::new()
+                    .spacing(40)
+                    .align_items(Alignment::Center)
+                    .push(
+                        Text::new(match self.session_pin.as_ref() {
+                            Some(pin) => format!("PIN della sessione: {pin}"),
+                            None => "Sessione senza PIN".to_string(),
+                        }).size(20)
+                    )
+                    .push(
+                        Button::new(Text::new(if self.session_pin.is_some() { "Rimuovi PIN" } else { "Genera PIN" }).horizontal_alignment(Horizontal::Center))
+                            .padding(10)
+                            .width(Length::Fixed(200.0))
+                            .on_press(Message::TogglePin),
+                    )
+                    .push(match self.session_pin {
+                        Some(_) => Button::new(Text::new("Copia").horizontal_alignment(Horizontal::Center))
+                            .padding(10)
+                            .width(Length::Fixed(200.0))
+                            .on_press(Message::CopySessionPin),
+                        None => Button::new(Text::new("Genera chiave").horizontal_alignment(Horizontal::Center))
+                            .padding(10)
+                            .width(Length::Fixed(200.0))
+                            .on_press(Message::GenerateKey),
+                    }),
+            )
             .push(Space::with_height(30))
             .push(
                 Row::new()
@@ -654,7 +806,10 @@ impl ScreenCaster {
                             .width(Length::Fixed(200.0))
                             .on_press(Message::ToggleAnnotationTool)
                     ),
-            );
+            )
+            .push(self.view_stream_urls())
+            .push(self.view_rate_status())
+            .push(self.view_viewers_roster());
 
         Container::new(content)
             .width(Length::Fill)
@@ -664,20 +819,127 @@ impl ScreenCaster {
             .into()
     }
 
+    /// Render the URLs the stream can be watched at without the client: the browser viewer and the RTSP stream for third-party players, if served.
+    fn view_stream_urls(&self) -> Element<Message> {
+        let (http_url, rtsp_url) = match self.app_state.lock().unwrap().streaming_server.as_ref() {
+            Some(server) => (server.http_url(), server.rtsp_url()),
+            None => (None, None),
+        };
+        let mut column = Column::new().spacing(5).align_items(Alignment::Center);
+        if let Some(url) = http_url {
+            column = column.push(Text::new(format!("Guarda dal browser: {url}")).size(18));
+        }
+        if let Some(url) = rtsp_url {
+            column = column.push(Text::new(format!("Flusso RTSP per altri player: {url}")).size(18));
+        }
+        column.into()
+    }
+
+    /// Render the current target of the encoder, chosen by the rate controller from the reports of the viewers,
+    /// followed by its most recent changes and the reason behind each of them.
+    /// In a simulcast session the layers have fixed targets, which are listed instead.
+    fn view_rate_status(&self) -> Element<Message> {
+        let ((target, changes), layers) = match self.app_state.lock().unwrap().streaming_server.as_ref() {
+            Some(server) => (server.rate_status(), server.simulcast_layers()),
+            None => return Column::new().into(),
+        };
+        if !layers.is_empty() {
+            let layers = layers.iter()
+                .map(|(height, target)| target.map_or(format!("{height}p"), |target| format!("{height}p ({} kbps)", target.bitrate_kbps)))
+                .collect::<Vec<String>>();
+            return Text::new(format!("Livelli di qualità: {}", layers.join(", "))).size(18).into();
+        }
+
+        changes.iter().fold(
+            Column::new()
+                .spacing(5)
+                .align_items(Alignment::Center)
+                .push(Text::new(format!("Qualità attuale: {target}")).size(18)),
+            |column, change| {
+                column.push(Text::new(format!("{} → {}: {}", change.at.format("%H:%M:%S"), change.target, change.reason)).size(14))
+            },
+        ).into()
+    }
+
+    /// Render the live roster of the connected viewers, refreshed at every tick of the hotkey subscription while casting.
+    /// The viewers served by a relay are shown in a tree below it.
+    /// Viewers evicted because they stopped sending heartbeats or could not keep up with the stream are listed below the table.
+    fn view_viewers_roster(&self) -> Element<Message> {
+        let (clients, evicted_clients) = match self.app_state.lock().unwrap().streaming_server.as_ref() {
+            Some(server) => (server.clients_snapshot(), server.evicted_clients()),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        let header = ["Spettatore", "Connesso dalle", "Dati inviati", "Ultimo contatto", "NACK / ritrasmessi", "Coda / attesa / scartati", "Qualità", ""]
+            .iter()
+            .fold(Row::new().spacing(20).align_items(Alignment::Center), |row, title| {
+                row.push(Container::new(Text::new(*title).size(18)).width(Length::FillPortion(1)).center_x())
+            });
+        let mut table = Column::new()
+            .push(Container::new(header).padding(5).style(theme::Container::Custom(RecordStyle.into())));
+
+        for client in clients.iter() {
+            table = table.push(Container::new(view_roster_record(client)).padding(5).style(theme::Container::Custom(RecordStyle.into())));
+            // Viewers watching through a relay are listed below it, indented by their depth
+            for (address, depth) in client.downstream.iter() {
+                table = table.push(
+                    Row::new()
+                        .push(Space::with_width(Length::Fixed(30.0 * *depth as f32)))
+                        .push(Text::new(format!("↳ {address} (tramite relay)")).size(16))
+                        .padding(5)
+                );
+            }
+        }
+        if clients.is_empty() {
+            table = table.push(Container::new(Text::new("Nessuno spettatore connesso").size(16)).width(Length::Fill).center_x().padding(5));
+        }
+
+        let table = evicted_clients.iter().fold(table.spacing(2), |column, (address, reason)| {
+            column.push(Container::new(Text::new(format!("{address} disconnesso ({reason})")).size(16)).width(Length::Fill).center_x())
+        });
+
+        Container::new(table)
+            .width(Length::Fixed(1000.0))
+            .padding(20)
+            .into()
+    }
+
     fn view_connect(&self) -> Element<Message> {
         let content = Column::new()
             .spacing(20)
             .align_items(Alignment::Center)
-            .push(Text::new("Inserisci l'indirizzo IP").size(30))
+            .push(Text::new("Inserisci l'indirizzo IP o il nome dell'host").size(30))
             .push(
                 TextInput::new(
-                    "Inserisci l'indirizzo IP...",
+                    "Indirizzo IP o nome dell'host...",
                     &self.input_state,
                 )
                     .padding(10)
                     .width(Length::Fixed(500.0))
                     .on_input(|input| Message::ConnectInputChanged(input)),
             )
+            .push(
+                TextInput::new(
+                    "PIN o chiave della sessione (se richiesto)...",
+                    &self.pin_input,
+                )
+                    .padding(10)
+                    .width(Length::Fixed(500.0))
+                    .on_input(Message::PinInputChanged),
+            )
+            .push(
+                Row::new()
+                    .spacing(20)
+                    .align_items(Alignment::Center)
+                    .push(Text::new("Trasporto:").size(20))
+                    .push(
+                        PickList::new(
+                            &TransportKind::ALL[..],
+                            Some(self.transport),
+                            Message::SelectTransport,
+                        ),
+                    ),
+            )
             .push(
                 Scrollable::new(
                     self.streamers_suggestions.iter().fold(Column::new().spacing(5), |column, (suggestion, ip)| {
@@ -704,16 +966,21 @@ impl ScreenCaster {
                 ),
 
             )
+            .push(self.view_discovered_sessions())
             .push(
                 Row::new()
                     .spacing(20)
                     .align_items(Alignment::Center)
-                    .push(
-                        Button::new(Text::new("Connetti").horizontal_alignment(Horizontal::Center))
+                    .push(match self.state {
+                        // The button is disabled while the host is probed
+                        AppStateEnum::Probing => Button::new(Text::new("Verifica in corso...").horizontal_alignment(Horizontal::Center))
+                            .padding(10)
+                            .width(Length::Fixed(200.0)),
+                        _ => Button::new(Text::new("Connetti").horizontal_alignment(Horizontal::Center))
                             .padding(10)
                             .width(Length::Fixed(200.0))
                             .on_press(Message::TryConnect),
-                    )
+                    })
                     .push(
                         Button::new(Text::new("Gestisci lista streamers").horizontal_alignment(Horizontal::Center))
                             .padding(10)
@@ -748,6 +1015,37 @@ impl ScreenCaster {
         
     }
 
+    /// Render the casting sessions announced on the LAN, refreshed every BEACON_INTERVAL while in the connect screen.
+    /// Clicking a session fills the address to connect to.
+    fn view_discovered_sessions(&self) -> Element<Message> {
+        let mut column = Column::new()
+            .spacing(5)
+            .align_items(Alignment::Center)
+            .push(Text::new("Sessioni attive nella rete").size(20));
+        if self.discovered_sessions.is_empty() {
+            column = column.push(Text::new("Nessuna sessione trovata").size(14));
+        }
+        for session in &self.discovered_sessions {
+            let saved_name = self.streamers_map.iter()
+                .find(|(_, ip)| **ip == session.address.ip().to_string())
+                .map(|(name, _)| format!(" ({name})"))
+                .unwrap_or_default();
+            column = column.push(
+                Button::new(
+                    Row::new()
+                        .push(Text::new(format!("{}{saved_name}", session.name)).width(Length::Fixed(200.0)))
+                        .push(Text::new(session.address.to_string()).width(Length::Fixed(150.0)))
+                        .push(Text::new(session.resolution.clone()).width(Length::Fixed(90.0)))
+                        .push(Text::new(if session.pin_required { "PIN" } else { "" }).width(Length::Fixed(40.0)))
+                )
+                    .on_press(Message::DiscoveredSessionClicked(session.clone()))
+                    .padding(8)
+                    .width(Length::Fixed(500.0)),
+            );
+        }
+        Scrollable::new(column).height(Length::Fixed(160.0)).into()
+    }
+
     fn view_streaming(&self) -> Element<Message> {
         let content;
         if let Some(sc) = self.streaming_client.as_ref(){
@@ -767,6 +1065,15 @@ impl ScreenCaster {
             if let Some(record_button) = optional_button{
                 row = row.push(record_button.map(Message::VideoPlayerMessage));
             }
+            if let Some(layer_picker) = sc.view_layer_picker(){
+                row = row.push(layer_picker.map(Message::VideoPlayerMessage));
+            }
+            if let Some(relay_button) = sc.view_relay_button(){
+                row = row.push(relay_button.map(Message::VideoPlayerMessage));
+            }
+            if let Some(stats) = sc.view_stats(){
+                row = row.push(stats.map(Message::VideoPlayerMessage));
+            }
             content = Column::new()
                 .push(video_player)
                 .push(
@@ -993,6 +1300,41 @@ impl ScreenCaster {
 
 }
 
+/// Render a row of the viewers roster.
+fn view_roster_record(client: &ClientSnapshot) -> Row<'static, Message> {
+    let cells = [
+        client.address.clone(),
+        client.connected_at.format("%H:%M:%S").to_string(),
+        format_bytes(client.bytes_sent),
+        format!("{} s fa", (chrono::Local::now() - client.last_seen).num_seconds()),
+        format!("{} / {}", client.retransmission.nacks, client.retransmission.retransmitted),
+        format!("{} / {} ms / {}", client.queue_depth, client.pacing_delay.as_millis(), client.dropped),
+        // The quality is adapted for all the viewers together without simulcast
+        client.layer.map_or("adattiva".to_string(), |height| format!("{height}p")),
+    ];
+    let row = cells.into_iter().fold(Row::new().spacing(20).align_items(Alignment::Center), |row, cell| {
+        row.push(Container::new(Text::new(cell).size(16)).width(Length::FillPortion(1)).center_x())
+    });
+    row.push(
+        Container::new(
+            Row::new()
+                .spacing(10)
+                .push(Button::new(Text::new("Espelli").size(14)).padding(5).on_press(Message::KickViewer(client.address.clone())))
+                .push(Button::new(Text::new("Banna").size(14)).padding(5).on_press(Message::BanViewer(client.address.clone())))
+        ).width(Length::FillPortion(1)).center_x()
+    )
+}
+
+/// Format an amount of bytes with the most suitable unit.
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.2} GB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.2} MB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{b} B"),
+    }
+}
+
 pub fn run_gui(app_state: Arc<Mutex<AppState>>, manager: Arc<Mutex<GlobalHotKeyManager>>, id1: Arc<Mutex<u32>>, id2: Arc<Mutex<u32>>, id3: Arc<Mutex<u32>>, id4: Arc<Mutex<u32>>, hotkey_record: HotKey, hotkey_stop: HotKey, hotkey_clear: HotKey, hotkey_close: HotKey) {
     let app_state_clone = app_state.clone();
     let settings = Settings::with_flags((app_state, manager, id1, id2, id3, id4, hotkey_record, hotkey_stop, hotkey_clear, hotkey_close));
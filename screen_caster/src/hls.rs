@@ -0,0 +1,417 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use crate::rtp::{self, TsPacket};
+use crate::transport;
+use crate::utils;
+
+/// This module contains the HttpServer, which lets the casting session be watched from a browser at http://address:port/.
+/// The fan-out thread of the StreamingServer pushes every chunk read from the encoder, an HlsSegmenter cuts the MPEG-TS stream
+/// into segments of about SEGMENT_DURATION, each one starting with the PAT, the PMT and a keyframe, and keeps the most recent ones.
+/// The server answers:
+/// - /            the viewer page, read from assets/viewer.html, which plays the stream with hls.js (or natively on Safari)
+/// - /hls.min.js  the hls.js player, read from assets/hls.min.js (fetched by the setup program), so that no page is loaded from a CDN
+/// - /live.m3u8   the live playlist, listing the last PLAYLIST_SIZE segments (503 until the first segment is ready)
+/// - /segmentN.ts the segments still kept
+///
+/// Every connection serves a single request. The encoder restarts are marked with a discontinuity in the playlist.
+/// The stream is served in clear and without authentication, so the StreamingServer only starts the HttpServer for sessions without a PIN.
+
+/// Path of the live playlist.
+pub const PLAYLIST_PATH: &str = "/live.m3u8";
+/// Minimum duration of a segment: segments are cut at the first keyframe after it, so the encoder GOP of one second makes them last about as much.
+pub const SEGMENT_DURATION: f64 = 1.0;
+/// Number of segments listed in the playlist.
+pub const PLAYLIST_SIZE: usize = 4;
+
+const VIEWER_PAGE_PATH: &str = "../assets/viewer.html";
+const HLS_JS_PATH: &str = "../assets/hls.min.js";
+// Files served from the assets directory: path requested, path of the file and content type
+const ASSETS: [(&str, &str, &str); 3] = [
+    ("/", VIEWER_PAGE_PATH, "text/html; charset=utf-8"),
+    ("/index.html", VIEWER_PAGE_PATH, "text/html; charset=utf-8"),
+    ("/hls.min.js", HLS_JS_PATH, "text/javascript"),
+];
+// Segments kept after leaving the playlist, for the players still downloading them
+const SEGMENTS_KEPT: usize = PLAYLIST_SIZE * 2;
+const PTS_CLOCK: f64 = 90_000.0;
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const FEED_SIZE: usize = 256;
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Segment struct contains a segment of the stream.
+/// - discontinuity: the segment follows a restart of the encoder, so its timestamps do not continue the previous one
+pub struct Segment {
+    pub sequence: u64,
+    pub duration: f64,
+    pub discontinuity: bool,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// The HlsSegmenter cuts the MPEG-TS stream read from the encoder in segments.
+/// The packets received before the first keyframe are dropped, since a segment has to be decodable on its own.
+pub struct HlsSegmenter {
+    pending: Vec<u8>,
+    pat: Option<Vec<u8>>,
+    pmt: Option<Vec<u8>>,
+    pmt_pid: Option<u16>,
+    video_pid: Option<u16>,
+    current: Vec<u8>,
+    // PTS of the first and of the last frame of the current segment, None until the first keyframe
+    start_pts: Option<u64>,
+    last_pts: u64,
+    discontinuity: bool,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+}
+
+impl HlsSegmenter {
+    pub fn new() -> Self {
+        HlsSegmenter {
+            pending: Vec::new(),
+            pat: None,
+            pmt: None,
+            pmt_pid: None,
+            video_pid: None,
+            current: Vec::new(),
+            start_pts: None,
+            last_pts: 0,
+            discontinuity: false,
+            segments: VecDeque::with_capacity(SEGMENTS_KEPT),
+            next_sequence: 0,
+        }
+    }
+
+    /// Feed a chunk of the stream, which doesn't need to be aligned to the TS packets.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.pending.extend_from_slice(chunk);
+        let mut offset = 0;
+        while self.pending.len() - offset >= rtp::TS_PACKET_SIZE {
+            if self.pending[offset] != rtp::TS_SYNC_BYTE {
+                offset += 1;
+                continue;
+            }
+            let packet = self.pending[offset..offset + rtp::TS_PACKET_SIZE].to_vec();
+            offset += rtp::TS_PACKET_SIZE;
+            self.on_packet(packet);
+        }
+        self.pending.drain(..offset);
+    }
+
+    fn on_packet(&mut self, packet: Vec<u8>) {
+        let (pid, unit_start, random_access, pts) = match TsPacket::parse(&packet) {
+            Some(ts) => (ts.pid, ts.unit_start, ts.random_access, rtp::pes_pts(ts.payload)),
+            None => (0, false, false, None),
+        };
+
+        // The tables are kept to be repeated at the start of every segment
+        if pid == rtp::PAT_PID && unit_start {
+            self.pmt_pid = TsPacket::parse(&packet).and_then(|ts| rtp::section(ts.payload)).and_then(rtp::parse_pat);
+            self.pat = Some(packet);
+            return;
+        }
+        if Some(pid) == self.pmt_pid && unit_start {
+            self.video_pid = TsPacket::parse(&packet).and_then(|ts| rtp::section(ts.payload)).and_then(rtp::parse_pmt);
+            self.pmt = Some(packet);
+            return;
+        }
+
+        if Some(pid) == self.video_pid && unit_start {
+            if let Some(pts) = pts {
+                if random_access {
+                    match self.start_pts {
+                        // The encoder has been restarted, its timestamps start again
+                        Some(start) if pts < start => {
+                            self.finish_segment(self.last_pts.saturating_sub(start));
+                            self.discontinuity = true;
+                            self.start_segment(pts);
+                        }
+                        Some(start) if (pts - start) as f64 / PTS_CLOCK >= SEGMENT_DURATION => {
+                            self.finish_segment(pts - start);
+                            self.start_segment(pts);
+                        }
+                        Some(_) => {}
+                        None => self.start_segment(pts),
+                    }
+                }
+                self.last_pts = pts;
+            }
+        }
+
+        if self.start_pts.is_some() {
+            self.current.extend_from_slice(&packet);
+        }
+    }
+
+    fn start_segment(&mut self, pts: u64) {
+        self.current.clear();
+        if let (Some(pat), Some(pmt)) = (self.pat.as_ref(), self.pmt.as_ref()) {
+            self.current.extend_from_slice(pat);
+            self.current.extend_from_slice(pmt);
+        }
+        self.start_pts = Some(pts);
+    }
+
+    fn finish_segment(&mut self, duration: u64) {
+        if self.segments.len() == SEGMENTS_KEPT {
+            self.segments.pop_front();
+        }
+        self.segments.push_back(Segment {
+            sequence: self.next_sequence,
+            duration: duration as f64 / PTS_CLOCK,
+            discontinuity: self.discontinuity,
+            data: Arc::new(std::mem::take(&mut self.current)),
+        });
+        self.discontinuity = false;
+        self.next_sequence += 1;
+    }
+
+    /// Return the live playlist, None until the first segment is ready.
+    pub fn playlist(&self) -> Option<String> {
+        let listed = self.segments.iter().skip(self.segments.len().saturating_sub(PLAYLIST_SIZE)).collect::<Vec<&Segment>>();
+        let first = listed.first()?;
+        let target_duration = listed.iter().map(|segment| segment.duration).fold(SEGMENT_DURATION, f64::max).ceil() as u64;
+        let mut playlist = format!("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{target_duration}\n#EXT-X-MEDIA-SEQUENCE:{}\n", first.sequence);
+        for segment in listed {
+            if segment.discontinuity {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            playlist.push_str(&format!("#EXTINF:{:.3},\nsegment{}.ts\n", segment.duration, segment.sequence));
+        }
+        Some(playlist)
+    }
+
+    /// Return the data of a segment, if it is still kept.
+    pub fn segment(&self, sequence: u64) -> Option<Arc<Vec<u8>>> {
+        self.segments.iter().find(|segment| segment.sequence == sequence).map(|segment| segment.data.clone())
+    }
+}
+
+/// The HttpServer serves the viewer page, the playlist and the segments to the browsers, until it is stopped.
+pub struct HttpServer {
+    address: SocketAddr,
+    feed: Sender<Vec<u8>>,
+    running: Arc<AtomicBool>,
+}
+
+impl HttpServer {
+    /// Start the server on the given address, dual-stack if it is the unspecified IPv6 one.
+    pub fn start(address: SocketAddr) -> io::Result<Self> {
+        let listener = transport::bind_tcp(address)?;
+        listener.set_nonblocking(true)?;
+        let segmenter = Arc::new(Mutex::new(HlsSegmenter::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (feed, feed_rx) = bounded(FEED_SIZE);
+
+        let segmenter_clone = segmenter.clone();
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let segmenter = segmenter_clone.clone();
+                        thread::spawn(move || HttpServer::serve(stream, segmenter));
+                    }
+                    Err(_) => thread::sleep(ACCEPT_INTERVAL),
+                }
+            }
+        });
+
+        let running_clone = running.clone();
+        thread::spawn(move || HttpServer::segment_stream(feed_rx, segmenter, running_clone));
+
+        Ok(HttpServer {
+            address: utils::advertised_address(address),
+            feed,
+            running,
+        })
+    }
+
+    /// Return the URL of the viewer page.
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.address)
+    }
+
+    /// Push a chunk of the MPEG-TS stream read from the encoder.
+    pub fn push(&self, chunk: &[u8]) {
+        let _ = self.feed.try_send(chunk.to_vec());
+    }
+
+    /// Stop accepting browsers and segmenting the stream.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    fn segment_stream(feed: Receiver<Vec<u8>>, segmenter: Arc<Mutex<HlsSegmenter>>, running: Arc<AtomicBool>) {
+        while running.load(Ordering::Relaxed) {
+            if let Ok(chunk) = feed.recv_timeout(READ_TIMEOUT) {
+                segmenter.lock().unwrap().push(&chunk);
+            }
+        }
+    }
+
+    // Read a request and send the response, then close the connection.
+    fn serve(mut stream: TcpStream, segmenter: Arc<Mutex<HlsSegmenter>>) {
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+
+        let mut request = Vec::new();
+        let mut buffer = [0; 2048];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            match stream.read(&mut buffer) {
+                Ok(n) if n > 0 && request.len() < MAX_REQUEST_SIZE => request.extend_from_slice(&buffer[..n]),
+                _ => return,
+            }
+        }
+        let request = String::from_utf8_lossy(&request).to_string();
+        let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+        let method = request_line.next().unwrap_or("");
+        // The query string, used by the players to avoid caches, is ignored
+        let path = request_line.next().unwrap_or("/").split('?').next().unwrap_or("/");
+
+        let (status, content_type, body) = if method != "GET" {
+            ("405 Method Not Allowed", "text/plain", Arc::new(b"Metodo non supportato".to_vec()))
+        } else if let Some((_, file, content_type)) = ASSETS.iter().find(|(asset, _, _)| *asset == path) {
+            match fs::read(file) {
+                Ok(data) => ("200 OK", *content_type, Arc::new(data)),
+                Err(_) => ("404 Not Found", "text/plain", Arc::new(b"Pagina non trovata".to_vec())),
+            }
+        } else if path == PLAYLIST_PATH {
+            match segmenter.lock().unwrap().playlist() {
+                Some(playlist) => ("200 OK", "application/vnd.apple.mpegurl", Arc::new(playlist.into_bytes())),
+                None => ("503 Service Unavailable", "text/plain", Arc::new(b"Flusso non ancora disponibile".to_vec())),
+            }
+        } else {
+            let segment = path.strip_prefix("/segment")
+                .and_then(|name| name.strip_suffix(".ts"))
+                .and_then(|sequence| sequence.parse::<u64>().ok())
+                .and_then(|sequence| segmenter.lock().unwrap().segment(sequence));
+            match segment {
+                Some(data) => ("200 OK", "video/mp2t", data),
+                None => ("404 Not Found", "text/plain", Arc::new(b"Segmento non trovato".to_vec())),
+            }
+        };
+
+        let header = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+            body.len());
+        let _ = stream.write_all(header.as_bytes());
+        let _ = stream.write_all(&body);
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use crate::rtp::TsDemuxer;
+
+    const PMT_PID: u16 = 0x1000;
+    const VIDEO_PID: u16 = 0x100;
+    const FRAMERATE: u64 = 30;
+    const GOP: u64 = 30;
+
+    // Build a TS packet carrying the payload, padded with the adaptation field, which sets the random access indicator if asked.
+    fn ts_packet(pid: u16, random_access: bool, payload: &[u8]) -> Vec<u8> {
+        let stuffing = rtp::TS_PACKET_SIZE - 4 - payload.len();
+        let mut packet = vec![rtp::TS_SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x30, (stuffing - 1) as u8];
+        packet.push(if random_access { 0x40 } else { 0x00 });
+        packet.resize(4 + stuffing, 0xFF);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // Return the tables and the frames of a stream of the given duration, each frame in a single TS packet.
+    // Keyframes carry the SPS and the PPS before the IDR slice, like the ones of the encoder.
+    fn stream(seconds: u64) -> Vec<Vec<u8>> {
+        let pat = [0x00, 0x00, 0xB0, 13, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01, 0xE0 | (PMT_PID >> 8) as u8, PMT_PID as u8, 0, 0, 0, 0];
+        let pmt = [0x00, 0x02, 0xB0, 18, 0x00, 0x01, 0xC1, 0x00, 0x00, 0xE1, 0x00, 0xF0, 0x00,
+            0x1B, 0xE0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xF0, 0x00, 0, 0, 0, 0];
+        let mut chunks = vec![ts_packet(rtp::PAT_PID, false, &pat), ts_packet(PMT_PID, false, &pmt)];
+        for frame in 0..seconds * FRAMERATE {
+            let keyframe = frame % GOP == 0;
+            let pts = frame * 90_000 / FRAMERATE;
+            let mut pes = vec![0, 0, 1, 0xE0, 0, 0, 0x80, 0x80, 5,
+                0x21 | ((pts >> 29) & 0x0E) as u8, (pts >> 22) as u8, ((pts >> 14) & 0xFE) as u8 | 1, (pts >> 7) as u8, ((pts << 1) & 0xFE) as u8 | 1];
+            if keyframe {
+                pes.extend_from_slice(&[0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, 0xAC, 0, 0, 0, 1, 0x68, 0xEE, 0x3C, 0x80, 0, 0, 0, 1, 0x65, 0x88, 0x84]);
+            } else {
+                pes.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x9A, 0x02]);
+            }
+            chunks.push(ts_packet(VIDEO_PID, keyframe, &pes));
+        }
+        chunks
+    }
+
+    // Send a GET request to the server and return the status line and the body of the response.
+    fn get(address: SocketAddr, path: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[..end]).lines().next().unwrap().to_string();
+        (status, response[end + 4..].to_vec())
+    }
+
+    #[test]
+    fn segments_served_over_http_start_decodable() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let server = HttpServer::start(address).unwrap();
+        for chunk in stream(4) {
+            server.push(&chunk);
+        }
+
+        // The segmenter runs on its own thread
+        let start = Instant::now();
+        let playlist = loop {
+            let (status, body) = get(address, PLAYLIST_PATH);
+            if status.contains("200") && String::from_utf8_lossy(&body).matches(".ts").count() >= 3 {
+                break String::from_utf8(body).unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no playlist served: {status}");
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(playlist.starts_with("#EXTM3U"));
+
+        for name in playlist.lines().filter(|line| line.ends_with(".ts")) {
+            let (status, segment) = get(address, &format!("/{name}"));
+            assert!(status.contains("200"), "{name}: {status}");
+            assert!(segment.chunks(rtp::TS_PACKET_SIZE).all(|packet| packet.len() == rtp::TS_PACKET_SIZE && packet[0] == rtp::TS_SYNC_BYTE));
+            assert!(rtp::contains_pat(&segment));
+
+            // A decoder starting from the segment gets the parameter sets and a keyframe first
+            let access_units = TsDemuxer::new().push(&segment);
+            let first = access_units.first().unwrap_or_else(|| panic!("{name} carries no frame"));
+            let types = first.nal_units.iter().map(|nal| nal[0] & 0x1F).collect::<Vec<u8>>();
+            assert_eq!(types, vec![7, 8, 5], "{name}");
+            assert_eq!(access_units.len() as u64, GOP - 1, "{name}");
+        }
+        assert!(get(address, "/segment999.ts").0.contains("404"));
+    }
+
+    #[test]
+    fn viewer_page_loads_no_remote_script() {
+        let page = fs::read_to_string(VIEWER_PAGE_PATH).unwrap();
+        assert!(page.contains("<script src=\"hls.min.js\">"));
+        assert!(!page.contains("://"));
+    }
+}
@@ -45,6 +45,8 @@ pub struct AppState {
     pub(crate) cast_started: bool,
     // Flag to check if the session has been closed.
     pub(crate) session_closed: bool,
+    // The PIN viewers must provide to connect, if the session is protected.
+    pub(crate) session_pin: Option<String>,
 }
 
 impl AppState {
@@ -57,6 +59,7 @@ impl AppState {
             annotation_stdin: None,
             cast_started: false,
             session_closed: false,
+            session_pin: None,
         }
     }
 
@@ -67,6 +70,7 @@ impl AppState {
             if self.streaming_server.is_none(){
                 self.streaming_server = Some(streaming_server::StreamingServer::new());
             }
+            self.streaming_server.as_mut().unwrap().set_pin(self.session_pin.clone());
             self.streaming_server.as_mut().unwrap().start(self.screen_index, self.share_mode);
             self.cast_started = true;
         }
@@ -197,7 +201,7 @@ pub fn linux_event_loop(id1: Arc<Mutex<u32>>, id2: Arc<Mutex<u32>>, id3: Arc<Mut
             let mut state = app_state.lock().unwrap();
             if event.state == HotKeyState::Released {
                 if event.id == *id1.lock().unwrap() {
-                    state.start(state.screen_index, state.share_mode); // Avvia la registrazione solo se siamo nella schermata di condivisione
+                    state.start(); // Avvia la registrazione solo se siamo nella schermata di condivisione
                 } else if event.id == *id2.lock().unwrap() {
                     state.stop(); // Ferma la registrazione
                 } else if event.id == *id3.lock().unwrap() {
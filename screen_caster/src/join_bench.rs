@@ -0,0 +1,259 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::crypto;
+use crate::join_cache::DEFAULT_JOIN_CACHE_SIZE;
+use crate::protocol::{self, ControlMessage, PacketHeader, PacketType};
+use crate::rtp::{self, PAT_PID, TS_PACKET_SIZE, TS_SYNC_BYTE};
+use crate::session::{self, SessionDescriptor};
+use crate::streaming_server::StreamingServer;
+use crate::utils;
+
+/// This module contains a benchmark of the time a viewer takes to join a casting session, run with "screen_caster --bench-join [clients] [GOP seconds]".
+/// A relay session is fed with a synthetic MPEG-TS stream at BENCH_FRAMERATE, where every GOP starts with the PAT, the PMT
+/// and a keyframe like the stream muxed by ffmpeg. The given number of clients (BENCH_CLIENTS by default) join the session on loopback
+/// one after the other: the i-th one waits i/clients of a GOP after the previous one has started decoding, right after a keyframe,
+/// so that the clients join at points evenly spread over the GOP.
+/// The join time of a client goes from the "START" request to the first data packet a decoder can start from: a keyframe received
+/// after the PAT, without gaps in between. The session is run without join cache and then with the default one, see the join_cache module,
+/// and for each run the median and the longest join time are printed. Without the cache the join time is spread over the whole GOP.
+/// Last, the same stream is relayed as two layers of a simulcast session, see the simulcast module: each client joins the first layer
+/// and switches to the second one at a point of the GOP spread like the joins, and the time from the "LAYER" request to the first data packet
+/// of the new layer a decoder can start from is measured, since the switch is a join on the cache of the new layer.
+
+pub const BENCH_FLAG: &str = "--bench-join";
+const BENCH_CLIENTS: usize = 10;
+const BENCH_GOP_SECONDS: u64 = 2;
+const BENCH_FRAMERATE: u64 = 30;
+// TS packets of each frame of the synthetic stream, about 2 Mbit/s at BENCH_FRAMERATE
+const FRAME_PACKETS: usize = 45;
+const VIDEO_PID: u16 = 0x100;
+const PMT_PID: u16 = 0x1000;
+
+/// Run the benchmark with the command line arguments following BENCH_FLAG.
+pub fn run(args: &[String]) {
+    let clients = args.first().and_then(|arg| arg.parse().ok()).filter(|clients| *clients > 0).unwrap_or(BENCH_CLIENTS);
+    let gop_seconds = args.get(1).and_then(|arg| arg.parse().ok()).filter(|seconds| *seconds > 0).unwrap_or(BENCH_GOP_SECONDS);
+    if env::var("SCREEN_CASTER_BIND").is_err() {
+        env::set_var("SCREEN_CASTER_BIND", "127.0.0.1");
+    }
+
+    println!("Ingresso di {clients} client, GOP di {gop_seconds} s a {BENCH_FRAMERATE} fps");
+    for cache_size in [0, DEFAULT_JOIN_CACHE_SIZE] {
+        env::set_var("SCREEN_CASTER_JOIN_CACHE", cache_size.to_string());
+        // The cache configured may be cut to fit the queue size
+        let cache_size = utils::read_network_config().join_cache_size;
+        let mut join_times = measure(clients, gop_seconds);
+        join_times.sort_unstable();
+        let title = if cache_size == 0 { "Senza cache".to_string() } else { format!("Con cache di {cache_size} pacchetti") };
+        match (join_times.get(join_times.len() / 2), join_times.last()) {
+            (Some(median), Some(longest)) => println!("{title}: ingresso in p50 {:.1} ms, max {:.1} ms ({} client su {clients})",
+                median.as_secs_f64() * 1000.0, longest.as_secs_f64() * 1000.0, join_times.len()),
+            _ => println!("{title}: nessun client ha iniziato a decodificare"),
+        }
+    }
+    env::set_var("SCREEN_CASTER_JOIN_CACHE", DEFAULT_JOIN_CACHE_SIZE.to_string());
+    let mut switch_times = measure_switch(clients, gop_seconds);
+    switch_times.sort_unstable();
+    match (switch_times.get(switch_times.len() / 2), switch_times.last()) {
+        (Some(median), Some(longest)) => println!("Cambio di livello: p50 {:.1} ms, max {:.1} ms ({} client su {clients})",
+            median.as_secs_f64() * 1000.0, longest.as_secs_f64() * 1000.0, switch_times.len()),
+        _ => println!("Cambio di livello: nessun client ha cambiato livello"),
+    }
+}
+
+// Return the address of the server of the benchmark, on loopback unless a bind address is configured.
+fn server_address() -> SocketAddr {
+    let config = utils::read_network_config();
+    let server_ip = match config.bind_ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(server_ip, config.server_port)
+}
+
+// Run a session and return the join time of each client able to start decoding.
+fn measure(clients: usize, gop_seconds: u64) -> Vec<Duration> {
+    let server_address = server_address();
+
+    let (upstream_tx, upstream_rx) = channel::<Vec<u8>>();
+    let mut server = StreamingServer::new();
+    server.start_relay(vec![upstream_rx], SessionDescriptor::new(utils::host_name(), None, BENCH_FRAMERATE as u32));
+    let feeding = Arc::new(AtomicBool::new(true));
+    let feeding_clone = feeding.clone();
+    let feeder = thread::spawn(move || feed(vec![upstream_tx], gop_seconds, &feeding_clone));
+
+    let timeout = Duration::from_secs(2 * gop_seconds + 1);
+    let mut join_times = Vec::new();
+    let gop = Duration::from_secs(gop_seconds);
+    for client in 0..clients {
+        thread::sleep(gop * client as u32 / clients as u32);
+        if let Some(join_time) = join(server_address, timeout) {
+            join_times.push(join_time);
+        }
+    }
+
+    feeding.store(false, Ordering::Relaxed);
+    let _ = feeder.join();
+    server.stop();
+    join_times
+}
+
+// Run a simulcast session of two layers and return the time each client takes to switch from the first layer to the second one.
+fn measure_switch(clients: usize, gop_seconds: u64) -> Vec<Duration> {
+    let server_address = server_address();
+
+    let (upstream_txs, upstream_rxs): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::<Vec<u8>>()).unzip();
+    let mut descriptor = SessionDescriptor::new(utils::host_name(), None, BENCH_FRAMERATE as u32);
+    descriptor.layers = vec![720, 360];
+    let mut server = StreamingServer::new();
+    server.start_relay(upstream_rxs, descriptor);
+    let feeding = Arc::new(AtomicBool::new(true));
+    let feeding_clone = feeding.clone();
+    let feeder = thread::spawn(move || feed(upstream_txs, gop_seconds, &feeding_clone));
+
+    let timeout = Duration::from_secs(2 * gop_seconds + 1);
+    let mut switch_times = Vec::new();
+    let gop = Duration::from_secs(gop_seconds);
+    for client in 0..clients {
+        if let Some(switch_time) = switch(server_address, gop * client as u32 / clients as u32, timeout) {
+            switch_times.push(switch_time);
+        }
+    }
+
+    feeding.store(false, Ordering::Relaxed);
+    let _ = feeder.join();
+    server.stop();
+    switch_times
+}
+
+// Send the synthetic stream to the relay one frame at a time, on each of the given channels, until the benchmark is over.
+fn feed(upstream_txs: Vec<Sender<Vec<u8>>>, gop_seconds: u64, feeding: &AtomicBool) {
+    let start = Instant::now();
+    let mut frames = 0u64;
+    let mut continuity = 0u8;
+    while feeding.load(Ordering::Relaxed) {
+        let keyframe = frames.is_multiple_of(gop_seconds * BENCH_FRAMERATE);
+        let mut frame = Vec::with_capacity((FRAME_PACKETS + 2) * TS_PACKET_SIZE);
+        if keyframe {
+            frame.extend_from_slice(&ts_packet(PAT_PID, true, false, 0));
+            frame.extend_from_slice(&ts_packet(PMT_PID, true, false, 0));
+        }
+        for n in 0..FRAME_PACKETS {
+            frame.extend_from_slice(&ts_packet(VIDEO_PID, n == 0, keyframe && n == 0, continuity));
+            continuity = (continuity + 1) % 16;
+        }
+        if upstream_txs.iter().any(|upstream_tx| upstream_tx.send(frame.clone()).is_err()) {
+            break;
+        }
+        frames += 1;
+        let next = Duration::from_micros(frames * 1_000_000 / BENCH_FRAMERATE);
+        if let Some(wait) = next.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+// Build a TS packet of the given PID, starting a keyframe with an adaptation field setting the random access indicator if asked.
+fn ts_packet(pid: u16, unit_start: bool, random_access: bool, continuity: u8) -> [u8; TS_PACKET_SIZE] {
+    let mut packet = [0xFF; TS_PACKET_SIZE];
+    packet[0] = TS_SYNC_BYTE;
+    packet[1] = (if unit_start { 0x40 } else { 0x00 }) | (pid >> 8) as u8 & 0x1F;
+    packet[2] = pid as u8;
+    if random_access {
+        packet[3] = 0x30 | continuity;
+        packet[4] = 1;
+        packet[5] = 0x40;
+    } else {
+        packet[3] = 0x10 | continuity;
+    }
+    packet
+}
+
+// Connect to the server like a StreamingClient with an open session and no FEC, and receive until a decoder could start from the data received.
+fn join(server_address: SocketAddr, timeout: Duration) -> Option<Duration> {
+    let socket = UdpSocket::bind(SocketAddr::new(server_address.ip(), 0)).ok()?;
+    let _ = socket.set_read_timeout(Some(Duration::from_millis(100)));
+    let port = socket.local_addr().ok()?.port();
+
+    let start = start_request(port);
+    let requested = Instant::now();
+    let _ = socket.send_to(&start, server_address);
+    let join_time = receive_decodable(&socket, server_address, &start, None, timeout).map(|_| requested.elapsed());
+
+    let stop = crypto::encode_request(&ControlMessage::new("STOP").with_param("port", port), None);
+    let _ = socket.send_to(&stop, server_address);
+    join_time
+}
+
+// Connect to the first layer of the server like join, wait the given time after starting to decode it,
+// then ask for the second layer and receive until a decoder could start from its data.
+fn switch(server_address: SocketAddr, wait: Duration, timeout: Duration) -> Option<Duration> {
+    let socket = UdpSocket::bind(SocketAddr::new(server_address.ip(), 0)).ok()?;
+    let _ = socket.set_read_timeout(Some(Duration::from_millis(100)));
+    let port = socket.local_addr().ok()?.port();
+
+    let start = start_request(port);
+    let _ = socket.send_to(&start, server_address);
+    let switch_time = receive_decodable(&socket, server_address, &start, None, timeout).and_then(|stream_id| {
+        // Keep receiving the first layer meanwhile, as a client watching it would
+        let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+        let watching = Instant::now();
+        while watching.elapsed() < wait {
+            let _ = socket.recv_from(&mut buffer);
+        }
+        let layer = crypto::encode_request(&ControlMessage::new("LAYER").with_param("layer", 1), None);
+        let requested = Instant::now();
+        let _ = socket.send_to(&layer, server_address);
+        // The packets of each layer carry the stream id of the first one plus the index of the layer
+        receive_decodable(&socket, server_address, &layer, Some(stream_id.wrapping_add(1)), timeout).map(|_| requested.elapsed())
+    });
+
+    let stop = crypto::encode_request(&ControlMessage::new("STOP").with_param("port", port), None);
+    let _ = socket.send_to(&stop, server_address);
+    switch_time
+}
+
+// Build the "START" request of a client receiving on the given port.
+fn start_request(port: u16) -> Vec<u8> {
+    crypto::encode_request(&ControlMessage::new("START").with_param("fec", 0).with_param("port", port).with_param("version", session::SESSION_VERSION), None)
+}
+
+// Receive the data packets of the given stream, of any stream if None, until a decoder could start from them: a keyframe received
+// after the PAT, without gaps in between. The request is sent again while no data packet arrives, since it or its reply may have been lost.
+// Return the stream id of the data received, None if the timeout expires first.
+fn receive_decodable(socket: &UdpSocket, server_address: SocketAddr, request: &[u8], stream_id: Option<u32>, timeout: Duration) -> Option<u32> {
+    let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+    let started = Instant::now();
+    // The decoder needs the PAT first, then a keyframe, and restarts from the next PAT after a gap
+    let mut next_sequence = None;
+    let mut seen_pat = false;
+    while started.elapsed() < timeout {
+        let n = match socket.recv_from(&mut buffer) {
+            Ok((n, _)) => n,
+            Err(_) if next_sequence.is_none() => {
+                let _ = socket.send_to(request, server_address);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let (header, payload) = match PacketHeader::decode(&buffer[..n]) {
+            Some((header, payload)) if header.packet_type == PacketType::Data && stream_id.is_none_or(|id| id == header.stream_id) => (header, payload),
+            _ => continue,
+        };
+        if next_sequence.is_some_and(|next| next != header.sequence) {
+            seen_pat = false;
+        }
+        next_sequence = Some(header.sequence.wrapping_add(1));
+        seen_pat |= rtp::contains_pat(payload);
+        if seen_pat && rtp::contains_keyframe(payload) {
+            return Some(header.stream_id);
+        }
+    }
+    None
+}
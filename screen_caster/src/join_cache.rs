@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use crate::client_queue::{ClientQueue, Fanout, OverflowPolicy, QueuedPacket, DEFAULT_QUEUE_SIZE};
+
+/// This module contains the JoinCache, which lets a client joining a casting session start decoding at once instead of waiting for the next keyframe.
+/// The fan-out of the StreamingServer publishes the data packets through the cache, which keeps the ones sent since the most recent keyframe,
+/// starting from the packet carrying the PAT that precedes it: ffmpeg writes the PAT and the PMT right before every keyframe,
+/// and x264 repeats the SPS and the PPS in front of every IDR frame when muxing to MPEG-TS, so the packets cached are decodable on their own.
+/// The ClientQueue of a new client is filled with the cached packets, then receives the ones published from then on:
+/// publishing and subscribing take the same lock, so that no packet is missed nor received twice.
+/// At most the configured number of packets is cached: a GOP longer than that is dropped, and the clients joining before the next keyframe
+/// wait for it as if there were no cache. While a new client is sent the cached packets, the ones published meanwhile wait in the fan-out,
+/// which keeps queue_size of them: the cache is kept shorter than the queue (see capacity), so that a client doesn't lag behind as soon as it joins.
+/// A size of 0 disables the cache. In multicast mode the stream is not published on the fan-out,
+/// so the viewers joining the group wait for the next keyframe, one GOP (see utils::get_ffmpeg_command) at most.
+/// Each layer of a simulcast session has its own cache, so that switching layer is a join as well (see the simulcast module).
+/// The join time can be measured with the benchmark of the join_bench module.
+
+/// Default size of the cache, the largest the default queue size allows.
+pub const DEFAULT_JOIN_CACHE_SIZE: usize = capacity(usize::MAX, DEFAULT_QUEUE_SIZE);
+// Part of the queue of a client left free for the packets published while the cached ones are sent
+const QUEUE_HEADROOM_DIVISOR: usize = 4;
+
+/// Return the number of packets the cache can keep for clients with the given queue size: the configured one,
+/// at most the queue size minus a quarter of it.
+pub const fn capacity(join_cache_size: usize, queue_size: usize) -> usize {
+    let limit = queue_size - queue_size / QUEUE_HEADROOM_DIVISOR;
+    if join_cache_size < limit { join_cache_size } else { limit }
+}
+
+// Packets kept by the cache.
+// - since_pat: the packets published since the most recent one carrying the PAT
+// - gop: the packets a new client receives first, from the PAT preceding the most recent keyframe
+struct CachedPackets {
+    since_pat: VecDeque<Arc<QueuedPacket>>,
+    gop: Vec<Arc<QueuedPacket>>,
+}
+
+pub struct JoinCache {
+    fanout: Fanout,
+    capacity: usize,
+    packets: Mutex<CachedPackets>,
+}
+
+impl JoinCache {
+    pub fn new(fanout: Fanout, capacity: usize) -> Self {
+        JoinCache {
+            fanout,
+            capacity,
+            packets: Mutex::new(CachedPackets { since_pat: VecDeque::new(), gop: Vec::new() }),
+        }
+    }
+
+    /// Publish a packet to the clients, caching it for the ones joining later. Fails only when nobody is watching.
+    pub fn publish(&self, packet: Arc<QueuedPacket>) -> bool {
+        let mut packets = self.packets.lock().unwrap();
+        if self.capacity > 0 {
+            if packet.pat {
+                packets.since_pat.clear();
+            }
+            packets.since_pat.push_back(packet.clone());
+            if packets.since_pat.len() > self.capacity {
+                packets.since_pat.pop_front();
+            }
+            if packet.keyframe {
+                // The cache is only useful if the decoder can find the PAT before the keyframe
+                packets.gop = match packets.since_pat.front() {
+                    Some(first) if first.pat => packets.since_pat.iter().cloned().collect(),
+                    _ => Vec::new(),
+                };
+            } else if !packets.gop.is_empty() {
+                packets.gop.push(packet.clone());
+                if packets.gop.len() > self.capacity {
+                    packets.gop.clear();
+                }
+            }
+        }
+        self.fanout.send(packet).is_ok()
+    }
+
+    /// Subscribe a new client: its queue starts with the packets cached, followed by the ones published from now on.
+    pub fn subscribe(&self, policy: OverflowPolicy) -> ClientQueue {
+        let packets = self.packets.lock().unwrap();
+        ClientQueue::subscribe(&self.fanout, policy).with_backlog(packets.gop.iter().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::{self, Instant};
+    use crate::client_queue::{self, QueueEvent};
+
+    const QUEUE_SIZE: usize = 64;
+    // The stream is published a packet every PACKET_INTERVAL, and sent at the same rate to a client that just keeps up with it
+    const PACKET_INTERVAL: Duration = Duration::from_millis(2);
+
+    fn packet(index: usize, gop: usize) -> Arc<QueuedPacket> {
+        let start = index.is_multiple_of(gop);
+        Arc::new(QueuedPacket { packet: index.to_be_bytes().to_vec(), keyframe: start, pat: start })
+    }
+
+    // Join a session after the given number of packets of a stream with GOPs of the given length, return the time the client took
+    // to receive a keyframe and the packets it dropped over the next two GOPs.
+    async fn join(cache_size: usize, gop: usize, joined_after: usize) -> (Duration, u64) {
+        let cache = JoinCache::new(client_queue::fanout(QUEUE_SIZE), cache_size);
+        for index in 0..joined_after {
+            cache.publish(packet(index, gop));
+        }
+        let mut queue = cache.subscribe(OverflowPolicy::DropUntilKeyframe);
+        let stats = queue.stats();
+
+        // The fan-out is closed once the publisher is done
+        let publisher = tokio::spawn(async move {
+            for index in joined_after..joined_after + 2 * gop {
+                cache.publish(packet(index, gop));
+                time::sleep(PACKET_INTERVAL).await;
+            }
+        });
+        let joined = Instant::now();
+        let mut join_time = None;
+        while let QueueEvent::Packet(packet) = queue.next().await {
+            if packet.keyframe && join_time.is_none() {
+                join_time = Some(joined.elapsed());
+            }
+            time::sleep(PACKET_INTERVAL).await;
+        }
+        publisher.await.unwrap();
+        (join_time.unwrap(), stats.dropped())
+    }
+
+    #[test]
+    fn cache_leaves_a_quarter_of_the_queue_free() {
+        assert_eq!(capacity(100, 1024), 100);
+        assert_eq!(capacity(1024, 1024), 768);
+        assert_eq!(capacity(0, 1024), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_join_at_once_without_lagging_behind() {
+        let cache_size = capacity(usize::MAX, QUEUE_SIZE);
+        // Without the cache a client joining in the middle of a GOP waits for the next keyframe, with the cache it starts at once
+        assert_eq!(join(0, 40, 20).await, (20 * PACKET_INTERVAL, 0));
+        assert_eq!(join(cache_size, 40, 20).await, (Duration::ZERO, 0));
+        assert_eq!(join(cache_size, 40, 39).await, (Duration::ZERO, 0));
+
+        // A backlog longer than the queue makes the client lag behind while it catches up, dropping packets until the next keyframe
+        let (join_time, dropped) = join(QUEUE_SIZE + QUEUE_SIZE / 2, 80, 79).await;
+        assert_eq!(join_time, Duration::ZERO);
+        assert!(dropped > 0);
+        // A GOP longer than the cache allowed is not cached, the client waits for the next keyframe without dropping anything
+        assert_eq!(join(cache_size, 80, 79).await, (PACKET_INTERVAL, 0));
+    }
+}
@@ -0,0 +1,102 @@
+use std::env;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+#[cfg(target_os = "linux")]
+use crate::batch_io;
+
+/// This module contains the LossySocket struct, a wrapper around a UdpSocket that silently drops
+/// a given percentage of the outgoing datagrams. It is used to reproduce a bad network on loopback
+/// and check how the FEC layer and the client behave when packets go missing.
+/// The percentage is read from the SCREEN_CASTER_LOSS environment variable, by default no packet is dropped.
+/// The socket is either a blocking std UdpSocket or a tokio UdpSocket, used by the async core of the StreamingServer.
+
+pub const LOSS_ENV_VARIABLE: &str = "SCREEN_CASTER_LOSS";
+
+pub struct LossySocket<S = UdpSocket> {
+    socket: S,
+    loss_percentage: u32,
+    // State of the xorshift generator used to pick the packets to drop
+    random_state: Mutex<u64>,
+}
+
+impl<S> LossySocket<S> {
+    pub fn new(socket: S, loss_percentage: u32) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        LossySocket {
+            socket,
+            loss_percentage: loss_percentage.min(100),
+            random_state: Mutex::new(seed | 1),
+        }
+    }
+
+    /// Wrap the socket using the loss percentage set in the environment, if any.
+    pub fn from_env(socket: S) -> Self {
+        let loss_percentage = env::var(LOSS_ENV_VARIABLE)
+            .ok()
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .unwrap_or(0);
+        LossySocket::new(socket, loss_percentage)
+    }
+
+    fn should_drop(&self) -> bool {
+        if self.loss_percentage == 0 {
+            return false;
+        }
+        let mut state = self.random_state.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state % 100) < self.loss_percentage as u64
+    }
+}
+
+impl LossySocket<UdpSocket> {
+    /// Send a datagram, unless it is picked to be dropped. Dropped datagrams are reported as sent.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        if self.should_drop() {
+            return Ok(buf.len());
+        }
+        self.socket.send_to(buf, addr)
+    }
+}
+
+impl LossySocket<tokio::net::UdpSocket> {
+    /// Send a datagram, unless it is picked to be dropped. Dropped datagrams are reported as sent.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if self.should_drop() {
+            return Ok(buf.len());
+        }
+        self.socket.send_to(buf, addr).await
+    }
+
+    /// Send a batch of datagrams to the same address, with a single system call where batches are supported, see the batch_io module.
+    /// Return the bytes sent, dropped datagrams are reported as sent.
+    pub async fn send_batch(&self, datagrams: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+        let bytes = datagrams.iter().map(|datagram| datagram.len()).sum();
+        let kept = datagrams.iter().copied().filter(|_| !self.should_drop()).collect::<Vec<&[u8]>>();
+        #[cfg(target_os = "linux")]
+        {
+            let mut sent = 0;
+            while sent < kept.len() {
+                sent += self.socket.async_io(Interest::WRITABLE, || batch_io::send_batch(&self.socket, &kept[sent..], addr)).await?;
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for datagram in kept {
+            self.socket.send_to(datagram, addr).await?;
+        }
+        Ok(bytes)
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
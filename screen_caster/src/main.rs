@@ -1,5 +1,6 @@
 #![windows_subsystem = "windows"]
 
+use std::env;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use global_hotkey::GlobalHotKeyManager;
@@ -15,8 +16,49 @@ mod streaming_client;
 mod streaming_server;
 mod streamers_table;
 mod error_banner;
+mod protocol;
+mod fec;
+mod lossy_socket;
+mod nack;
+mod crypto;
+mod discovery;
+mod rate_control;
+mod multicast;
+mod transport;
+mod rtp;
+mod rtsp;
+mod hls;
+mod pacing;
+mod client_queue;
+mod fanout_bench;
+mod ts_chunker;
+mod batch_io;
+mod throughput_bench;
+mod probe;
+mod session;
+mod join_cache;
+mod join_bench;
+mod simulcast;
 
 fn main() {
+    // Run the benchmark of the fan-out of the server instead of the GUI, see the fanout_bench module
+    let args = env::args().collect::<Vec<String>>();
+    if args.get(1).map(String::as_str) == Some(fanout_bench::BENCH_FLAG) {
+        fanout_bench::run(&args[2..]);
+        return;
+    }
+    // Run the benchmark of the packetization over loopback instead of the GUI, see the throughput_bench module
+    if args.get(1).map(String::as_str) == Some(throughput_bench::BENCH_FLAG) {
+        throughput_bench::run(&args[2..]);
+        return;
+    }
+
+    // Run the benchmark of the join time of the viewers instead of the GUI, see the join_bench module
+    if args.get(1).map(String::as_str) == Some(join_bench::BENCH_FLAG) {
+        join_bench::run(&args[2..]);
+        return;
+    }
+
     // Flag to stop the hotkey thread
     let running = Arc::new(Mutex::new(true));
     let running_clone = Arc::clone(&running);
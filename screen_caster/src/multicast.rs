@@ -0,0 +1,48 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// This module contains the sockets used by the multicast delivery mode.
+/// When a multicast group is configured, the fan-out thread of the StreamingServer sends every data and parity packet
+/// once to the group instead of once per client, and the "OK" reply to "START" carries the group in the "multicast" parameter:
+///
+/// OK
+/// fec=8
+/// multicast=239.255.42.1:5004
+///
+/// The StreamingClient then joins the group on the interface it is bound to, with a reader thread per socket forwarding the datagrams to its socket manager. The control traffic ("START", "HEARTBEAT", "NACK",
+/// "REPORT", "STOP") and the retransmissions stay unicast. Datagrams are sent with a TTL of 1, so they never leave the LAN,
+/// and are looped back to the sending machine, so that the mode can be tried on the loopback interface.
+
+const MULTICAST_TTL: u32 = 1;
+
+fn interface_v4(interface: IpAddr) -> Ipv4Addr {
+    match interface {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    }
+}
+
+/// Open the socket used by the server to send to a multicast group through the given interface.
+pub fn sender_socket(interface: IpAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::new(interface, 0))?;
+    let socket = Socket::from(socket);
+    socket.set_multicast_if_v4(&interface_v4(interface))?;
+    socket.set_multicast_ttl_v4(MULTICAST_TTL)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+/// Open a socket receiving the datagrams sent to a multicast group, joined on the given interface.
+/// The port can be shared by more clients running on the same machine.
+pub fn join_group(group: SocketAddr, interface: IpAddr) -> io::Result<UdpSocket> {
+    let group_ip = match group.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "IPv6 multicast groups are not supported")),
+    };
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), group.port()).into())?;
+    socket.join_multicast_v4(&group_ip, &interface_v4(interface))?;
+    Ok(socket.into())
+}
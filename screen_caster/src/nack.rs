@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// This module implements the selective retransmission of lost data packets.
+/// The client looks at the gaps in the SequenceTracker and sends the missing sequence ranges to the server
+/// with a "NACK" control message, together with a deadline expressed in stream time (milliseconds since the stream started):
+///
+/// NACK
+/// ranges=120-122,130-130
+/// deadline=45210
+///
+/// The server keeps the most recent data packets in a bounded RetransmissionHistory and sends again the requested
+/// ones, unless the deadline has already passed, since the decoder of the client has moved past them by then.
+/// A NACK without a valid deadline is always served. The fan-out thread of each layer keeps its own history, and the retransmissions
+/// count against the total upload budget (see the pacing module). The client only sends NACKs to a server announcing the "nack" feature.
+/// Ranges never wrap around the end of the sequence space: the client splits such a range in two, so first <= last in every range.
+
+/// Number of data packets kept by the server to be retransmitted.
+pub const HISTORY_SIZE: usize = 512;
+/// Time, in milliseconds of stream time, after the newest received packet in which a retransmission is still useful.
+pub const NACK_DEADLINE_MS: u32 = 200;
+/// Maximum number of packets requested with a single NACK message.
+pub const MAX_NACK_PACKETS: usize = 64;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(40);
+const MAX_ATTEMPTS: u8 = 3;
+
+/// Format a list of sequence ranges as "first-last,first-last".
+pub fn format_ranges(ranges: &[(u32, u32)]) -> String {
+    ranges.iter().map(|(first, last)| format!("{first}-{last}")).collect::<Vec<String>>().join(",")
+}
+
+/// Parse a list of sequence ranges, invalid ranges are skipped.
+pub fn parse_ranges(text: &str) -> Vec<(u32, u32)> {
+    text.split(',')
+        .filter_map(|range| {
+            let (first, last) = range.split_once('-')?;
+            let first = first.trim().parse::<u32>().ok()?;
+            let last = last.trim().parse::<u32>().ok()?;
+            if first <= last { Some((first, last)) } else { None }
+        })
+        .collect()
+}
+
+/// Tell whether the retransmissions requested with the given "deadline" parameter are too late at the given stream time, in milliseconds.
+/// A missing or invalid deadline never expires.
+pub fn is_expired(deadline: Option<&str>, stream_time_ms: u32) -> bool {
+    deadline.and_then(|deadline| deadline.trim().parse::<u32>().ok()).is_some_and(|deadline| stream_time_ms > deadline)
+}
+
+/// Merge a list of sequence numbers, in stream order, into ranges of consecutive numbers, split where the sequence wraps around.
+fn to_ranges(sequences: &[u32]) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &sequence in sequences {
+        match ranges.last_mut() {
+            Some((_, last)) if last.checked_add(1) == Some(sequence) => *last = sequence,
+            _ => ranges.push((sequence, sequence)),
+        }
+    }
+    ranges
+}
+
+/// The NackScheduler is used by the client to decide which missing packets have to be requested.
+/// A packet is requested as soon as it goes missing, then again every RETRY_INTERVAL up to MAX_ATTEMPTS times.
+pub struct NackScheduler {
+    requested: HashMap<u32, (Instant, u8)>,
+}
+
+impl NackScheduler {
+    pub fn new() -> Self {
+        NackScheduler {
+            requested: HashMap::new(),
+        }
+    }
+
+    /// Return the ranges to be requested now, given the ranges currently missing.
+    pub fn poll(&mut self, missing: &[(u32, u32)], now: Instant) -> Vec<(u32, u32)> {
+        let mut to_request = Vec::new();
+        let mut still_missing = HashMap::new();
+
+        'ranges: for &(first, last) in missing {
+            for sequence in first..=last {
+                let entry = match self.requested.get(&sequence) {
+                    None => {
+                        to_request.push(sequence);
+                        (now, 1)
+                    }
+                    Some(&(last_request, attempts)) if attempts < MAX_ATTEMPTS && now.duration_since(last_request) >= RETRY_INTERVAL => {
+                        to_request.push(sequence);
+                        (now, attempts + 1)
+                    }
+                    Some(&entry) => entry,
+                };
+                still_missing.insert(sequence, entry);
+                if to_request.len() >= MAX_NACK_PACKETS {
+                    break 'ranges;
+                }
+            }
+        }
+
+        // Forget the packets received in the meantime or given up by the tracker
+        self.requested = still_missing;
+        to_ranges(&to_request)
+    }
+}
+
+/// RetransmissionStats struct contains the per client retransmission counters kept by the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetransmissionStats {
+    pub nacks: u64,
+    pub retransmitted: u64,
+    pub expired: u64,
+}
+
+/// The RetransmissionHistory is a bounded ring of the most recent data packets sent by the server, indexed by sequence number.
+pub struct RetransmissionHistory {
+    packets: VecDeque<(u32, Vec<u8>)>,
+    capacity: usize,
+}
+
+impl RetransmissionHistory {
+    pub fn new(capacity: usize) -> Self {
+        RetransmissionHistory {
+            packets: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Store a framed data packet, dropping the oldest one if the ring is full.
+    pub fn push(&mut self, sequence: u32, packet: Vec<u8>) {
+        if self.packets.len() == self.capacity {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((sequence, packet));
+    }
+
+    pub fn get(&self, sequence: u32) -> Option<&Vec<u8>> {
+        // Packets are stored with consecutive sequence numbers, so the position can be computed from the first one,
+        // even across the wrap; a sequence before the first one gives a position far beyond the end of the ring
+        let &(first, _) = self.packets.front()?;
+        let (stored, packet) = self.packets.get(sequence.wrapping_sub(first) as usize)?;
+        if *stored == sequence { Some(packet) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_without_a_deadline_never_expire() {
+        assert!(!is_expired(Some("45210"), 45210));
+        assert!(is_expired(Some("45210"), 45211));
+        assert!(!is_expired(None, u32::MAX));
+        assert!(!is_expired(Some(""), u32::MAX));
+        assert!(!is_expired(Some("soon"), u32::MAX));
+    }
+
+    #[test]
+    fn ranges_do_not_merge_across_the_wrap() {
+        assert_eq!(to_ranges(&[u32::MAX - 1, u32::MAX, 0, 1, 3]), vec![(u32::MAX - 1, u32::MAX), (0, 1), (3, 3)]);
+        assert_eq!(parse_ranges(&format_ranges(&[(u32::MAX - 1, u32::MAX), (0, 1)])), vec![(u32::MAX - 1, u32::MAX), (0, 1)]);
+    }
+
+    #[test]
+    fn history_finds_packets_across_the_wrap() {
+        let mut history = RetransmissionHistory::new(4);
+        for sequence in [u32::MAX - 2, u32::MAX - 1, u32::MAX, 0, 1] {
+            history.push(sequence, sequence.to_be_bytes().to_vec());
+        }
+        assert_eq!(history.get(u32::MAX), Some(&u32::MAX.to_be_bytes().to_vec()));
+        assert_eq!(history.get(1), Some(&1u32.to_be_bytes().to_vec()));
+        // Already dropped from the ring, and not sent yet
+        assert_eq!(history.get(u32::MAX - 2), None);
+        assert_eq!(history.get(2), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::rate_control::EncoderTarget;
+use crate::transport::BatchSender;
+
+/// This module implements the pacing of the datagrams sent by the StreamingServer.
+/// Instead of sending a keyframe in a single burst, the task sending to each client asks its Pacer how long to wait before each packet:
+/// the packets of a client are spread at PACING_FACTOR times the bitrate of the encoder target, with a burst of at most one frame interval,
+/// further limited by the per-client rate and by the total upload budget shared by all the clients, if configured.
+/// The roster shows, for each client, how long its last packet had to wait. Both limits are enforced with a TokenBucket. The time is always passed by the caller, so that the pacing does not depend on the clock.
+/// send_paced sends the batches of a client task through any BatchSender, reading the clock of the runtime, so that it can be paused in tests.
+
+/// Clients are paced at this factor times the bitrate of the encoder, so that they keep up with the stream while the bursts are smoothed.
+pub const PACING_FACTOR: f64 = 2.0;
+/// Burst allowed by the total upload budget.
+pub const TOTAL_BURST: Duration = Duration::from_millis(50);
+
+// Convert a rate in kbit/s to bytes per second.
+fn bytes_per_second(kbps: u32) -> f64 {
+    kbps as f64 * 1000.0 / 8.0
+}
+
+/// The TokenBucket lets a given number of bytes per second through, allowing bursts up to its capacity.
+/// Tokens are taken as soon as a packet is scheduled and may go negative: the packet then has to wait until the debt is paid back.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket with the given rate in bytes per second and the given capacity in bytes.
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Create the bucket enforcing an upload budget in kbit/s, allowing bursts of the given duration.
+    pub fn with_limit(limit_kbps: u32, burst: Duration, now: Instant) -> Self {
+        let rate = bytes_per_second(limit_kbps);
+        TokenBucket::new(rate, rate * burst.as_secs_f64(), now)
+    }
+
+    pub fn set_rate(&mut self, rate: f64, capacity: f64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.capacity = capacity;
+        self.tokens = self.tokens.min(capacity);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = self.last.max(now);
+    }
+
+    /// Take the tokens of a packet of the given size, returning how long it has to wait before being sent (zero to send it now).
+    pub fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// The Pacer schedules the packets sent to a client, following the encoder target, the per-client limit and the shared upload budget.
+pub struct Pacer {
+    client: TokenBucket,
+    client_limit_kbps: Option<u32>,
+    total: Option<Arc<Mutex<TokenBucket>>>,
+    target: EncoderTarget,
+}
+
+impl Pacer {
+    pub fn new(client_limit_kbps: Option<u32>, total: Option<Arc<Mutex<TokenBucket>>>, target: EncoderTarget, now: Instant) -> Self {
+        let (rate, capacity) = Pacer::client_rate(client_limit_kbps, target);
+        Pacer {
+            client: TokenBucket::new(rate, capacity, now),
+            client_limit_kbps,
+            total,
+            target,
+        }
+    }
+
+    // Return the rate of a client in bytes per second and the burst allowed, the bytes of a frame interval at that rate.
+    fn client_rate(client_limit_kbps: Option<u32>, target: EncoderTarget) -> (f64, f64) {
+        let mut rate = bytes_per_second(target.bitrate_kbps) * PACING_FACTOR;
+        if let Some(limit) = client_limit_kbps {
+            rate = rate.min(bytes_per_second(limit));
+        }
+        (rate, rate / target.framerate.max(1) as f64)
+    }
+
+    /// Follow a change of the encoder target.
+    pub fn set_target(&mut self, target: EncoderTarget, now: Instant) {
+        if target != self.target {
+            let (rate, capacity) = Pacer::client_rate(self.client_limit_kbps, target);
+            self.client.set_rate(rate, capacity, now);
+            self.target = target;
+        }
+    }
+
+    /// Schedule a packet of the given size, returning how long it has to wait before being sent.
+    pub fn delay(&mut self, bytes: usize, now: Instant) -> Duration {
+        let client_delay = self.client.reserve(bytes, now);
+        let total_delay = self.total.as_ref().map_or(Duration::ZERO, |total| total.lock().unwrap().reserve(bytes, now));
+        client_delay.max(total_delay)
+    }
+}
+
+/// Send a batch of datagrams to a client on the runtime, as many at a time as its pacer lets go, returning the bytes sent and the time waited.
+pub async fn send_paced<S: BatchSender>(sender: &S, target: SocketAddr, datagrams: &[&[u8]], pacer: &mut Pacer) -> (usize, Duration) {
+    let mut sent = 0;
+    let mut waited = Duration::ZERO;
+    let mut first = 0;
+    for (i, datagram) in datagrams.iter().enumerate() {
+        let delay = pacer.delay(datagram.len(), tokio::time::Instant::now().into_std());
+        if !delay.is_zero() {
+            // Send the datagrams already let go, then wait for this one
+            if first < i {
+                sent += sender.send_batch_to(&datagrams[first..i], target).await.unwrap_or(0);
+            }
+            first = i;
+            tokio::time::sleep(delay).await;
+            waited += delay;
+        }
+    }
+    if first < datagrams.len() {
+        sent += sender.send_batch_to(&datagrams[first..], target).await.unwrap_or(0);
+    }
+    (sent, waited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    const TARGET: EncoderTarget = EncoderTarget { bitrate_kbps: 1000, framerate: 25, height: None };
+
+    fn ms(milliseconds: u64) -> Duration {
+        Duration::from_millis(milliseconds)
+    }
+
+    #[test]
+    fn bucket_lets_a_burst_through_then_paces_at_its_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100_000.0, 1000.0, start);
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), ms(5));
+        // The debt is paid back after 5 ms, the next packet waits for its own tokens only
+        assert_eq!(bucket.reserve(500, start + ms(5)), ms(5));
+        // Idle time refills the bucket up to its capacity, not beyond
+        assert_eq!(bucket.reserve(1000, start + ms(1000)), Duration::ZERO);
+        assert_eq!(bucket.reserve(100, start + ms(1000)), ms(1));
+    }
+
+    #[test]
+    fn bucket_ignores_a_clock_going_back() {
+        let start = Instant::now() + ms(100);
+        let mut bucket = TokenBucket::new(100_000.0, 1000.0, start);
+        assert_eq!(bucket.reserve(1500, start), ms(5));
+        assert_eq!(bucket.reserve(0, start - ms(50)), ms(5));
+    }
+
+    #[test]
+    fn pacer_follows_the_target_and_the_limits() {
+        let start = Instant::now();
+        // 2 x 1000 kbit/s = 250000 bytes/s, with a burst of a frame interval: 10000 bytes
+        let mut pacer = Pacer::new(None, None, TARGET, start);
+        assert_eq!(pacer.delay(10_000, start), Duration::ZERO);
+        assert_eq!(pacer.delay(1000, start), ms(4));
+
+        // The per-client limit of 400 kbit/s lowers the rate to 50000 bytes/s
+        let mut pacer = Pacer::new(Some(400), None, TARGET, start);
+        assert_eq!(pacer.delay(2000, start), Duration::ZERO);
+        assert_eq!(pacer.delay(1000, start), ms(20));
+
+        // The shared budget is taken by every pacer using it
+        let total = Arc::new(Mutex::new(TokenBucket::with_limit(800, ms(10), start)));
+        let mut first = Pacer::new(None, Some(total.clone()), TARGET, start);
+        let mut second = Pacer::new(None, Some(total), TARGET, start);
+        assert_eq!(first.delay(1000, start), Duration::ZERO);
+        assert_eq!(second.delay(1000, start), ms(10));
+
+        // A lower target shrinks the burst at once
+        let mut pacer = Pacer::new(None, None, TARGET, start);
+        pacer.set_target(EncoderTarget { bitrate_kbps: 500, ..TARGET }, start);
+        assert_eq!(pacer.delay(6000, start), ms(8));
+    }
+
+    // FakeSocket records the batches sent and the time of the runtime they were sent at.
+    struct FakeSocket {
+        batches: Mutex<Vec<(Duration, usize)>>,
+        start: tokio::time::Instant,
+    }
+
+    impl BatchSender for FakeSocket {
+        async fn send_batch_to(&self, datagrams: &[&[u8]], _: SocketAddr) -> io::Result<usize> {
+            self.batches.lock().unwrap().push((self.start.elapsed(), datagrams.len()));
+            Ok(datagrams.iter().map(|datagram| datagram.len()).sum())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paced_burst_is_sent_as_the_pacer_lets_go() {
+        let socket = FakeSocket { batches: Mutex::new(Vec::new()), start: tokio::time::Instant::now() };
+        let mut pacer = Pacer::new(None, None, TARGET, tokio::time::Instant::now().into_std());
+        let datagram = [0u8; 1000];
+        let datagrams = vec![&datagram[..]; 20];
+
+        let (sent, waited) = send_paced(&socket, "127.0.0.1:9".parse().unwrap(), &datagrams, &mut pacer).await;
+
+        assert_eq!(sent, 20_000);
+        assert_eq!(waited, ms(40));
+        // The burst of a frame interval goes at once, then a datagram every 4 ms
+        let batches = socket.batches.lock().unwrap();
+        assert_eq!(batches[0], (Duration::ZERO, 10));
+        assert_eq!(batches[1..], (1..=10).map(|i| (ms(4 * i), 1)).collect::<Vec<(Duration, usize)>>()[..]);
+    }
+}
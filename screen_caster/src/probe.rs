@@ -0,0 +1,136 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use crate::crypto::{self, Salt};
+use crate::error_banner::InputError;
+use crate::protocol::{self, ControlMessage};
+use crate::transport::{TcpTransport, Transport, TransportKind, UdpTransport};
+use crate::utils::{self, NetworkConfig};
+
+/// This module checks whether a server can be watched before the StreamingClient connects to it.
+/// The host typed in the connect view is an IP address or a host name, resolved with the system resolver
+/// (so /etc/hosts and, where the system supports it, the ".local" names of mDNS work too).
+/// Each address resolved has to be allowed by the network configuration, see utils::check_allowed_address, and is probed with a "PROBE"
+/// request on the transport picked by the viewer: a casting server answers "CASTING", even in a protected session, with a "pin" parameter
+/// telling whether a PIN is required and the "salt" the key of a protected session is derived with (see the crypto module),
+/// or "REFUSED" if the viewer has been banned. The probe tells apart:
+/// - Unreachable: no answer at all, or no route to the host
+/// - Refused: the session refused the viewer
+/// - NotCasting: the host answered, but nothing is casting on the port (the port is closed, or something else listens on it)
+///
+/// The probe runs on its own thread, so the GUI is not blocked while it waits for the answers.
+
+// Time waited for the answer to each probe
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+// Probes sent over UDP before considering the host unreachable, since datagrams may be lost
+const PROBE_ATTEMPTS: usize = 3;
+
+/// ProbedServer struct contains the address of a casting server and, if its session is protected, the salt of the session key.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbedServer {
+    pub address: SocketAddr,
+    pub salt: Option<Salt>,
+}
+
+/// Resolve the host and probe its addresses in turn, returning the first one casting.
+/// If none is casting, the most telling error is returned: refused by the session, then not casting, then unreachable.
+pub fn find_server(host: &str, port: u16, transport: TransportKind) -> Result<ProbedServer, InputError> {
+    let config = utils::read_network_config();
+    let addresses = resolve(host, port)?;
+    let mut error = InputError::NotInAllowedSubnet;
+    for address in addresses {
+        let result = utils::check_allowed_address(address, &config).and_then(|_| probe(address, transport, &config));
+        match result {
+            Ok(salt) => return Ok(ProbedServer { address, salt }),
+            Err(e) => error = most_telling(error, e),
+        }
+    }
+    Err(error)
+}
+
+// Return the addresses of a host, an IP address or a host name.
+fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, InputError> {
+    if let Some(address) = utils::socket_address(host, port) {
+        return Ok(vec![address]);
+    }
+    if !utils::is_valid_host(host) {
+        return Err(InputError::NotAnIp);
+    }
+    let addresses = (host.trim(), port).to_socket_addrs().map_err(|_| InputError::Unresolved)?.collect::<Vec<SocketAddr>>();
+    if addresses.is_empty() {
+        return Err(InputError::Unresolved);
+    }
+    Ok(addresses)
+}
+
+// Probe an address on the given transport, returning the salt of the session key if the session is protected.
+// With the automatic transport, TCP is tried when UDP gets no answer or is refused, since UDP may be blocked by the network.
+fn probe(address: SocketAddr, transport: TransportKind, config: &NetworkConfig) -> Result<Option<Salt>, InputError> {
+    let udp = || {
+        // A server on this machine answers from the loopback address, which the connected socket only accepts from the loopback address
+        let ip = if address.ip().is_loopback() { address.ip() } else { config.bind_ip_for(address.ip()) };
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).map_err(classify)?;
+        // A connected socket reports the ICMP errors, which tell a closed port from a silent host
+        socket.connect(address).map_err(classify)?;
+        probe_transport(&UdpTransport::new(socket, address.to_string()), PROBE_ATTEMPTS)
+    };
+    let tcp = || probe_transport(&TcpTransport::connect(&address.to_string()).map_err(classify)?, 1);
+    match transport {
+        TransportKind::Udp => udp(),
+        TransportKind::Tcp => tcp(),
+        TransportKind::Auto => match udp() {
+            Err(udp_error @ (InputError::Unreachable | InputError::NotCasting)) => tcp().map_err(|tcp_error| most_telling(udp_error, tcp_error)),
+            result => result,
+        },
+    }
+}
+
+// Send the probe and wait for the answer of the server. A protected session announcing no valid salt can't be joined.
+fn probe_transport(transport: &dyn Transport, attempts: usize) -> Result<Option<Salt>, InputError> {
+    let request = crypto::encode_request(&ControlMessage::new("PROBE"), None);
+    let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+    transport.set_read_timeout(Some(PROBE_TIMEOUT)).map_err(classify)?;
+    for _ in 0..attempts {
+        transport.send(&request).map_err(classify)?;
+        match transport.recv(&mut buffer) {
+            Ok(n) => {
+                return match protocol::decode_control(&buffer[..n]) {
+                    Some((_, reply)) if reply.command == "CASTING" => match reply.param("pin") {
+                        Some("1") => reply.param("salt").and_then(crypto::parse_salt).map(Some).ok_or(InputError::IncompatibleVersion),
+                        _ => Ok(None),
+                    },
+                    Some((_, reply)) if reply.command == "REFUSED" => Err(InputError::Refused),
+                    // Something answers on the port, but it is not a casting session
+                    _ => Err(InputError::NotCasting),
+                };
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(classify(e)),
+        }
+    }
+    // The TCP connection has been accepted, so something listens on the port without answering
+    match transport.kind() {
+        TransportKind::Tcp => Err(InputError::NotCasting),
+        _ => Err(InputError::Unreachable),
+    }
+}
+
+// Tell what an error of a socket means for the viewer: a closed port (reported by ICMP for UDP, as a refused connection
+// for TCP, as a reset on Windows) or a connection closed right away means that the host is up but not casting.
+fn classify(error: io::Error) -> InputError {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof => InputError::NotCasting,
+        _ => InputError::Unreachable,
+    }
+}
+
+// Return the error telling the viewer the most about a host.
+fn most_telling(first: InputError, second: InputError) -> InputError {
+    let rank = |error: InputError| match error {
+        InputError::Refused => 3,
+        InputError::NotCasting => 2,
+        InputError::Unreachable => 1,
+        _ => 0,
+    };
+    if rank(second) > rank(first) { second } else { first }
+}
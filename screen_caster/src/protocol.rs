@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// This module defines the wire format shared by the StreamingServer and the StreamingClient.
+/// Every datagram sent by the server starts with a fixed size header carrying a stream id, a sequence number,
+/// a timestamp and a packet type, followed by the payload (MPEG-TS bytes for data packets, text for control packets).
+/// The client uses the header to tell data from control traffic, to detect lost and reordered packets
+/// and to drop stale packets belonging to a previous session or arriving too late to be played.
+/// Control messages are made up of a command on the first line followed by optional "key=value" lines,
+/// e.g. "START\nfec=8" sent by the client and "OK\nfec=8" replied by the server.
+///
+/// HEADER LAYOUT (big endian, 16 bytes)
+///
+/// +--------+---------+------+-----------+----------+-----------+
+/// | magic  | version | type | stream id | sequence | timestamp |
+/// | 2 byte | 1 byte  | 1 b. |  4 byte   |  4 byte  |  4 byte   |
+/// +--------+---------+------+-----------+----------+-----------+
+///
+/// The highest bit of the type byte tells whether the payload has been encrypted, see the crypto module.
+
+pub const MAGIC: u16 = 0x5343;
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 16;
+pub const MAX_DATAGRAM_SIZE: usize = 2048;
+pub const ENCRYPTED_FLAG: u8 = 0x80;
+
+/// Interval between two "HEARTBEAT" messages sent by the client to the server.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of heartbeats a client may miss in a row before being evicted by the server.
+pub const MAX_MISSED_HEARTBEATS: u32 = 5;
+
+/// Number of out of order packets the client keeps waiting for a missing one before giving it up as lost.
+pub const REORDER_WINDOW: usize = 64;
+
+/// PacketType enum used to distinguish the kind of payload carried by a datagram.
+/// - Data: a chunk of the MPEG-TS stream
+/// - Control: a text message of the control protocol ("OK", ...)
+/// - Parity: a FEC parity packet protecting a group of data packets, see the fec module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Control,
+    Parity,
+}
+
+impl PacketType {
+    fn to_u8(self) -> u8 {
+        match self {
+            PacketType::Data => 0,
+            PacketType::Control => 1,
+            PacketType::Parity => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Control),
+            2 => Some(PacketType::Parity),
+            _ => None,
+        }
+    }
+}
+
+/// PacketHeader struct contains the fields placed in front of every datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub packet_type: PacketType,
+    pub stream_id: u32,
+    pub sequence: u32,
+    pub timestamp: u32,
+}
+
+impl PacketHeader {
+    pub fn new(packet_type: PacketType, stream_id: u32, sequence: u32, timestamp: u32) -> Self {
+        PacketHeader {
+            packet_type,
+            stream_id,
+            sequence,
+            timestamp,
+        }
+    }
+
+    /// Serialize the header followed by the payload into a single datagram.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+        packet.extend_from_slice(&MAGIC.to_be_bytes());
+        packet.push(PROTOCOL_VERSION);
+        packet.push(self.packet_type.to_u8());
+        packet.extend_from_slice(&self.stream_id.to_be_bytes());
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Parse a datagram, returning the header and a slice over the payload.
+    /// Returns None if the datagram is too short, has a wrong magic number, an unsupported version or an unknown packet type.
+    pub fn decode(packet: &[u8]) -> Option<(PacketHeader, &[u8])> {
+        if packet.len() < HEADER_SIZE {
+            return None;
+        }
+        if u16::from_be_bytes([packet[0], packet[1]]) != MAGIC || packet[2] != PROTOCOL_VERSION {
+            return None;
+        }
+        let packet_type = PacketType::from_u8(packet[3] & !ENCRYPTED_FLAG)?;
+        let stream_id = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let sequence = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let timestamp = u32::from_be_bytes([packet[12], packet[13], packet[14], packet[15]]);
+
+        Some((PacketHeader::new(packet_type, stream_id, sequence, timestamp), &packet[HEADER_SIZE..]))
+    }
+}
+
+/// Tell whether a datagram carries an encrypted payload.
+pub fn is_encrypted(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE && packet[3] & ENCRYPTED_FLAG != 0
+}
+
+/// ControlMessage struct contains a command of the control protocol and its parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub command: String,
+    pub params: HashMap<String, String>,
+}
+
+impl ControlMessage {
+    pub fn new(command: &str) -> Self {
+        ControlMessage {
+            command: command.to_string(),
+            params: HashMap::new(),
+        }
+    }
+
+    pub fn with_param(mut self, key: &str, value: impl ToString) -> Self {
+        self.params.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Parse a text message, the first line is the command and the following "key=value" lines are the parameters.
+    /// Lines without a "=" are stored under the empty key, keeping the old "STOP\n<ip>" format readable.
+    pub fn parse(text: &str) -> Self {
+        let mut lines = text.trim().lines();
+        let command = lines.next().unwrap_or("").trim().to_string();
+        let mut params = HashMap::new();
+        for line in lines {
+            match line.split_once('=') {
+                Some((key, value)) => {
+                    params.insert(key.trim().to_string(), value.trim().to_string());
+                }
+                None => {
+                    params.insert(String::new(), line.trim().to_string());
+                }
+            }
+        }
+        ControlMessage { command, params }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(|value| value.as_str())
+    }
+
+    /// Serialize the message, parameters are sorted to get a stable output.
+    pub fn to_text(&self) -> String {
+        let mut params = self.params.iter().collect::<Vec<_>>();
+        params.sort();
+        let mut text = self.command.clone();
+        for (key, value) in params {
+            if key.is_empty() {
+                text.push_str(&format!("\n{value}"));
+            } else {
+                text.push_str(&format!("\n{key}={value}"));
+            }
+        }
+        text
+    }
+}
+
+/// Build a control datagram carrying a message of the control protocol.
+pub fn encode_control(stream_id: u32, message: &ControlMessage) -> Vec<u8> {
+    PacketHeader::new(PacketType::Control, stream_id, 0, 0).encode(message.to_text().as_bytes())
+}
+
+/// Parse a datagram and return the control message it carries, if any.
+pub fn decode_control(packet: &[u8]) -> Option<(u32, ControlMessage)> {
+    match PacketHeader::decode(packet) {
+        Some((header, payload)) if header.packet_type == PacketType::Control => {
+            Some((header.stream_id, ControlMessage::parse(&String::from_utf8_lossy(payload))))
+        }
+        _ => None,
+    }
+}
+
+/// Generate a random stream id, used to tell apart the packets of different casting sessions.
+pub fn new_stream_id() -> u32 {
+    uuid::Uuid::new_v4().as_u128() as u32
+}
+
+/// ReceiverStats struct contains the counters updated by the SequenceTracker, together with the jitter estimated by the client.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReceiverStats {
+    pub received: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub duplicated: u64,
+    pub stale: u64,
+    pub recovered: u64,
+    pub auth_failed: u64,
+    pub jitter_ms: u32,
+}
+
+/// Tell whether the sequence number a comes before b. Sequence numbers wrap around, so they are compared as serial numbers
+/// (RFC 1982): a comes before b if b is less than half the sequence space ahead of it.
+pub fn sequence_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+// Offset of the first position given to the SequenceTracker, so that a packet up to half the sequence space behind it still gets a position
+const SEQUENCE_ORIGIN: u64 = 1 << 32;
+
+/// The SequenceTracker is used by the client to deliver data packets in order to the decoder.
+/// Packets arriving ahead of a missing one are kept in a small reorder buffer; if the missing packet does
+/// not show up before the buffer holds REORDER_WINDOW packets it is counted as lost and skipped.
+/// Packets older than the next expected sequence number are dropped as stale, as well as duplicates.
+/// Sequence numbers wrap around, so the tracker turns them into positions in the stream that keep growing across the wrap.
+pub struct SequenceTracker {
+    next_sequence: Option<u64>,
+    pending: BTreeMap<u64, Vec<u8>>,
+    window: usize,
+    stats: ReceiverStats,
+}
+
+impl SequenceTracker {
+    pub fn new(window: usize) -> Self {
+        SequenceTracker {
+            next_sequence: None,
+            pending: BTreeMap::new(),
+            window,
+            stats: ReceiverStats::default(),
+        }
+    }
+
+    /// Insert a data packet and return the payloads that can be delivered in order.
+    pub fn push(&mut self, sequence: u32, payload: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        let next = match self.next_sequence {
+            Some(next) => next,
+            None => SEQUENCE_ORIGIN + sequence as u64,
+        };
+        // Position of the packet in the stream, the sequence number being at most half the sequence space away from the next one
+        let position = next.wrapping_add_signed(sequence.wrapping_sub(next as u32) as i32 as i64);
+
+        if position < next {
+            // The decoder has already moved past this packet
+            self.stats.stale += 1;
+            return ready;
+        }
+        if self.pending.contains_key(&position) {
+            self.stats.duplicated += 1;
+            return ready;
+        }
+
+        self.stats.received += 1;
+        // A packet filling a gap behind an already received one arrived out of order
+        if let Some(&last) = self.pending.keys().next_back() {
+            if position < last {
+                self.stats.reordered += 1;
+            }
+        }
+        self.pending.insert(position, payload);
+        self.next_sequence = Some(next);
+
+        self.drain(&mut ready);
+        // Give up waiting for the missing packets if the reorder buffer is full
+        while self.pending.len() >= self.window {
+            let (&first, _) = self.pending.iter().next().unwrap();
+            self.stats.lost += first - self.next_sequence.unwrap();
+            self.next_sequence = Some(first);
+            self.drain(&mut ready);
+        }
+        ready
+    }
+
+    /// Move the consecutive packets at the head of the reorder buffer to the output.
+    fn drain(&mut self, ready: &mut Vec<Vec<u8>>) {
+        let mut next = self.next_sequence.unwrap();
+        while let Some(payload) = self.pending.remove(&next) {
+            ready.push(payload);
+            next += 1;
+        }
+        self.next_sequence = Some(next);
+    }
+
+    /// Return the ranges of sequence numbers the tracker is still waiting for.
+    /// A range never wraps around: one crossing the end of the sequence space is split in two, so that first <= last.
+    pub fn missing_ranges(&self) -> Vec<(u32, u32)> {
+        let mut ranges = Vec::new();
+        let mut expected = match self.next_sequence {
+            Some(next) => next,
+            None => return ranges,
+        };
+        for &position in self.pending.keys() {
+            let mut first = expected;
+            while first < position {
+                let last = (position - 1).min(first | u32::MAX as u64);
+                ranges.push((first as u32, last as u32));
+                first = last + 1;
+            }
+            expected = position + 1;
+        }
+        ranges
+    }
+
+    /// Forget the position in the stream and the packets waiting in the reorder buffer, keeping the counters.
+    /// Used when the client moves to another layer of a simulcast session, whose packets are numbered on their own.
+    pub fn restart(&mut self) {
+        self.next_sequence = None;
+        self.pending.clear();
+    }
+
+    /// Count a packet dropped before reaching the tracker, e.g. because it belongs to another stream.
+    pub fn mark_stale(&mut self) {
+        self.stats.stale += 1;
+    }
+
+    /// Count a packet dropped because it failed the authentication of a protected session.
+    pub fn mark_auth_failed(&mut self) {
+        self.stats.auth_failed += 1;
+    }
+
+    pub fn stats(&self) -> ReceiverStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_order_holds_across_the_wrap() {
+        assert!(sequence_before(u32::MAX, 0));
+        assert!(sequence_before(u32::MAX - 10, 5));
+        assert!(!sequence_before(5, u32::MAX - 10));
+        assert!(!sequence_before(7, 7));
+    }
+
+    #[test]
+    fn tracker_delivers_in_order_across_the_wrap() {
+        let mut tracker = SequenceTracker::new(REORDER_WINDOW);
+        let mut delivered = Vec::new();
+        for sequence in [u32::MAX - 1, 0, u32::MAX, 1] {
+            delivered.extend(tracker.push(sequence, sequence.to_be_bytes().to_vec()));
+        }
+        let expected = [u32::MAX - 1, u32::MAX, 0, 1].map(|sequence| sequence.to_be_bytes().to_vec());
+        assert_eq!(delivered, expected);
+        assert!(tracker.push(u32::MAX, Vec::new()).is_empty());
+
+        let stats = tracker.stats();
+        assert_eq!((stats.received, stats.reordered, stats.stale, stats.lost), (4, 1, 1, 0));
+    }
+
+    #[test]
+    fn missing_ranges_are_split_at_the_wrap() {
+        let mut tracker = SequenceTracker::new(REORDER_WINDOW);
+        tracker.push(u32::MAX - 3, Vec::new());
+        tracker.push(2, Vec::new());
+        assert_eq!(tracker.missing_ranges(), vec![(u32::MAX - 2, u32::MAX), (0, 1)]);
+    }
+
+    #[test]
+    fn tracker_counts_losses_across_the_wrap() {
+        let mut tracker = SequenceTracker::new(4);
+        tracker.push(u32::MAX - 1, Vec::new());
+        for sequence in 2..6 {
+            tracker.push(sequence, Vec::new());
+        }
+        // u32::MAX, 0 and 1 are given up when the reorder buffer fills
+        assert_eq!(tracker.stats().lost, 3);
+        assert!(tracker.missing_ranges().is_empty());
+    }
+}
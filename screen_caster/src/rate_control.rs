@@ -0,0 +1,203 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use crate::protocol::ControlMessage;
+
+/// This module implements the adaptive bitrate of the casting session.
+/// Every REPORT_INTERVAL the client sends a "REPORT" control message describing how the stream is being received:
+///
+/// REPORT
+/// loss=12        (per mille of the data packets given up as lost since the previous report)
+/// jitter=35      (interarrival jitter in milliseconds, estimated as in RFC 3550)
+/// backlog=40     (data packets received but not consumed yet by the decoder)
+///
+/// The server feeds the reports to a RateController, which walks a ladder of EncoderTargets: it steps down as soon as
+/// a viewer is congested and steps up again after the network has been clean for all the viewers for a while.
+/// Each change restarts the encoder, so changes are spaced by at least MIN_CHANGE_INTERVAL: the fan-out thread of the StreamingServer
+/// switches to the output of the new encoder once the previous one has been flushed.
+
+/// Interval between two receiver reports sent by the client.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of changes of the encoder target kept to be shown to the streamer.
+pub const CHANGE_LOG_SIZE: usize = 5;
+
+const MIN_CHANGE_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+// A viewer is congested if any of these limits is exceeded
+const CONGESTED_LOSS: u32 = 20;
+const CONGESTED_JITTER: u32 = 80;
+const CONGESTED_BACKLOG: u32 = 200;
+// The network is clean if all the viewers stay below these limits
+const CLEAN_LOSS: u32 = 5;
+const CLEAN_JITTER: u32 = 30;
+const CLEAN_BACKLOG: u32 = 50;
+
+/// EncoderTarget struct contains the parameters of the encoder for a quality level.
+/// - height: the height the video is scaled down to, None to keep the captured one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderTarget {
+    pub bitrate_kbps: u32,
+    pub framerate: u32,
+    pub height: Option<u32>,
+}
+
+impl fmt::Display for EncoderTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.height {
+            Some(height) => write!(f, "{} kbps, {} fps, {}p", self.bitrate_kbps, self.framerate, height),
+            None => write!(f, "{} kbps, {} fps, risoluzione piena", self.bitrate_kbps, self.framerate),
+        }
+    }
+}
+
+/// Quality levels, from the best to the worst. The session starts from the first one.
+pub const TARGET_LADDER: [EncoderTarget; 5] = [
+    EncoderTarget { bitrate_kbps: 4000, framerate: 30, height: None },
+    EncoderTarget { bitrate_kbps: 2500, framerate: 30, height: None },
+    EncoderTarget { bitrate_kbps: 1500, framerate: 25, height: Some(720) },
+    EncoderTarget { bitrate_kbps: 800, framerate: 20, height: Some(540) },
+    EncoderTarget { bitrate_kbps: 400, framerate: 15, height: Some(360) },
+];
+
+/// ReceiverReport struct contains the reception quality reported by a client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReceiverReport {
+    pub loss_permille: u32,
+    pub jitter_ms: u32,
+    pub backlog: u32,
+}
+
+impl ReceiverReport {
+    pub fn to_message(self) -> ControlMessage {
+        ControlMessage::new("REPORT")
+            .with_param("loss", self.loss_permille)
+            .with_param("jitter", self.jitter_ms)
+            .with_param("backlog", self.backlog)
+    }
+
+    /// Parse a "REPORT" message, missing or invalid values are read as 0.
+    pub fn from_message(message: &ControlMessage) -> Self {
+        let value = |key: &str| message.param(key).and_then(|value| value.parse::<u32>().ok()).unwrap_or(0);
+        ReceiverReport {
+            loss_permille: value("loss"),
+            jitter_ms: value("jitter"),
+            backlog: value("backlog"),
+        }
+    }
+
+    fn is_congested(&self) -> bool {
+        self.loss_permille > CONGESTED_LOSS || self.jitter_ms > CONGESTED_JITTER || self.backlog > CONGESTED_BACKLOG
+    }
+
+    fn is_clean(&self) -> bool {
+        self.loss_permille < CLEAN_LOSS && self.jitter_ms < CLEAN_JITTER && self.backlog < CLEAN_BACKLOG
+    }
+}
+
+/// The JitterEstimator is used by the client to estimate the interarrival jitter of the data packets,
+/// comparing the time between two arrivals with the time between the two timestamps.
+pub struct JitterEstimator {
+    last: Option<(Instant, u32)>,
+    jitter: f64,
+}
+
+impl JitterEstimator {
+    pub fn new() -> Self {
+        JitterEstimator {
+            last: None,
+            jitter: 0.0,
+        }
+    }
+
+    pub fn on_packet(&mut self, arrival: Instant, timestamp: u32) {
+        if let Some((last_arrival, last_timestamp)) = self.last {
+            let transit = arrival.duration_since(last_arrival).as_secs_f64() * 1000.0 - (timestamp as f64 - last_timestamp as f64);
+            self.jitter += (transit.abs() - self.jitter) / 16.0;
+        }
+        self.last = Some((arrival, timestamp));
+    }
+
+    pub fn jitter_ms(&self) -> u32 {
+        self.jitter.round() as u32
+    }
+}
+
+/// RateChange struct contains a change of the encoder target and the reason behind it, shown to the streamer.
+#[derive(Debug, Clone)]
+pub struct RateChange {
+    pub at: DateTime<Local>,
+    pub target: EncoderTarget,
+    pub reason: String,
+}
+
+/// The RateController keeps the last report of every client and decides the quality level of the encoder.
+/// Since a single encoder feeds all the viewers, the level follows the worst one.
+/// The most recent changes are kept with their reason, to be shown to the streamer.
+pub struct RateController {
+    level: usize,
+    reports: HashMap<String, ReceiverReport>,
+    last_change: Instant,
+    changes: VecDeque<RateChange>,
+}
+
+impl RateController {
+    pub fn new() -> Self {
+        RateController {
+            level: 0,
+            reports: HashMap::new(),
+            last_change: Instant::now(),
+            changes: VecDeque::with_capacity(CHANGE_LOG_SIZE),
+        }
+    }
+
+    pub fn target(&self) -> EncoderTarget {
+        TARGET_LADDER[self.level]
+    }
+
+    /// Return the most recent changes of the target, the newest first.
+    pub fn changes(&self) -> Vec<RateChange> {
+        self.changes.iter().rev().cloned().collect()
+    }
+
+    /// Forget the reports of a client that left the session.
+    pub fn forget(&mut self, client: &str) {
+        self.reports.remove(client);
+    }
+
+    /// Store the report of a client and return the new target, if the level has to change.
+    pub fn on_report(&mut self, client: &str, report: ReceiverReport, now: Instant) -> Option<EncoderTarget> {
+        self.reports.insert(client.to_string(), report);
+        let since_last_change = now.duration_since(self.last_change);
+        if since_last_change < MIN_CHANGE_INTERVAL {
+            return None;
+        }
+
+        let congested = self.reports.iter()
+            .filter(|(_, report)| report.is_congested())
+            .max_by_key(|(_, report)| (report.loss_permille, report.jitter_ms, report.backlog));
+        let reason = if let Some((address, report)) = congested {
+            if self.level + 1 >= TARGET_LADDER.len() {
+                return None;
+            }
+            self.level += 1;
+            format!("{address} congestionato (perdita {}‰, jitter {} ms, backlog {})", report.loss_permille, report.jitter_ms, report.backlog)
+        } else if self.level > 0 && since_last_change >= PROBE_INTERVAL && self.reports.values().all(|report| report.is_clean()) {
+            self.level -= 1;
+            format!("rete stabile per tutti gli spettatori da {} s", since_last_change.as_secs())
+        } else {
+            return None;
+        };
+
+        self.last_change = now;
+        if self.changes.len() == CHANGE_LOG_SIZE {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(RateChange {
+            at: Local::now(),
+            target: self.target(),
+            reason,
+        });
+        Some(self.target())
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+/// This module turns the MPEG-TS stream produced by the encoder into RTP packets carrying H.264 (RFC 6184),
+/// used by the RTSP output of the StreamingServer. The parsing of the TS packets is shared with the HLS segmenter of the hls module.
+/// The TsDemuxer follows the PAT and the PMT to find the H.264 elementary stream and rebuilds its PES packets:
+/// each one carries an access unit, whose NAL units are extracted from the Annex B byte stream.
+/// The H264Packetizer sends each NAL unit in a single RTP packet when it fits, otherwise splits it in FU-A fragments.
+/// The RTP timestamp is the PTS of the access unit, already on the 90 kHz clock required by H.264,
+/// and the marker bit is set on the last packet of each access unit.
+
+pub const TS_PACKET_SIZE: usize = 188;
+/// Dynamic payload type announced in the SDP for H.264.
+pub const H264_PAYLOAD_TYPE: u8 = 96;
+/// Largest RTP payload sent, so that the packets fit in the MTU of an Ethernet link.
+pub const MAX_RTP_PAYLOAD: usize = 1400;
+
+pub const TS_SYNC_BYTE: u8 = 0x47;
+pub const PAT_PID: u16 = 0;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+const NAL_TYPE_AUD: u8 = 9;
+const NAL_TYPE_FU_A: u8 = 28;
+
+/// TsPacket struct contains the fields of the header of a TS packet used by the demuxer and by the HLS segmenter, and its payload.
+/// - random_access: set by the muxer on the packets starting a keyframe
+pub struct TsPacket<'a> {
+    pub pid: u16,
+    pub unit_start: bool,
+    pub random_access: bool,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TsPacket<'a> {
+    /// Parse a TS packet, None if it is not valid or carries no payload.
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() != TS_PACKET_SIZE || packet[0] != TS_SYNC_BYTE {
+            return None;
+        }
+        let adaptation_field = (packet[3] >> 4) & 0x03;
+        if adaptation_field & 0x01 == 0 {
+            return None;
+        }
+        let (payload_offset, random_access) = if adaptation_field & 0x02 != 0 {
+            (5 + packet[4] as usize, packet[4] > 0 && packet[5] & 0x40 != 0)
+        } else {
+            (4, false)
+        };
+        Some(TsPacket {
+            pid: (((packet[1] & 0x1F) as u16) << 8) | packet[2] as u16,
+            unit_start: packet[1] & 0x40 != 0,
+            random_access,
+            payload: packet.get(payload_offset..).filter(|payload| !payload.is_empty())?,
+        })
+    }
+}
+
+/// AccessUnit struct contains the NAL units of a video frame and its presentation timestamp, on the 90 kHz clock.
+pub struct AccessUnit {
+    pub pts: u32,
+    pub nal_units: Vec<Vec<u8>>,
+}
+
+/// The TsDemuxer extracts the H.264 access units from the chunks of MPEG-TS read from the encoder.
+/// Chunks don't need to be aligned to the TS packets. The PAT and the PMT are read again every time they are received,
+/// so that the demuxer follows the stream when the encoder is restarted.
+pub struct TsDemuxer {
+    pending: Vec<u8>,
+    pmt_pid: Option<u16>,
+    video_pid: Option<u16>,
+    pes: HashMap<u16, Vec<u8>>,
+}
+
+impl TsDemuxer {
+    pub fn new() -> Self {
+        TsDemuxer {
+            pending: Vec::new(),
+            pmt_pid: None,
+            video_pid: None,
+            pes: HashMap::new(),
+        }
+    }
+
+    /// Feed a chunk of the stream, returning the access units completed by it.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<AccessUnit> {
+        self.pending.extend_from_slice(chunk);
+        let mut access_units = Vec::new();
+        let mut offset = 0;
+        while self.pending.len() - offset >= TS_PACKET_SIZE {
+            // Resynchronize on the next sync byte if the stream is corrupted
+            if self.pending[offset] != TS_SYNC_BYTE {
+                offset += 1;
+                continue;
+            }
+            let packet = self.pending[offset..offset + TS_PACKET_SIZE].to_vec();
+            offset += TS_PACKET_SIZE;
+            if let Some(access_unit) = self.on_packet(&packet) {
+                access_units.push(access_unit);
+            }
+        }
+        self.pending.drain(..offset);
+        access_units
+    }
+
+    fn on_packet(&mut self, packet: &[u8]) -> Option<AccessUnit> {
+        let TsPacket { pid, unit_start, payload, .. } = TsPacket::parse(packet)?;
+        if pid == PAT_PID && unit_start {
+            self.pmt_pid = section(payload).and_then(parse_pat);
+            None
+        } else if Some(pid) == self.pmt_pid && unit_start {
+            self.video_pid = section(payload).and_then(parse_pmt);
+            None
+        } else if Some(pid) == self.video_pid {
+            // The length of video PES packets is left unspecified, a packet ends when the next one starts
+            let completed = if unit_start { self.pes.insert(pid, Vec::new()) } else { None };
+            if let Some(pes) = self.pes.get_mut(&pid) {
+                pes.extend_from_slice(payload);
+            }
+            completed.and_then(|pes| parse_pes(&pes))
+        } else {
+            None
+        }
+    }
+}
+
+/// Return the PSI section carried by a payload starting a section, skipping the pointer field.
+pub fn section(payload: &[u8]) -> Option<&[u8]> {
+    let start = 1 + *payload.first()? as usize;
+    let header = payload.get(start..start + 3)?;
+    let length = (((header[1] & 0x0F) as usize) << 8) | header[2] as usize;
+    payload.get(start..start + 3 + length)
+}
+
+/// Return the PID of the PMT of the first program listed in the PAT.
+pub fn parse_pat(section: &[u8]) -> Option<u16> {
+    // Program entries follow the 8 bytes of header, the section ends with a 4 bytes CRC
+    let entries = section.get(8..section.len().checked_sub(4)?)?;
+    entries.chunks_exact(4)
+        .find(|entry| u16::from_be_bytes([entry[0], entry[1]]) != 0)
+        .map(|entry| (((entry[2] & 0x1F) as u16) << 8) | entry[3] as u16)
+}
+
+/// Return the PID of the first H.264 stream listed in the PMT.
+pub fn parse_pmt(section: &[u8]) -> Option<u16> {
+    let program_info_length = (((*section.get(10)? & 0x0F) as usize) << 8) | *section.get(11)? as usize;
+    let end = section.len().checked_sub(4)?;
+    let mut offset = 12 + program_info_length;
+    while offset + 5 <= end {
+        let stream_type = section[offset];
+        let pid = (((section[offset + 1] & 0x1F) as u16) << 8) | section[offset + 2] as u16;
+        let es_info_length = (((section[offset + 3] & 0x0F) as usize) << 8) | section[offset + 4] as usize;
+        if stream_type == STREAM_TYPE_H264 {
+            return Some(pid);
+        }
+        offset += 5 + es_info_length;
+    }
+    None
+}
+
+/// Return the PTS of the PES packet starting with the given bytes, on the 90 kHz clock, if it carries one.
+pub fn pes_pts(pes: &[u8]) -> Option<u64> {
+    if pes.get(..3)? != [0, 0, 1] || *pes.get(7)? & 0x80 == 0 {
+        return None;
+    }
+    let p = pes.get(9..14)?;
+    Some((((p[0] >> 1) & 0x07) as u64) << 30
+        | (p[1] as u64) << 22
+        | ((p[2] >> 1) as u64) << 15
+        | (p[3] as u64) << 7
+        | (p[4] >> 1) as u64)
+}
+
+/// Return whether a chunk of MPEG-TS, not necessarily aligned to the TS packets, contains the start of a keyframe.
+/// A sync byte is trusted only if another one is found a packet before or after it, the header of the packet must be in the chunk.
+pub fn contains_keyframe(chunk: &[u8]) -> bool {
+    (0..chunk.len().saturating_sub(6)).any(|i| {
+        let before = i.checked_sub(TS_PACKET_SIZE).map(|j| chunk[j] == TS_SYNC_BYTE);
+        let after = chunk.get(i + TS_PACKET_SIZE).map(|byte| *byte == TS_SYNC_BYTE);
+        chunk[i] == TS_SYNC_BYTE
+            && before.or(after).unwrap_or(false)
+            && before != Some(false) && after != Some(false)
+            && chunk[i + 1] & 0x40 != 0
+            && chunk[i + 3] & 0x20 != 0
+            && chunk[i + 4] > 0
+            && chunk[i + 5] & 0x40 != 0
+    })
+}
+
+/// Return whether a chunk of whole TS packets, as cut by the ts_chunker module, contains the start of a PAT section.
+pub fn contains_pat(chunk: &[u8]) -> bool {
+    chunk.chunks_exact(TS_PACKET_SIZE)
+        .filter_map(TsPacket::parse)
+        .any(|packet| packet.pid == PAT_PID && packet.unit_start)
+}
+
+// Parse a PES packet into the access unit it carries.
+fn parse_pes(pes: &[u8]) -> Option<AccessUnit> {
+    if pes.get(..3)? != [0, 0, 1] {
+        return None;
+    }
+    let header_length = *pes.get(8)? as usize;
+    let pts = pes_pts(pes).unwrap_or(0) as u32;
+    let nal_units = split_annex_b(pes.get(9 + header_length..)?)
+        .into_iter()
+        .filter(|nal| nal[0] & 0x1F != NAL_TYPE_AUD)
+        .map(|nal| nal.to_vec())
+        .collect::<Vec<Vec<u8>>>();
+    if nal_units.is_empty() {
+        return None;
+    }
+    Some(AccessUnit { pts, nal_units })
+}
+
+// Split an Annex B byte stream in its NAL units, without the start codes.
+fn split_annex_b(stream: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= stream.len() {
+        if stream[i] == 0 && stream[i + 1] == 0 && stream[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts.iter().enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).map_or(stream.len(), |&next| next - 3);
+            let mut nal = &stream[start..end];
+            // The zero of a 4 bytes start code belongs to the next one
+            while let Some((&0, rest)) = nal.split_last() {
+                nal = rest;
+            }
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// ParameterSets struct contains the last SPS and PPS seen in the stream. They are announced in the SDP (sprop-parameter-sets),
+/// so that a player can set up its decoder without waiting for the ones the encoder sends with every keyframe.
+#[derive(Debug, Clone, Default)]
+pub struct ParameterSets {
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl ParameterSets {
+    /// Keep the parameter sets carried by an access unit, if any.
+    pub fn update(&mut self, access_unit: &AccessUnit) {
+        for nal in access_unit.nal_units.iter() {
+            match nal[0] & 0x1F {
+                NAL_TYPE_SPS => self.sps = Some(nal.clone()),
+                NAL_TYPE_PPS => self.pps = Some(nal.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.sps.as_ref().is_some_and(|sps| sps.len() >= 4) && self.pps.is_some()
+    }
+
+    /// Return the format parameters of the SDP (RFC 6184): the packetization mode and, once known, the profile and the parameter sets.
+    pub fn fmtp(&self) -> String {
+        match (self.sps.as_ref(), self.pps.as_ref()) {
+            (Some(sps), Some(pps)) if self.is_known() => format!(
+                "packetization-mode=1;profile-level-id={:02X}{:02X}{:02X};sprop-parameter-sets={},{}",
+                sps[1], sps[2], sps[3], base64(sps), base64(pps)),
+            _ => "packetization-mode=1".to_string(),
+        }
+    }
+}
+
+// Encode bytes in base64 with padding, as the parameter sets of the SDP.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let value = group.iter().enumerate().fold(0u32, |value, (i, byte)| value | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                text.push(ALPHABET[(value >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// The H264Packetizer builds the RTP packets of an H.264 stream, keeping the sequence number and the SSRC.
+pub struct H264Packetizer {
+    sequence: u16,
+    ssrc: u32,
+}
+
+impl H264Packetizer {
+    pub fn new(ssrc: u32) -> Self {
+        H264Packetizer {
+            sequence: 0,
+            ssrc,
+        }
+    }
+
+    /// Return the RTP packets carrying an access unit.
+    pub fn packetize(&mut self, access_unit: &AccessUnit) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let count = access_unit.nal_units.len();
+        for (n, nal) in access_unit.nal_units.iter().enumerate() {
+            let last_nal = n + 1 == count;
+            if nal.len() <= MAX_RTP_PAYLOAD {
+                packets.push(self.packet(access_unit.pts, last_nal, nal));
+                continue;
+            }
+            // FU-A: the NAL header is replaced by the FU indicator and the FU header, the first and the last fragments are flagged
+            let indicator = (nal[0] & 0xE0) | NAL_TYPE_FU_A;
+            let fragments = nal[1..].chunks(MAX_RTP_PAYLOAD - 2).collect::<Vec<&[u8]>>();
+            for (f, fragment) in fragments.iter().enumerate() {
+                let start = if f == 0 { 0x80 } else { 0 };
+                let end = if f + 1 == fragments.len() { 0x40 } else { 0 };
+                let mut payload = Vec::with_capacity(2 + fragment.len());
+                payload.push(indicator);
+                payload.push(start | end | (nal[0] & 0x1F));
+                payload.extend_from_slice(fragment);
+                packets.push(self.packet(access_unit.pts, last_nal && end != 0, &payload));
+            }
+        }
+        packets
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.push(0x80);
+        packet.push(if marker { 0x80 } else { 0 } | H264_PAYLOAD_TYPE);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_the_last_group() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b""), "");
+    }
+
+    #[test]
+    fn parameter_sets_are_announced_once_known() {
+        let mut parameter_sets = ParameterSets::default();
+        assert_eq!(parameter_sets.fmtp(), "packetization-mode=1");
+
+        parameter_sets.update(&AccessUnit {
+            pts: 0,
+            nal_units: vec![vec![0x67, 0x64, 0x00, 0x28, 0xAC], vec![0x68, 0xEE, 0x3C, 0x80], vec![0x65, 0x88]],
+        });
+        assert_eq!(parameter_sets.fmtp(), "packetization-mode=1;profile-level-id=640028;sprop-parameter-sets=Z2QAKKw=,aO48gA==");
+    }
+}
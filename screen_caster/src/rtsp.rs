@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use crate::rtp::{self, H264Packetizer, ParameterSets, TsDemuxer};
+use crate::transport;
+use crate::utils;
+
+/// This module contains the RtspServer, which serves the casting session to third-party players (VLC, ffplay, recorders)
+/// at rtsp://address:port/cast, next to the native clients.
+/// The fan-out thread of the StreamingServer pushes every chunk read from the encoder, the RtspServer demuxes it and sends
+/// the H.264 stream as RTP packets (see the rtp module) to every player that issued "PLAY".
+/// The methods supported are OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN and GET_PARAMETER (used by players as keep-alive).
+/// SETUP accepts both RTP over UDP (client_port) and RTP interleaved in the RTSP connection (RTP/AVP/TCP), for players behind a firewall.
+/// A session lasts as long as the RTSP connection it was set up on, and only that connection can play or tear it down: its id is always
+/// drawn by the server. RTCP is neither sent nor read.
+/// Each connection has a writer thread sending its queue of responses and interleaved packets, so that a slow player never blocks the others:
+/// when its queue is full, the access units are dropped for that player only.
+/// The SDP carries the SPS and the PPS of the stream (sprop-parameter-sets): DESCRIBE waits up to PARAMETER_SETS_WAIT for the first ones.
+/// The stream is served in clear and without authentication, so the StreamingServer only starts the RtspServer for sessions without a PIN.
+
+/// Path of the stream served.
+pub const RTSP_PATH: &str = "/cast";
+
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+// Responses and interleaved packets waiting to be written on a connection
+const OUTPUT_QUEUE_SIZE: usize = 1024;
+const PARAMETER_SETS_WAIT: Duration = Duration::from_secs(2);
+const PARAMETER_SETS_POLL: Duration = Duration::from_millis(50);
+const SESSION_TIMEOUT_SECS: u32 = 60;
+// Chunks waiting to be packetized, the newest ones are dropped if the packetizer falls behind
+const FEED_SIZE: usize = 256;
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// RtpSink enum contains where the RTP packets of a session are sent: to a UDP port of the player or interleaved in its RTSP connection.
+#[derive(Clone)]
+enum RtpSink {
+    Udp(SocketAddr),
+    Interleaved(Sender<Vec<u8>>, u8),
+}
+
+struct Session {
+    sink: RtpSink,
+    playing: bool,
+}
+
+impl Session {
+    // Tell whether the queue of the connection of an interleaved session has room for the given number of packets.
+    fn fits(&self, packets: usize) -> bool {
+        match &self.sink {
+            RtpSink::Interleaved(output, _) => output.len() + packets <= OUTPUT_QUEUE_SIZE,
+            RtpSink::Udp(_) => true,
+        }
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+// Request struct contains a parsed RTSP request, header names are lowercase.
+struct Request {
+    method: String,
+    url: String,
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+}
+
+// The RequestReader splits the bytes read from an RTSP connection into requests, skipping the interleaved packets sent by the player.
+// Bytes are kept across reads, so that a read timeout in the middle of a request does not lose it.
+struct RequestReader {
+    pending: Vec<u8>,
+}
+
+impl RequestReader {
+    fn new() -> Self {
+        RequestReader {
+            pending: Vec::new(),
+        }
+    }
+
+    fn read(&mut self, stream: &mut TcpStream) -> io::Result<Request> {
+        let mut buffer = [0; 2048];
+        loop {
+            if let Some(request) = self.next_request() {
+                return Ok(request);
+            }
+            if self.pending.len() > MAX_REQUEST_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Request too large"));
+            }
+            let n = stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"));
+            }
+            self.pending.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    fn next_request(&mut self) -> Option<Request> {
+        loop {
+            // Interleaved packet: '$', channel, length (2 bytes), data
+            if self.pending.first() == Some(&b'$') {
+                let length = u16::from_be_bytes([*self.pending.get(2)?, *self.pending.get(3)?]) as usize;
+                if self.pending.len() < 4 + length {
+                    return None;
+                }
+                self.pending.drain(..4 + length);
+                continue;
+            }
+            let end = self.pending.windows(4).position(|window| window == b"\r\n\r\n")? + 4;
+            let text = String::from_utf8_lossy(&self.pending[..end]).to_string();
+            let mut lines = text.lines();
+            let mut request_line = lines.next().unwrap_or("").split_whitespace();
+            let method = request_line.next().unwrap_or("").to_string();
+            let url = request_line.next().unwrap_or("").to_string();
+            let headers = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+                .collect::<HashMap<String, String>>();
+            // A body, if any, is not used by the methods supported
+            let body_length = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+            if self.pending.len() < end + body_length {
+                return None;
+            }
+            self.pending.drain(..end + body_length);
+            return Some(Request { method, url, headers });
+        }
+    }
+}
+
+// Return the path of an RTSP URL.
+fn url_path(url: &str) -> &str {
+    let without_scheme = url.strip_prefix("rtsp://").unwrap_or(url);
+    without_scheme.find('/').map_or("/", |start| &without_scheme[start..])
+}
+
+// Parse a port range of the Transport header, like "client_port=5000-5001".
+fn port_range(transport: &str, name: &str) -> Option<(u16, u16)> {
+    let value = transport.split(';').find_map(|parameter| parameter.trim().strip_prefix(name)?.strip_prefix('='))?;
+    let (first, second) = value.split_once('-').unwrap_or((value, value));
+    Some((first.parse().ok()?, second.parse().ok()?))
+}
+
+/// The RtspServer accepts RTSP connections and sends the stream pushed by the StreamingServer to the players, until it is stopped.
+pub struct RtspServer {
+    address: SocketAddr,
+    feed: Sender<Vec<u8>>,
+    running: Arc<AtomicBool>,
+}
+
+impl RtspServer {
+    /// Start the server on the given address, dual-stack if it is the unspecified IPv6 one.
+    pub fn start(address: SocketAddr) -> io::Result<Self> {
+        let listener = transport::bind_tcp(address)?;
+        listener.set_nonblocking(true)?;
+        // Players reached through a dual-stack listener are sent to with the same address family, so the RTP socket follows the listener
+        let rtp_socket: UdpSocket = transport::bind_udp(SocketAddr::new(address.ip(), 0))?;
+        let rtp_port = rtp_socket.local_addr()?.port();
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        let parameter_sets = Arc::new(Mutex::new(ParameterSets::default()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (feed, feed_rx) = bounded(FEED_SIZE);
+
+        let sessions_clone = sessions.clone();
+        let parameter_sets_clone = parameter_sets.clone();
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        let sessions = sessions_clone.clone();
+                        let parameter_sets = parameter_sets_clone.clone();
+                        let running = running_clone.clone();
+                        thread::spawn(move || RtspServer::serve(stream, peer, sessions, parameter_sets, rtp_port, running));
+                    }
+                    Err(_) => thread::sleep(ACCEPT_INTERVAL),
+                }
+            }
+        });
+
+        let running_clone = running.clone();
+        thread::spawn(move || RtspServer::send_stream(feed_rx, rtp_socket, sessions, parameter_sets, running_clone));
+
+        Ok(RtspServer {
+            address: utils::advertised_address(address),
+            feed,
+            running,
+        })
+    }
+
+    /// Return the URL players can open the stream with.
+    pub fn url(&self) -> String {
+        format!("rtsp://{}{}", self.address, RTSP_PATH)
+    }
+
+    /// Push a chunk of the MPEG-TS stream read from the encoder.
+    pub fn push(&self, chunk: &[u8]) {
+        let _ = self.feed.try_send(chunk.to_vec());
+    }
+
+    /// Stop accepting players and close the RTSP connections, at the next read timeout.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    // Demux the chunks pushed and send the RTP packets to the sessions playing. Interleaved packets are queued to the writer of their connection
+    // without blocking, the sessions whose connection has been closed are dropped.
+    fn send_stream(feed: Receiver<Vec<u8>>, rtp_socket: UdpSocket, sessions: Sessions, parameter_sets: Arc<Mutex<ParameterSets>>, running: Arc<AtomicBool>) {
+        let mut demuxer = TsDemuxer::new();
+        let mut packetizer = H264Packetizer::new(uuid::Uuid::new_v4().as_u128() as u32);
+        while running.load(Ordering::Relaxed) {
+            let chunk = match feed.recv_timeout(READ_TIMEOUT) {
+                Ok(chunk) => chunk,
+                Err(_) => continue,
+            };
+            for access_unit in demuxer.push(&chunk) {
+                parameter_sets.lock().unwrap().update(&access_unit);
+                let packets = packetizer.packetize(&access_unit);
+                let mut sessions = sessions.lock().unwrap();
+                sessions.retain(|_, session| {
+                    if !session.playing {
+                        return true;
+                    }
+                    match &session.sink {
+                        RtpSink::Udp(address) => {
+                            for packet in packets.iter() {
+                                let _ = rtp_socket.send_to(packet, address);
+                            }
+                            true
+                        }
+                        // A player reading too slowly misses whole access units rather than some packets of each
+                        RtpSink::Interleaved(_, _) if !session.fits(packets.len()) => true,
+                        RtpSink::Interleaved(output, channel) => {
+                            packets.iter().all(|packet| {
+                                let mut frame = Vec::with_capacity(4 + packet.len());
+                                frame.push(b'$');
+                                frame.push(*channel);
+                                frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                                frame.extend_from_slice(packet);
+                                !matches!(output.try_send(frame), Err(TrySendError::Disconnected(_)))
+                            })
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    // Write the responses and the interleaved packets queued for a connection, until all the senders are dropped or a write fails.
+    // The connection is then shut down, which also ends the thread reading its requests.
+    fn write_output(mut stream: TcpStream, output: Receiver<Vec<u8>>) {
+        for frame in output.iter() {
+            if stream.write_all(&frame).is_err() {
+                break;
+            }
+        }
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    // Answer the requests of a player until the connection is closed, then drop the sessions set up on it.
+    fn serve(stream: TcpStream, peer: SocketAddr, sessions: Sessions, parameter_sets: Arc<Mutex<ParameterSets>>, rtp_port: u16, running: Arc<AtomicBool>) {
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+        let mut reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(_) => return,
+        };
+        let (writer, output) = bounded(OUTPUT_QUEUE_SIZE);
+        thread::spawn(move || RtspServer::write_output(stream, output));
+        let mut requests = RequestReader::new();
+        let mut own_sessions = Vec::new();
+
+        while running.load(Ordering::Relaxed) {
+            let request = match requests.read(&mut reader) {
+                Ok(request) => request,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(_) => break,
+            };
+            let (status, headers, body) = RtspServer::respond(&request, peer, &writer, &sessions, &parameter_sets, &mut own_sessions, rtp_port);
+
+            let mut response = format!("RTSP/1.0 {status}\r\nCSeq: {}\r\n", request.header("cseq").unwrap_or("0"));
+            for (name, value) in headers {
+                response.push_str(&format!("{name}: {value}\r\n"));
+            }
+            if !body.is_empty() {
+                response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            response.push_str("\r\n");
+            response.push_str(&body);
+            // Responses are never dropped: wait for room in the queue, unless the writer has given up on the connection
+            if writer.send(response.into_bytes()).is_err() {
+                break;
+            }
+        }
+
+        // Once the sessions and this sender are dropped the writer sends what is left and shuts the connection down
+        let mut sessions = sessions.lock().unwrap();
+        for id in own_sessions {
+            sessions.remove(&id);
+        }
+    }
+
+    // Build the status line, the headers and the body of the response to a request.
+    fn respond(request: &Request, peer: SocketAddr, writer: &Sender<Vec<u8>>, sessions: &Sessions, parameter_sets: &Mutex<ParameterSets>,
+               own_sessions: &mut Vec<String>, rtp_port: u16) -> (&'static str, Vec<(&'static str, String)>, String) {
+        if request.method != "OPTIONS" && !url_path(&request.url).starts_with(RTSP_PATH) {
+            return ("404 Not Found", Vec::new(), String::new());
+        }
+        // Only the sessions set up on this connection can be played or torn down with it
+        let session_id = request.header("session")
+            .map(|session| session.split(';').next().unwrap_or("").trim().to_string())
+            .filter(|id| own_sessions.contains(id));
+
+        match request.method.as_str() {
+            "OPTIONS" => ("200 OK", vec![("Public", "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string())], String::new()),
+            "DESCRIBE" => {
+                let start = Instant::now();
+                while !parameter_sets.lock().unwrap().is_known() && start.elapsed() < PARAMETER_SETS_WAIT {
+                    thread::sleep(PARAMETER_SETS_POLL);
+                }
+                let fmtp = parameter_sets.lock().unwrap().fmtp();
+                let peer_ip = peer.ip().to_canonical();
+                let (family, unspecified) = if peer_ip.is_ipv4() { ("IP4", "0.0.0.0") } else { ("IP6", "::") };
+                let sdp = format!(
+                    "v=0\r\no=- 0 0 IN {family} {0}\r\ns=ScreenCaster\r\nc=IN {family} {unspecified}\r\nt=0 0\r\na=control:*\r\n\
+                     m=video 0 RTP/AVP {1}\r\na=rtpmap:{1} H264/90000\r\na=fmtp:{1} {fmtp}\r\na=control:track1\r\n",
+                    peer_ip, rtp::H264_PAYLOAD_TYPE);
+                let headers = vec![
+                    ("Content-Type", "application/sdp".to_string()),
+                    ("Content-Base", format!("{}/", request.url.trim_end_matches('/'))),
+                ];
+                ("200 OK", headers, sdp)
+            }
+            "SETUP" => {
+                let transport = request.header("transport").unwrap_or("");
+                let (sink, reply_transport) = if transport.contains("RTP/AVP/TCP") {
+                    let (rtp_channel, rtcp_channel) = port_range(transport, "interleaved").unwrap_or((0, 1));
+                    (RtpSink::Interleaved(writer.clone(), rtp_channel as u8), format!("RTP/AVP/TCP;unicast;interleaved={rtp_channel}-{rtcp_channel}"))
+                } else if let Some((rtp_client_port, rtcp_client_port)) = port_range(transport, "client_port") {
+                    (RtpSink::Udp(SocketAddr::new(peer.ip(), rtp_client_port)),
+                     format!("RTP/AVP;unicast;client_port={rtp_client_port}-{rtcp_client_port};server_port={}-{}", rtp_port, rtp_port.wrapping_add(1)))
+                } else {
+                    return ("461 Unsupported Transport", Vec::new(), String::new());
+                };
+                let id = format!("{:016X}", uuid::Uuid::new_v4().as_u128() as u64);
+                sessions.lock().unwrap().insert(id.clone(), Session { sink, playing: false });
+                own_sessions.push(id.clone());
+                ("200 OK", vec![("Transport", reply_transport), ("Session", format!("{id};timeout={SESSION_TIMEOUT_SECS}"))], String::new())
+            }
+            "PLAY" => {
+                let id = session_id.unwrap_or_default();
+                match sessions.lock().unwrap().get_mut(&id) {
+                    Some(session) => session.playing = true,
+                    None => return ("454 Session Not Found", Vec::new(), String::new()),
+                }
+                ("200 OK", vec![("Session", id), ("Range", "npt=0.000-".to_string())], String::new())
+            }
+            "TEARDOWN" => {
+                if let Some(id) = session_id {
+                    sessions.lock().unwrap().remove(&id);
+                    own_sessions.retain(|own| *own != id);
+                }
+                ("200 OK", Vec::new(), String::new())
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => ("200 OK", Vec::new(), String::new()),
+            _ => ("501 Not Implemented", Vec::new(), String::new()),
+        }
+    }
+}
+
+impl Drop for RtspServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // Send a request on an RTSP connection and return the response.
+    fn request(stream: &mut TcpStream, text: &str) -> String {
+        stream.write_all(text.as_bytes()).unwrap();
+        let mut buffer = [0; 2048];
+        let n = stream.read(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    fn start_server() -> RtspServer {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        RtspServer::start(SocketAddr::new("127.0.0.1".parse().unwrap(), port)).unwrap()
+    }
+
+    #[test]
+    fn session_ids_are_drawn_by_the_server_and_bound_to_their_connection() {
+        let server = start_server();
+        let mut player = TcpStream::connect(server.address).unwrap();
+        let setup = request(&mut player, &format!(
+            "SETUP {}/track1 RTSP/1.0\r\nCSeq: 1\r\nSession: CHOSEN\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n", server.url()));
+        assert!(setup.starts_with("RTSP/1.0 200 OK"));
+        let id = setup.lines().find_map(|line| line.strip_prefix("Session: ")).unwrap().split(';').next().unwrap().to_string();
+        assert_ne!(id, "CHOSEN");
+
+        let mut other = TcpStream::connect(server.address).unwrap();
+        let play = request(&mut other, &format!("PLAY {} RTSP/1.0\r\nCSeq: 1\r\nSession: {id}\r\n\r\n", server.url()));
+        assert!(play.starts_with("RTSP/1.0 454"));
+
+        let play = request(&mut player, &format!("PLAY {} RTSP/1.0\r\nCSeq: 2\r\nSession: {id}\r\n\r\n", server.url()));
+        assert!(play.starts_with("RTSP/1.0 200 OK"));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::error_banner::InputError;
+use crate::protocol::ControlMessage;
+
+/// This module defines the session descriptor the StreamingServer sends in the "OK" reply to "START", describing the casting session
+/// before the first packet of the stream arrives. The descriptor is made up of "key=value" lines following the ones of the reply:
+///
+/// OK
+/// audio=false
+/// codec=h264
+/// features=fec,nack,pin,simulcast,tcp
+/// fec=8
+/// framerate=30
+/// layers=1080,720,360
+/// resolution=1920x1080
+/// title=workstation
+/// version=2
+///
+/// The version is the one of the control protocol, and is also sent by the client in its "START" request. Each side refuses a peer
+/// whose version is older than MIN_SESSION_VERSION, so the newer side decides whether the older one can still be served:
+/// the server answers "REFUSED" with reason=version, the client shows an error banner instead of connecting.
+/// A reply without version comes from a server preceding the descriptor, version 1.
+/// The resolution is the one of the stream when the session started: the rate controller may lower it later,
+/// and the client keeps scaling the frames to the one announced. It is missing when the server does not know it, e.g. in a relay
+/// whose upstream server did not announce it. The features list what the server supports, in alphabetical order:
+/// the client only sends "NACK" and "REPORT" to a server listing them.
+/// In a simulcast session the layers list the heights of the quality layers, from the best to the worst, see the simulcast module:
+/// the resolution is the one of the first layer, and the client keeps scaling the frames to it whatever layer it watches.
+/// The reply then also carries the "layer" the client has been subscribed to. A session with a single layer has no layers.
+
+/// Version of the control protocol spoken by this build.
+pub const SESSION_VERSION: u32 = 2;
+/// Oldest version of the control protocol this build can talk to.
+pub const MIN_SESSION_VERSION: u32 = 2;
+/// Codecs the client is able to decode.
+pub const SUPPORTED_CODECS: [&str; 1] = ["h264"];
+/// Codec of the stream encoded by the server, see utils::get_ffmpeg_command.
+pub const DEFAULT_CODEC: &str = "h264";
+
+/// SessionDescriptor struct contains the description of a casting session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescriptor {
+    pub version: u32,
+    pub codec: String,
+    pub resolution: Option<(u32, u32)>,
+    pub framerate: u32,
+    pub audio: bool,
+    pub title: String,
+    pub features: Vec<String>,
+    pub layers: Vec<u32>,
+}
+
+impl SessionDescriptor {
+    pub fn new(title: String, resolution: Option<(u32, u32)>, framerate: u32) -> Self {
+        SessionDescriptor {
+            version: SESSION_VERSION,
+            codec: DEFAULT_CODEC.to_string(),
+            resolution,
+            framerate,
+            audio: false,
+            title,
+            features: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Return the descriptor with the given features, sorted to get a stable output.
+    pub fn with_features(mut self, features: &[&str]) -> Self {
+        self.features = features.iter().map(|feature| feature.to_string()).collect();
+        self.features.sort();
+        self
+    }
+
+    /// Add the parameters of the descriptor to a message.
+    pub fn add_to(&self, message: ControlMessage) -> ControlMessage {
+        let message = message
+            .with_param("version", self.version)
+            .with_param("codec", &self.codec)
+            .with_param("framerate", self.framerate)
+            .with_param("audio", self.audio)
+            .with_param("title", &self.title)
+            .with_param("features", self.features.join(","));
+        let message = if self.layers.is_empty() {
+            message
+        } else {
+            message.with_param("layers", self.layers.iter().map(u32::to_string).collect::<Vec<String>>().join(","))
+        };
+        match self.resolution {
+            Some((width, height)) => message.with_param("resolution", format!("{width}x{height}")),
+            None => message,
+        }
+    }
+
+    /// Read the descriptor carried by the "OK" reply of the server and check that the client can play the session.
+    /// Returns IncompatibleVersion if the server is too old, UnsupportedStream if the stream can't be decoded or the descriptor is malformed.
+    pub fn from_message(message: &ControlMessage) -> Result<Self, InputError> {
+        let version = match message.param("version") {
+            Some(version) => version.parse::<u32>().map_err(|_| InputError::UnsupportedStream)?,
+            None => 1,
+        };
+        if version < MIN_SESSION_VERSION {
+            return Err(InputError::IncompatibleVersion);
+        }
+        let codec = message.param("codec").ok_or(InputError::UnsupportedStream)?.to_lowercase();
+        if !SUPPORTED_CODECS.contains(&codec.as_str()) {
+            return Err(InputError::UnsupportedStream);
+        }
+        let resolution = match message.param("resolution") {
+            Some(resolution) => Some(parse_resolution(resolution).ok_or(InputError::UnsupportedStream)?),
+            None => None,
+        };
+        let framerate = message.param("framerate").and_then(|framerate| framerate.parse::<u32>().ok())
+            .filter(|framerate| *framerate > 0)
+            .ok_or(InputError::UnsupportedStream)?;
+        let layers = match message.param("layers") {
+            Some(layers) => layers.split(',').map(|height| height.trim().parse::<u32>().ok().filter(|height| *height > 0))
+                .collect::<Option<Vec<u32>>>()
+                .ok_or(InputError::UnsupportedStream)?,
+            None => Vec::new(),
+        };
+
+        Ok(SessionDescriptor {
+            version,
+            codec,
+            resolution,
+            framerate,
+            audio: message.param("audio") == Some("true"),
+            title: message.param("title").unwrap_or("").to_string(),
+            features: message.param("features").unwrap_or("").split(',').filter(|feature| !feature.is_empty()).map(str::to_string).collect(),
+            layers,
+        })
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+}
+
+/// Tell whether the version sent by a client in its "START" request can be served, a request without version comes from version 1.
+pub fn is_compatible(request: &ControlMessage) -> bool {
+    request.param("version").map_or(Some(1), |version| version.parse::<u32>().ok()).is_some_and(|version| version >= MIN_SESSION_VERSION)
+}
+
+// Parse a resolution written as "<width>x<height>", both non-zero.
+fn parse_resolution(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.split_once('x')?;
+    let (width, height) = (width.trim().parse::<u32>().ok()?, height.trim().parse::<u32>().ok()?);
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
@@ -0,0 +1,84 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use socket2::Socket;
+use crate::rate_control::{EncoderTarget, TARGET_LADDER};
+use crate::rtp::TS_PACKET_SIZE;
+
+/// This module contains the quality layers of a simulcast session. If layers are configured (simulcast_layers, e.g. "1080,720,360",
+/// see utils::read_network_config), the StreamingServer encodes the capture once per layer with a single ffmpeg process, splitting
+/// the captured video and scaling each copy to the height of its layer (see utils::get_simulcast_ffmpeg_command): the first layer is read
+/// from the output of the process, the others from the loopback sockets ffmpeg sends them to, bound by the server beforehand.
+/// Each layer is published on its own fan-out with its own history and join cache, and its data and parity packets carry the stream id
+/// of the session plus the index of the layer, so that a client drops the packets of the layer it just left.
+/// Layers are numbered from the best, 0, to the worst. The client picks its layer with the "layer" parameter of "START",
+/// and switches to another one with a "LAYER" request, repeated in its heartbeats in case it gets lost:
+///
+/// LAYER
+/// layer=2
+///
+/// The task of the client is then restarted on the join cache of the new layer, so that the stream it receives starts from a keyframe.
+/// The viewer picks the layer with the picker next to the record button. The StreamingClient drops the packets of the other layers,
+/// and puts the ones of the new layer back in order from scratch, since they are numbered on their own; the decoder keeps scaling
+/// the frames to the resolution of the best layer, so the switch does not restart the playback.
+/// The layers have fixed targets, there is no rate controller adapting them: the viewers on a weak network pick a lower layer instead.
+/// The multicast group is not used in simulcast, since each client may watch a different layer. The RTSP and HLS players get the first layer.
+
+/// Framerate of all the layers, the best one of the rate controller.
+pub const LAYER_FRAMERATE: u32 = TARGET_LADDER[0].framerate;
+/// Time after which the reader of a layer socket checks whether the server has been stopped.
+pub const LAYER_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bitrate of a 1080p layer, the other layers get a bitrate proportional to their number of pixels
+const FULL_HD_BITRATE_KBPS: u64 = 4000;
+const FULL_HD_HEIGHT: u64 = 1080;
+const MIN_LAYER_BITRATE_KBPS: u32 = 300;
+// Datagrams of the layers sent by ffmpeg, 7 TS packets as usual for MPEG-TS over UDP
+const LAYER_DATAGRAM_SIZE: usize = 7 * TS_PACKET_SIZE;
+// Receive buffer of the layer sockets, large enough for the burst of a keyframe
+const LAYER_SOCKET_BUFFER: usize = 4 * 1024 * 1024;
+
+/// Return the targets of the layers with the given heights, from the best to the worst. The encoder never scales the video up,
+/// so the heights above the one of the source are lowered to it, and the layers left with the same height are merged.
+/// Heights are kept even, as the encoder needs.
+pub fn layer_targets(heights: &[u32], source_height: Option<u32>) -> Vec<EncoderTarget> {
+    let mut heights = heights.iter()
+        .map(|height| source_height.map_or(*height, |source_height| (*height).min(source_height)) / 2 * 2)
+        .filter(|height| *height > 0)
+        .collect::<Vec<u32>>();
+    heights.sort_unstable_by(|a, b| b.cmp(a));
+    heights.dedup();
+    heights.into_iter().map(|height| EncoderTarget {
+        bitrate_kbps: layer_bitrate(height),
+        framerate: LAYER_FRAMERATE,
+        height: Some(height),
+    }).collect()
+}
+
+// Return the bitrate of a layer of the given height.
+fn layer_bitrate(height: u32) -> u32 {
+    let height = height as u64;
+    ((FULL_HD_BITRATE_KBPS * height * height / (FULL_HD_HEIGHT * FULL_HD_HEIGHT)) as u32).max(MIN_LAYER_BITRATE_KBPS)
+}
+
+/// Return the resolution of the source scaled down to the given height, keeping the aspect ratio and an even width like the encoder.
+pub fn scaled_resolution((width, height): (u32, u32), target_height: u32) -> (u32, u32) {
+    if target_height >= height {
+        return (width / 2 * 2, height / 2 * 2);
+    }
+    // Like the "scale=-2:<height>" filter, which rounds the width to the nearest even number
+    let half_width = (width as u64 * target_height as u64 + height as u64) / (2 * height as u64);
+    (half_width as u32 * 2, target_height)
+}
+
+/// Bind the loopback socket the encoder sends a layer to, returning it with the URL of the output to give to ffmpeg.
+pub fn layer_socket() -> io::Result<(UdpSocket, String)> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))?;
+    let socket = Socket::from(socket);
+    // The system may grant a smaller buffer, the layer is still received with the default one if the machine keeps up
+    let _ = socket.set_recv_buffer_size(LAYER_SOCKET_BUFFER);
+    socket.set_read_timeout(Some(LAYER_READ_TIMEOUT))?;
+    let socket: UdpSocket = socket.into();
+    let url = format!("udp://{}?pkt_size={LAYER_DATAGRAM_SIZE}", socket.local_addr()?);
+    Ok((socket, url))
+}
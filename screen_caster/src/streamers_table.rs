@@ -3,7 +3,7 @@ use iced::{Element, Alignment, Color, Theme, Length, theme, alignment::Horizonta
 use iced::widget::{Row, Text, TextInput, Button, Column, Container, Svg};
 use rusqlite::{params, Connection};
 use uuid::Uuid;
-use std::net::Ipv4Addr;
+use crate::utils;
 use crate::error_banner::{Banner, InputError};
 
 ///This module contains the StreamersTable struct and its 
@@ -312,7 +312,7 @@ impl StreamersTable{
     }
 
     /// The check_modifications method is used to check the modifications made to the record for any of the CUD operations.
-    /// The method will return an InputError if the name is empty, the ip is not a valid ip, the name is already present in the list of streamers or the ip is already present in the list of streamers.
+    /// The method will return an InputError if the name is empty, the ip is neither a valid IP address nor a host name (see utils::is_valid_host), the name is already present in the list of streamers or the ip is already present in the list of streamers.
     /// The method will return the id, the new name and the new ip if the modifications are valid.
     fn check_modifications(&self, id: Option<String>, opt: CudEnum) -> Result<(Option<String>, String, String), InputError> {
         let streamers = self.get_users();
@@ -325,11 +325,11 @@ impl StreamersTable{
                 }else {
                     new_name = self.name_input.clone().to_ascii_lowercase();
                 }
-                match &self.ip_input.parse::<Ipv4Addr>(){
-                    Err(_) => {
+                match utils::is_valid_host(&self.ip_input){
+                    false => {
                         return Err(InputError::NotAnIp);
                     },
-                    Ok(_) => {new_ip = self.ip_input.clone();}
+                    true => {new_ip = self.ip_input.trim().to_string();}
                 }
                 for record in streamers.iter(){
                         if new_name == record.1.0 {
@@ -356,11 +356,8 @@ impl StreamersTable{
                         }
                     }
                 }
-                match new_ip.parse::<Ipv4Addr>(){
-                    Err(_) => {
-                        return Err(InputError::NotAnIp);
-                    }
-                    _ => {}
+                if !utils::is_valid_host(&new_ip) {
+                    return Err(InputError::NotAnIp);
                 }
             },
             CudEnum::Delete => {
@@ -406,7 +403,7 @@ fn modify_record(conn: &Connection, name: String, ip: String, id: String) {
 }
 
 #[derive(Debug, Clone, Default)]
-struct RecordStyle;
+pub struct RecordStyle;
 
 impl iced::widget::container::StyleSheet for RecordStyle {
     type Style = Theme;
@@ -1,29 +1,55 @@
 use ffmpeg_sidecar::{command::FfmpegCommand, event::FfmpegEvent, event::OutputVideoFrame};
 
-use std::net::UdpSocket;
-use local_ip_address::local_ip;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 
-use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::Ordering};
+use std::fmt;
+use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::AtomicU32, atomic::AtomicUsize, atomic::Ordering};
 use std::thread;
 use std::sync::mpsc::{self, Receiver, Sender};
 use crossbeam_channel::{bounded, Sender as CrossbeamSender, Receiver as CrossbeamReceiver};
 
 use std::process::ChildStdin;
-use std::io::{ Write, BufWriter};
+use std::io::{self, Write, BufWriter};
 use std::path::PathBuf;
 use chrono::Local;
 use std::time::{Instant, Duration};
 use crate::workers::FrameProcessorConstructor;
 use crate::gif_widget::{GifPlayer, GifPlayerMessage};
-
-use iced::{ Subscription, time as iced_time, Element, Length};
-use iced::widget::{Button, image::Handle, image::Image, Text};
-
-const BUFFER_SIZE: usize = 1024;
+use crate::protocol::{self, ControlMessage, PacketHeader, PacketType, ReceiverStats, SequenceTracker};
+use crate::fec::{self, FecDecoder};
+use crate::nack::{self, NackScheduler};
+use crate::crypto::{self, Salt, SessionCipher};
+use crate::utils;
+use crate::rate_control::{self, JitterEstimator, ReceiverReport};
+use crate::multicast;
+use crate::transport::{TcpTransport, Transport, TransportKind, UdpTransport};
+use crate::streaming_server::StreamingServer;
+use crate::batch_io::{self, RecvBatch};
+use crate::session::{self, SessionDescriptor};
+use crate::error_banner::InputError;
+
+use iced::{ Subscription, time as iced_time, Alignment, Element, Length};
+use iced::widget::{Button, Column, image::Handle, image::Image, PickList, Row, Text};
+
+const BUFFER_SIZE: usize = protocol::MAX_DATAGRAM_SIZE;
+// Time without datagrams after which the connection is considered lost
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+// Time given to the server to answer "START" on a transport
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Time given to the server to answer "START" with UDP before falling back to TCP
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(3);
+// Size the frames are scaled to when the server does not announce the resolution of the stream
+const DEFAULT_RESOLUTION: (u32, u32) = (1280, 720);
 
 /// This module manages the streaming client. It is responsible for managing the connection with the server, receiving the video stream and displaying it.
 /// It also manages the recording of the video stream.
 /// When a new connection is issued a new StreamingClient is created.
+/// The socket receiving the stream is bound to the address and port read from the network configuration, and the port actually assigned
+/// is reported to the server in the "START" and "STOP" requests, sent on the transport picked by the viewer (see the transport module).
+/// Data packets are put back in order by a SequenceTracker (see the protocol module), once the lost ones have been rebuilt (see the fec module)
+/// or requested again (see the nack module). While connected, a "HEARTBEAT" is sent every HEARTBEAT_INTERVAL and a "REPORT" every REPORT_INTERVAL.
+/// A "KICKED" message, a "REFUSED" reply or a session descriptor that can't be played (see the session module) end the connection,
+/// and update returns the reason to the GUI. The viewer can turn the client into a relay, see start_relay.
 
 #[derive(Debug, Clone)]
 pub enum VideoPlayerMessage {
@@ -32,17 +58,39 @@ pub enum VideoPlayerMessage {
     Exit,
     StartRecord,
     StopRecord,
+    StartRelay,
+    StopRelay,
     StreamAvailable,
     NoStreamAvailable,
     NoConnection,
+    Kicked,
+    Refused,
+    WrongPin,
+    Incompatible(InputError),
+    SelectLayer(usize),
     GifPlayerMessage(GifPlayerMessage),
 }
 
+/// LayerChoice struct contains a quality layer of a simulcast session offered by the picker, shown by its height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerChoice {
+    pub index: usize,
+    pub height: u32,
+}
+
+impl fmt::Display for LayerChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}p", self.height)
+    }
+}
+
 pub enum StreamingClientStateEnum{
     NotConnected,
     ConnectedNoStreaming,
     Streaming,
     Retry,
+    Kicked,
+    Refused,
 }
 
 pub struct StreamingClient {
@@ -52,31 +100,44 @@ pub struct StreamingClient {
     pid_record: Option<i32>,
     stdin_record: Option<Arc<Mutex<ChildStdin>>>,
     target_address: String,
-    own_ip: String,
+    own_ip: IpAddr,
     current_frame: Handle,
-    socket: Arc<UdpSocket>,
+    transport_kind: TransportKind,
+    udp_transport: Arc<UdpTransport>,
+    transport: Arc<Mutex<Arc<dyn Transport>>>,
     tx_connection_status: CrossbeamSender<VideoPlayerMessage>,
     rx_connection_status: CrossbeamReceiver<VideoPlayerMessage>,
     gif_widget: Option<GifPlayer>,
     state: StreamingClientStateEnum,
     save_dir: String,
+    stream_id: Arc<Mutex<Option<u32>>>,
+    fec_group: Arc<Mutex<u8>>,
+    multicast_group: Arc<Mutex<Option<SocketAddr>>>,
+    descriptor: Arc<Mutex<Option<SessionDescriptor>>>,
+    // Layer received in a simulcast session, 0 otherwise
+    layer: Arc<AtomicUsize>,
+    stats: Arc<Mutex<ReceiverStats>>,
+    heartbeat_running: Arc<AtomicBool>,
+    cipher: Option<Arc<SessionCipher>>,
+    pin: Option<String>,
+    relay: Arc<Mutex<Option<StreamingServer>>>,
+    relay_tx: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
 }
 
 impl StreamingClient {
 
-    pub fn new(source_ip: String, save_dir: String) -> Self {
-        let target_address = format!("{source_ip}:8080");
-        //Check and get local ip address
-        let ip_address: String;
-        match local_ip() {
-            Ok(ip) => ip_address = ip.to_string(),
-            Err(_)=> {
-                panic!()
-            },
-        };
-
-        //Define socket
-        let socket = Arc::new(UdpSocket::bind(format!("{ip_address}:3040")).expect("Failed to bind socket"));  // Il client bind sulla porta 8080
+    /// The server is the address found by the probe of the connect view, see the probe module, together with the salt of the session key
+    /// announced by a protected session.
+    pub fn new(server: SocketAddr, save_dir: String, pin: Option<String>, salt: Option<Salt>, transport_kind: TransportKind) -> Self {
+        let config = utils::read_network_config();
+        // IPv6 addresses are written in brackets, with the scope id of link-local ones
+        let target_address = server.to_string();
+        //Get the address to bind to, by default the local ip address, or any IPv6 address to reach an IPv6 server
+        let ip_address = config.bind_ip_for(server.ip());
+
+        //Define socket, by default on an ephemeral port
+        let socket = UdpSocket::bind(SocketAddr::new(ip_address, config.client_port)).expect("Failed to bind socket");
+        let udp_transport = Arc::new(UdpTransport::new(socket, target_address.clone()));
         let current_frame = Handle::from_memory([0 as u8; 1]);
         let (tx_connection_status, rx_connection_status) = bounded(1);
 
@@ -90,12 +151,25 @@ impl StreamingClient {
             stdin_record: None,
             target_address,
             own_ip: ip_address,
-            socket,
+            transport_kind,
+            udp_transport: udp_transport.clone(),
+            transport: Arc::new(Mutex::new(udp_transport)),
             tx_connection_status,
             rx_connection_status,
             gif_widget: Some(GifPlayer::new()),
             state: StreamingClientStateEnum::NotConnected,
             save_dir,
+            stream_id: Arc::new(Mutex::new(None)),
+            fec_group: Arc::new(Mutex::new(0)),
+            multicast_group: Arc::new(Mutex::new(None)),
+            descriptor: Arc::new(Mutex::new(None)),
+            layer: Arc::new(AtomicUsize::new(0)),
+            stats: Arc::new(Mutex::new(ReceiverStats::default())),
+            heartbeat_running: Arc::new(AtomicBool::new(false)),
+            cipher: pin.as_deref().zip(salt).map(|(pin, salt)| Arc::new(SessionCipher::new(pin, &salt))),
+            pin,
+            relay: Arc::new(Mutex::new(None)),
+            relay_tx: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -112,43 +186,114 @@ impl StreamingClient {
     /// This method initiates the connection with the server
     /// It sends a "START" message to the server and waits for a response.
     /// If the server responds with "OK" it means that we are connected but stream is not yet available.
+    /// The stream id carried by the reply is stored to filter the incoming data packets.
+    /// The request also carries the FEC group size we would like to use, the reply carries the one accepted by the server,
+    /// and the port the stream has to be sent to. The request carries our version of the protocol, the reply the session descriptor.
+    /// The request also carries the layer we would like to receive in a simulcast session, the reply the one we have been subscribed to.
+    /// If a PIN has been provided the request is encrypted with the session key: the server refuses it if its PIN is different.
+    /// That refusal is sent in clear, so anyone could forge it: it doesn't stop the attempts, it only tells why no sealed reply came.
+    /// The transports are tried in turn, the one the server answered on is kept for the rest of the connection.
     fn start_connection(&mut self){
 
-        let mut buffer = [0; BUFFER_SIZE];
-        let message = "START".as_bytes();
         let target = self.target_address.clone();
-        let socket_clone = self.socket.clone();
-        socket_clone.set_read_timeout(Some(Duration::from_secs_f32(0.5))).expect("Failed to set read timeout");
-        let start = Instant::now();
+        let attempts = match self.transport_kind {
+            TransportKind::Auto => vec![(TransportKind::Udp, FALLBACK_TIMEOUT), (TransportKind::Tcp, HANDSHAKE_TIMEOUT)],
+            kind => vec![(kind, HANDSHAKE_TIMEOUT)],
+        };
+        let udp_transport = self.udp_transport.clone();
+        let transport = self.transport.clone();
         let tx_sc = self.tx_connection_status.clone();
         let rx_sc = self.rx_connection_status.clone();
+        let stream_id = self.stream_id.clone();
+        let fec_group = self.fec_group.clone();
+        let multicast_group = self.multicast_group.clone();
+        let descriptor = self.descriptor.clone();
+        let layer = self.layer.clone();
+        let cipher = self.cipher.clone();
 
         // INIT CONNECTION
         thread::spawn(move||{
-            loop {
-                if let Ok(VideoPlayerMessage::Exit) = rx_sc.try_recv(){
-                    break;
-                }
-                if start.elapsed() > Duration::from_secs(5) {
-                    tx_sc.send(VideoPlayerMessage::NoConnection).unwrap();
-                    break;
-                }
-                match socket_clone.send_to(&message, &target) {
-                    Ok(_) => {
-                        match socket_clone.recv(&mut buffer) {
-                            Ok(number_of_bytes) => {
-                                let data = &buffer[..number_of_bytes];
-                                if data == "OK".as_bytes() {
-                                    tx_sc.send(VideoPlayerMessage::NoStreamAvailable).unwrap();
-                                    break;
+            let mut buffer = [0; BUFFER_SIZE];
+            let mut refusal = None;
+            for (kind, timeout) in attempts {
+                let current: Arc<dyn Transport> = match kind {
+                    TransportKind::Tcp => match TcpTransport::connect(&target) {
+                        Ok(tcp) => Arc::new(tcp),
+                        Err(_) => continue,
+                    },
+                    _ => udp_transport.clone(),
+                };
+                current.set_read_timeout(Some(RECEIVE_TIMEOUT)).expect("Failed to set read timeout");
+                // Parity packets are useless on a TCP connection
+                let fec = if kind == TransportKind::Tcp { 0 } else { fec::DEFAULT_FEC_GROUP };
+                let request = ControlMessage::new("START")
+                    .with_param("fec", fec)
+                    .with_param("port", current.local_port())
+                    .with_param("version", session::SESSION_VERSION)
+                    .with_param("layer", layer.load(Ordering::Relaxed));
+                let start = Instant::now();
+
+                while start.elapsed() <= timeout {
+                    if let Ok(VideoPlayerMessage::Exit) = rx_sc.try_recv(){
+                        current.close();
+                        return;
+                    }
+                    // A sealed request is numbered, so every attempt is sealed again not to be dropped as a copy of the previous one
+                    match current.send(&crypto::encode_request(&request, cipher.as_deref())) {
+                        Ok(_) => {
+                            if let Ok(number_of_bytes) = current.recv(&mut buffer) {
+                                if cipher.is_some() {
+                                    refusal = crypto::refusal_hint(&buffer[..number_of_bytes]).or(refusal);
+                                }
+                                if let Some((id, reply)) = crypto::decode_reply(&buffer[..number_of_bytes], cipher.as_deref()) {
+                                    if reply.command == "OK" {
+                                        match SessionDescriptor::from_message(&reply) {
+                                            Ok(session_descriptor) => {
+                                                *stream_id.lock().unwrap() = Some(id);
+                                                *descriptor.lock().unwrap() = Some(session_descriptor);
+                                                layer.store(reply.param("layer").and_then(|layer| layer.parse().ok()).unwrap_or(0), Ordering::Relaxed);
+                                                *fec_group.lock().unwrap() = fec::negotiate_group(reply.param("fec"));
+                                                *multicast_group.lock().unwrap() = reply.param("multicast").and_then(|group| group.parse::<SocketAddr>().ok());
+                                                *transport.lock().unwrap() = current;
+                                                tx_sc.send(VideoPlayerMessage::NoStreamAvailable).unwrap();
+                                            }
+                                            // The session can't be played: keep the transport to leave it, and tell the GUI why
+                                            Err(e) => {
+                                                *transport.lock().unwrap() = current;
+                                                tx_sc.send(VideoPlayerMessage::Incompatible(e)).unwrap();
+                                            }
+                                        }
+                                        return;
+                                    }
+                                    // The streamer banned us or the PIN is wrong, do not keep trying
+                                    if reply.command == "REFUSED" {
+                                        if reply.param("reason") == Some("pin") {
+                                            tx_sc.send(VideoPlayerMessage::WrongPin).unwrap();
+                                        } else if reply.param("reason") == Some("version") {
+                                            tx_sc.send(VideoPlayerMessage::Incompatible(InputError::IncompatibleVersion)).unwrap();
+                                        } else {
+                                            tx_sc.send(VideoPlayerMessage::Refused).unwrap();
+                                        }
+                                        current.close();
+                                        return;
+                                    }
                                 }
                             }
-                            _ => {}
                         }
+                        // The TCP connection has been closed by the server
+                        Err(_) if kind == TransportKind::Tcp => break,
+                        _ => {}
                     }
-                    _ => {}
                 }
+                current.close();
             }
+            // No sealed reply came: a refusal in clear tells why, if any
+            let status = match refusal.as_deref() {
+                Some("pin") => VideoPlayerMessage::WrongPin,
+                Some(_) => VideoPlayerMessage::Refused,
+                None => VideoPlayerMessage::NoConnection,
+            };
+            tx_sc.send(status).unwrap();
         });
     }
     
@@ -160,7 +305,6 @@ impl StreamingClient {
     /// Socket manager also dispatches the frames to the record thread if recording is active.
     /// 
     fn manage_incoming_packets(&mut self){
-        let mut buffer = [0; BUFFER_SIZE];
         //Define playback channels
         let (sender_image, receiver_image): (Sender<Handle>, Receiver<Handle>) = mpsc::channel();
         let (sender_frame, receiver_frame): (Sender<OutputVideoFrame>, Receiver<OutputVideoFrame>) = mpsc::channel();
@@ -173,8 +317,24 @@ impl StreamingClient {
         let is_recording = Arc::new(Mutex::new(false));
         let is_recording1 = is_recording.clone();
 
-        //Clone socket
-        let socket_clone = self.socket.clone();
+        //Clone the transport the server answered on
+        let transport = self.transport.lock().unwrap().clone();
+
+        //Define readers forwarding the datagrams of the transport and of the multicast group, if any, to the socket manager
+        let (tx_datagrams, rx_datagrams) = bounded::<Vec<u8>>(protocol::REORDER_WINDOW * 4);
+        let receiving = Arc::new(AtomicBool::new(true));
+        StreamingClient::spawn_reader(transport.clone(), tx_datagrams.clone(), receiving.clone());
+        if let Some(group) = *self.multicast_group.lock().unwrap() {
+            match multicast::join_group(group, self.own_ip) {
+                Ok(multicast_socket) => {
+                    let multicast_transport = Arc::new(UdpTransport::new(multicast_socket, self.target_address.clone()));
+                    multicast_transport.set_read_timeout(Some(RECEIVE_TIMEOUT)).expect("Failed to set read timeout");
+                    StreamingClient::spawn_reader(multicast_transport, tx_datagrams, receiving.clone());
+                }
+                // Without the stream the connection times out and can be retried
+                Err(e) => println!("Impossibile unirsi al gruppo multicast {group}: {e}"),
+            }
+        }
 
         self.receiver_image = Some(receiver_image);
         self.rx_record = Some(rx_record);
@@ -185,23 +345,176 @@ impl StreamingClient {
         let tx_sm = self.tx_connection_status.clone();
         let tx_pb = self.tx_connection_status.clone();
 
+        let stream_id = *self.stream_id.lock().unwrap();
+        let stats = self.stats.clone();
+        let mut tracker = SequenceTracker::new(protocol::REORDER_WINDOW);
+        let mut decoder = if *self.fec_group.lock().unwrap() > 0 { Some(FecDecoder::new()) } else { None };
+        let mut scheduler = NackScheduler::new();
+        let mut newest_timestamp = 0;
+        let mut jitter = JitterEstimator::new();
+        // Number of chunks sent to the playback thread and not written to the decoder yet
+        let backlog = Arc::new(AtomicU32::new(0));
+        let backlog_sm = backlog.clone();
+        let backlog_pb = backlog.clone();
+        let cipher = self.cipher.clone();
+        // What the server supports and how the stream has to be decoded, as described by the server
+        let descriptor = self.descriptor.lock().unwrap().clone().expect("Connected without a session descriptor");
+        let send_reports = descriptor.supports("report");
+        let send_nacks = descriptor.supports("nack");
+        let simulcast = descriptor.supports("simulcast");
+        let layer = self.layer.clone();
+        let heartbeat_layer = self.layer.clone();
+        let mut current_layer = layer.load(Ordering::Relaxed);
+
+        // HEARTBEAT
+        // Stop the heartbeat thread of a previous connection attempt, if any, and start a new one
+        self.heartbeat_running.store(false, Ordering::Relaxed);
+        self.heartbeat_running = Arc::new(AtomicBool::new(true));
+        let heartbeat_running = self.heartbeat_running.clone();
+        let heartbeat_running_sm = self.heartbeat_running.clone();
+        let heartbeat_transport = transport.clone();
+        let heartbeat_cipher = self.cipher.clone();
+        let report_stats = self.stats.clone();
+        let relay = self.relay.clone();
+        let relay_tx = self.relay_tx.clone();
+        thread::spawn(move || {
+            let mut last_report = Instant::now();
+            let mut last_stats = ReceiverStats::default();
+            while heartbeat_running.load(Ordering::Relaxed) {
+                // In a simulcast session the heartbeat repeats the layer we want, in case the "LAYER" request got lost
+                let heartbeat = if simulcast {
+                    ControlMessage::new("HEARTBEAT").with_param("layer", heartbeat_layer.load(Ordering::Relaxed))
+                } else {
+                    ControlMessage::new("HEARTBEAT")
+                };
+                let heartbeat = crypto::encode_request(&heartbeat, heartbeat_cipher.as_deref());
+                let _ = heartbeat_transport.send(&heartbeat);
+                // The loss is computed over the packets expected since the previous report
+                if send_reports && last_report.elapsed() >= rate_control::REPORT_INTERVAL {
+                    let current_stats = *report_stats.lock().unwrap();
+                    let lost = current_stats.lost.saturating_sub(last_stats.lost);
+                    let expected = current_stats.received.saturating_sub(last_stats.received) + lost;
+                    let report = ReceiverReport {
+                        loss_permille: (lost * 1000).checked_div(expected).unwrap_or(0) as u32,
+                        jitter_ms: current_stats.jitter_ms,
+                        backlog: backlog.load(Ordering::Relaxed),
+                    };
+                    let _ = heartbeat_transport.send(&crypto::encode_request(&report.to_message(), heartbeat_cipher.as_deref()));
+                    last_stats = current_stats;
+                }
+                // Tell the upstream server which viewers we are relaying the stream to
+                if last_report.elapsed() >= rate_control::REPORT_INTERVAL {
+                    if let Some(relay_server) = relay.lock().unwrap().as_ref() {
+                        let _ = heartbeat_transport.send(&crypto::encode_request(&relay_server.relay_message(), heartbeat_cipher.as_deref()));
+                    }
+                    last_report = Instant::now();
+                }
+                thread::sleep(protocol::HEARTBEAT_INTERVAL);
+            }
+        });
+
         // SOCKET MANAGER
         thread::spawn(move || {
-            loop {
-                match socket_clone.recv(&mut buffer) {
-                    Ok(number_of_bytes) => {
-                        let data = &buffer[..number_of_bytes];
-                        if let Err(_) = tx_playback.send(data.to_vec()) {
-                            break;
+            'receive: loop {
+                match rx_datagrams.recv_timeout(RECEIVE_TIMEOUT) {
+                    Ok(datagram) => {
+                        // Decrypt the datagram, dropping the ones not sealed with our session key
+                        let packet = match cipher.as_deref() {
+                            Some(cipher) => match cipher.open(&datagram) {
+                                Some(packet) => packet,
+                                None => {
+                                    tracker.mark_auth_failed();
+                                    continue;
+                                }
+                            },
+                            None => datagram,
+                        };
+                        let (header, payload) = match PacketHeader::decode(&packet) {
+                            Some(packet) => packet,
+                            None => continue,
+                        };
+                        // The viewer picked another layer: its packets are numbered on their own, start over
+                        let picked_layer = layer.load(Ordering::Relaxed);
+                        if picked_layer != current_layer {
+                            current_layer = picked_layer;
+                            tracker.restart();
+                            decoder = decoder.map(|_| FecDecoder::new());
+                            scheduler = NackScheduler::new();
                         }
-                        let is_recording_guard = is_recording1.lock().unwrap();
-                        if *is_recording_guard {
-                            drop(is_recording_guard);
-                            let _  = tx_record.send(data.to_vec());
-                        }else{
-                            drop(is_recording_guard);
+                        // Drop the packets of a previous casting session, and the ones of the layers we are not watching
+                        let expected_stream_id = match header.packet_type {
+                            PacketType::Control => stream_id,
+                            _ => stream_id.map(|id| id.wrapping_add(current_layer as u32)),
+                        };
+                        if Some(header.stream_id) != expected_stream_id {
+                            tracker.mark_stale();
+                            continue;
+                        }
+                        // The streamer removed us from the session: stop receiving without falling into Retry.
+                        // In a protected session the copies of a control message replayed on the network are dropped
+                        if header.packet_type == PacketType::Control {
+                            if cipher.as_deref().is_some_and(|cipher| !cipher.is_fresh(&header)) {
+                                continue;
+                            }
+                            if let Some((_, message)) = protocol::decode_control(&packet) {
+                                if message.command == "KICKED" {
+                                    heartbeat_running_sm.store(false, Ordering::Relaxed);
+                                    let _ = tx_sm.send(VideoPlayerMessage::Kicked);
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+
+                        if header.packet_type == PacketType::Data {
+                            newest_timestamp = newest_timestamp.max(header.timestamp);
+                            jitter.on_packet(Instant::now(), header.timestamp);
+                        }
+                        let ready = match (header.packet_type, decoder.as_mut()) {
+                            (PacketType::Data, Some(decoder)) => {
+                                decoder.on_data(header.sequence, payload);
+                                tracker.push(header.sequence, payload.to_vec())
+                            },
+                            (PacketType::Data, None) => tracker.push(header.sequence, payload.to_vec()),
+                            // Rebuild the missing packet of the group, if possible
+                            (PacketType::Parity, Some(decoder)) => {
+                                match decoder.on_parity(header.sequence, payload) {
+                                    Some((sequence, rebuilt)) => tracker.push(sequence, rebuilt),
+                                    None => continue,
+                                }
+                            },
+                            _ => continue,
+                        };
+
+                        // Ask the server to send again the packets still missing
+                        let ranges = if send_nacks { scheduler.poll(&tracker.missing_ranges(), Instant::now()) } else { Vec::new() };
+                        if !ranges.is_empty() {
+                            let nack_message = ControlMessage::new("NACK")
+                                .with_param("ranges", nack::format_ranges(&ranges))
+                                .with_param("deadline", newest_timestamp + nack::NACK_DEADLINE_MS);
+                            let _ = transport.send(&crypto::encode_request(&nack_message, cipher.as_deref()));
                         }
 
+                        let mut current_stats = tracker.stats();
+                        current_stats.recovered = decoder.as_ref().map_or(0, |decoder| decoder.recovered());
+                        current_stats.jitter_ms = jitter.jitter_ms();
+                        *stats.lock().unwrap() = current_stats;
+                        for data in ready {
+                            let is_recording_guard = is_recording1.lock().unwrap();
+                            if *is_recording_guard {
+                                drop(is_recording_guard);
+                                let _  = tx_record.send(data.clone());
+                            }else{
+                                drop(is_recording_guard);
+                            }
+                            if let Some(relay_tx) = relay_tx.lock().unwrap().as_ref() {
+                                let _ = relay_tx.send(data.clone());
+                            }
+                            backlog_sm.fetch_add(1, Ordering::Relaxed);
+                            if tx_playback.send(data).is_err() {
+                                break 'receive;
+                            }
+                        }
                     }
                     Err(_) => {
                         let _ = tx_sm.send(VideoPlayerMessage::NoConnection);
@@ -209,14 +522,21 @@ impl StreamingClient {
                     }
                 }
             }
+            // Stop the readers, releasing the transport for a new connection attempt
+            receiving.store(false, Ordering::Relaxed);
         });
         // PLAYBACK
         thread::spawn(move || {
-            // Configura ffmpeg-sidecar per ricevere dati tramite UDP
+            // Configura ffmpeg-sidecar per ricevere dati tramite UDP, decodificando il codec annunciato dal server
+            // e scalando i frame alla risoluzione annunciata
+            let (width, height) = descriptor.resolution.unwrap_or(DEFAULT_RESOLUTION);
             let mut ffmpeg_command = FfmpegCommand::new()
                 //.input("udp:/192.168.1.95:1936?overrun_nonfatal=1&fifo_size=50000000")
+                .format("mpegts")
+                .codec_video(&descriptor.codec)
                 .input("pipe:0")
-                .args(&[ "-fflags", "nobuffer", "-flags", "low_delay", "-vf", "scale=1280:720",])
+                .args(["-fflags", "nobuffer", "-flags", "low_delay", "-vf", &format!("scale={width}:{height}")])
+                .no_audio()
                 .rawvideo()
                 .spawn()
                 .expect("Impossibile avviare ffmpeg");
@@ -257,6 +577,7 @@ impl StreamingClient {
             while !stop_receiving_ffpmeg.load(Ordering::Relaxed) {
                 match rx_playback.recv() {
                     Ok(data) => {
+                        backlog_pb.fetch_sub(1, Ordering::Relaxed);
                         writer.write_all(&data).unwrap();
 
                     }
@@ -270,37 +591,96 @@ impl StreamingClient {
         });
     }
 
+    /// This method starts a thread forwarding the datagrams received by a transport to the socket manager, until receiving is cleared
+    /// or the TCP connection is closed. The transport must have a read timeout, used to check the flag.
+    fn spawn_reader(transport: Arc<dyn Transport>, tx_datagrams: CrossbeamSender<Vec<u8>>, receiving: Arc<AtomicBool>) {
+        thread::spawn(move || {
+            let mut batch = RecvBatch::new(batch_io::MAX_BATCH);
+            while receiving.load(Ordering::Relaxed) {
+                match transport.recv_batch(&mut batch) {
+                    Ok(_) => {
+                        if batch.datagrams().any(|datagram| tx_datagrams.send(datagram.to_vec()).is_err()) {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(_) => {}
+                }
+            }
+        });
+    }
+
     /// This method sends a "STOP" message to the server to inform the server we are leaving.
+    /// With UDP the message is sent from a new socket on an ephemeral port, so that the reply is not consumed by the socket manager,
+    /// and carries the port of the socket receiving the stream. With TCP it is sent once on the connection, which is then closed.
+    /// It also stops the heartbeat thread.
     fn on_exit(&mut self) {
-        match UdpSocket::bind(format!("{}:3043", self.own_ip)){
-            Ok(s) => {
-                let socket = Arc::new(s);
-
-                let mut buffer = [0; BUFFER_SIZE];
-                let address = self.target_address.clone();
-                let message = format!("STOP\n{}:3040", self.own_ip);
-                socket.set_read_timeout(Some(Duration::from_secs_f32(0.2))).expect("Failed to set read timeout");
-                let start = Instant::now();
+        self.heartbeat_running.store(false, Ordering::Relaxed);
+        let transport = self.transport.lock().unwrap().clone();
+        let request = ControlMessage::new("STOP").with_param("port", transport.local_port());
+        if transport.kind() == TransportKind::Tcp {
+            let _ = transport.send(&crypto::encode_request(&request, self.cipher.as_deref()));
+            transport.close();
+            return;
+        }
+        if let Ok(s) = UdpSocket::bind(SocketAddr::new(self.own_ip, 0)) {
+            let socket = Arc::new(s);
 
-                loop{
-                    if start.elapsed() > Duration::from_secs(1) {
-                        break;
-                    }
-                    let _ = socket.send_to(&message.as_bytes(), &address);
-                    match socket.recv(&mut buffer) {
-                        Ok(number_of_bytes) => {
-                            let data = &buffer[..number_of_bytes];
-                            if data == "OK".as_bytes() {
-                                break;
-                            }
+            let mut buffer = [0; BUFFER_SIZE];
+            let address = self.target_address.clone();
+            socket.set_read_timeout(Some(Duration::from_secs_f32(0.2))).expect("Failed to set read timeout");
+            let start = Instant::now();
+
+            loop{
+                if start.elapsed() > Duration::from_secs(1) {
+                    break;
+                }
+                // Sealed again at every attempt, see start_connection
+                let _ = socket.send_to(&crypto::encode_request(&request, self.cipher.as_deref()), &address);
+                if let Ok(number_of_bytes) = socket.recv(&mut buffer) {
+                    if let Some((_, reply)) = crypto::decode_reply(&buffer[..number_of_bytes], self.cipher.as_deref()) {
+                        if reply.command == "OK" {
+                            break;
                         }
-                        Err(_) => {}
                     }
                 }
-                drop(socket);
-            },
-            Err(_) => {
             }
+            drop(socket);
+        }
+    }
+
+    /// This method moves us to another layer of a simulcast session: the server is asked for it at once, and the heartbeats repeat the request.
+    /// The socket manager drops the packets of the previous layer from now on.
+    fn select_layer(&mut self, layer: usize) {
+        self.layer.store(layer, Ordering::Relaxed);
+        let transport = self.transport.lock().unwrap().clone();
+        let request = ControlMessage::new("LAYER").with_param("layer", layer);
+        let _ = transport.send(&crypto::encode_request(&request, self.cipher.as_deref()));
+    }
+
+    /// This method starts relaying the stream to downstream clients, with a StreamingServer fed by the socket manager.
+    /// The server listens on the configured server port with the same PIN, and the viewers it serves are sent upstream
+    /// in a "RELAY" control message every REPORT_INTERVAL.
+    fn start_relay(&mut self) {
+        let mut relay = self.relay.lock().unwrap();
+        if relay.is_none() {
+            let (tx, rx) = mpsc::channel();
+            let mut server = StreamingServer::new();
+            server.set_pin(self.pin.clone());
+            let descriptor = self.descriptor.lock().unwrap().clone().unwrap_or_else(|| SessionDescriptor::new(utils::host_name(), None, rate_control::TARGET_LADDER[0].framerate));
+            server.start_relay(vec![rx], descriptor);
+            *self.relay_tx.lock().unwrap() = Some(tx);
+            *relay = Some(server);
+        }
+    }
+
+    /// This method stops relaying the stream, disconnecting the downstream clients.
+    fn stop_relay(&mut self) {
+        // Closing the channel makes the sender thread of the relay exit
+        self.relay_tx.lock().unwrap().take();
+        let server = self.relay.lock().unwrap().take();
+        if let Some(mut server) = server {
+            server.stop();
         }
     }
 
@@ -399,6 +779,25 @@ impl StreamingClient {
                 self.state = StreamingClientStateEnum::Retry;
                 None
             }
+            VideoPlayerMessage::Kicked => {
+                self.state = StreamingClientStateEnum::Kicked;
+                None
+            }
+            VideoPlayerMessage::Refused => {
+                self.state = StreamingClientStateEnum::Refused;
+                None
+            }
+            // The GUI is in charge of showing the error, since the connection has to be retried with another PIN
+            VideoPlayerMessage::WrongPin => {
+                self.state = StreamingClientStateEnum::Refused;
+                Some(VideoPlayerMessage::WrongPin)
+            }
+            // The same goes for a session that can't be played, which we leave at once
+            VideoPlayerMessage::Incompatible(e) => {
+                self.state = StreamingClientStateEnum::Refused;
+                self.on_exit();
+                Some(VideoPlayerMessage::Incompatible(e))
+            }
             VideoPlayerMessage::NoStreamAvailable =>{
                 self.state = StreamingClientStateEnum::ConnectedNoStreaming;
                 self.manage_incoming_packets();
@@ -414,6 +813,7 @@ impl StreamingClient {
                 if let Some(_) = self.pid_record {
                     self.stop_record();
                 }
+                self.stop_relay();
                 self.on_exit();
                 None
             }
@@ -440,6 +840,18 @@ impl StreamingClient {
                 self.stop_record();
                 None
             }
+            VideoPlayerMessage::StartRelay => {
+                self.start_relay();
+                None
+            }
+            VideoPlayerMessage::StopRelay => {
+                self.stop_relay();
+                None
+            }
+            VideoPlayerMessage::SelectLayer(layer) => {
+                self.select_layer(layer);
+                None
+            }
         }
     }
 
@@ -459,6 +871,12 @@ impl StreamingClient {
                     .on_press(VideoPlayerMessage::Connect)
                     .into()
             }
+            StreamingClientStateEnum::Kicked => {
+                Text::new("Sei stato rimosso dalla sessione dall'host").size(24).into()
+            }
+            StreamingClientStateEnum::Refused => {
+                Text::new("L'host ha rifiutato la connessione").size(24).into()
+            }
             _ => {
                 self.gif_widget.as_ref().unwrap().view().map(VideoPlayerMessage::GifPlayerMessage)
             }
@@ -488,6 +906,76 @@ impl StreamingClient {
 
     }
 
+    /// This method returns the picker of the quality layer to receive, shown while streaming a simulcast session
+    pub fn view_layer_picker(&self) -> Option<Element<VideoPlayerMessage>> {
+        match self.state{
+            StreamingClientStateEnum::Streaming => {
+                let layers = self.descriptor.lock().unwrap().as_ref().map_or(Vec::new(), |descriptor| descriptor.layers.clone());
+                if layers.len() < 2 {
+                    return None;
+                }
+                let choices = layers.iter().enumerate().map(|(index, height)| LayerChoice { index, height: *height }).collect::<Vec<LayerChoice>>();
+                let selected = choices.get(self.layer.load(Ordering::Relaxed)).copied();
+                Some(Row::new()
+                    .spacing(10)
+                    .align_items(Alignment::Center)
+                    .push(Text::new("Qualità:").size(14))
+                    .push(PickList::new(choices, selected, |choice: LayerChoice| VideoPlayerMessage::SelectLayer(choice.index)).padding(10))
+                    .into())
+            },
+            _ => {None}
+        }
+    }
+
+    /// This method returns the button starting or stopping the relay, with the number of viewers served while relaying
+    pub fn view_relay_button(&self) -> Option<Element<VideoPlayerMessage>> {
+        match self.state{
+            StreamingClientStateEnum::Streaming => {
+                match self.relay.lock().unwrap().as_ref() {
+                    Some(server) => Some(Row::new()
+                        .spacing(10)
+                        .align_items(Alignment::Center)
+                        .push(Button::new(Text::new("Ferma relay").horizontal_alignment(iced::alignment::Horizontal::Center))
+                            .padding(10)
+                            .width(Length::Fixed(200.0))
+                            .on_press(VideoPlayerMessage::StopRelay))
+                        .push(Text::new(format!("Spettatori serviti: {}", server.clients_snapshot().len())).size(14))
+                        .into()),
+                    None => Some(Button::new(Text::new("Avvia relay").horizontal_alignment(iced::alignment::Horizontal::Center))
+                        .padding(10)
+                        .width(Length::Fixed(200.0))
+                        .on_press(VideoPlayerMessage::StartRelay)
+                        .into()),
+                }
+            },
+            _ => {None}
+        }
+    }
+
+    /// This method returns the reception statistics of the stream, shown below the video while streaming
+    pub fn view_stats(&self) -> Option<Element<VideoPlayerMessage>> {
+        match self.state{
+            StreamingClientStateEnum::Streaming => {
+                let stats = *self.stats.lock().unwrap();
+                let stats_text = Text::new(format!("Pacchetti ricevuti: {}  persi: {}  recuperati: {}  fuori ordine: {}  scartati: {}  non autenticati: {}",
+                    stats.received, stats.lost, stats.recovered, stats.reordered, stats.stale + stats.duplicated, stats.auth_failed)).size(14);
+                // The session as described by the server when we connected
+                let session_text = self.descriptor.lock().unwrap().as_ref().map(|descriptor| Text::new(format!("{}: {} {}, {} fps, {}",
+                    descriptor.title,
+                    descriptor.codec,
+                    descriptor.resolution.map_or("risoluzione sconosciuta".to_string(), |(width, height)| format!("{width}x{height}")),
+                    descriptor.framerate,
+                    if descriptor.audio { "con audio" } else { "senza audio" })).size(14));
+                let column = Column::new().spacing(5).align_items(Alignment::Center).push(stats_text);
+                Some(match session_text {
+                    Some(session_text) => column.push(session_text).into(),
+                    None => column.into(),
+                })
+            },
+            _ => {None}
+        }
+    }
+
     pub fn subscription(&self) -> Subscription<VideoPlayerMessage>{
         match self.state{
             StreamingClientStateEnum::Streaming => {iced_time::every(Duration::from_secs_f32(1.0/40.0 )).map(|_| VideoPlayerMessage::NextFrame)},
@@ -508,6 +996,7 @@ impl Drop for StreamingClient {
         if let Some(_) = self.pid_record {
             self.stop_record();
         }
+        self.stop_relay();
         self.on_exit();
     }
 }
\ No newline at end of file
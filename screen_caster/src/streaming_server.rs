@@ -1,42 +1,667 @@
-use std::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
 use std::thread;
 use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use local_ip_address::local_ip;
-
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use ffmpeg_sidecar::command::FfmpegCommand;
 use ffmpeg_sidecar::child::FfmpegChild;
-use std::io::{Read, Write, BufReader};
-use std::time::Duration;
+use std::io::{self, Read, Write, BufReader};
+use std::process::ChildStdout;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
 use crate::gui::ShareMode;
-use crate::utils;
+use crate::utils::{self, NetworkConfig};
+use crate::protocol::{self, ControlMessage, PacketHeader, PacketType};
+use crate::fec::{self, FecEncoder};
+use crate::lossy_socket::LossySocket;
+use crate::nack::{self, RetransmissionHistory, RetransmissionStats};
+use crate::crypto::{self, Rejection, SessionCipher};
+use crate::discovery::BeaconSender;
+use crate::multicast;
+use crate::rate_control::{EncoderTarget, RateChange, RateController, ReceiverReport};
+use crate::transport::{self, ServerTransport};
+use crate::rtsp::RtspServer;
+use crate::hls::HttpServer;
+use crate::pacing::{self, Pacer, TokenBucket};
+use crate::client_queue::{self, QueueEvent, QueueStats, QueuedPacket};
+use crate::join_cache::JoinCache;
+use crate::rtp;
+use crate::ts_chunker::{self, TsChunker};
+use crate::batch_io;
+use crate::session::{self, SessionDescriptor};
+use crate::simulcast;
 
 /// This module contains the StreamingServer struct and its implementation.
 /// The StreamingServer struct is responsible for starting and stopping the screen casting process.
 /// The StreamingServer is in charge of sending the screen casting data to the clients.
 /// The list of clients is updated dinamically when a new client connects or disconnects.
 /// When the server is stopped, the server will notify all the connected clients and terminate the threads.
+/// The networking runs on a tokio runtime driven by a single thread: a control task handles the requests of all the clients one at a time,
+/// and a task per client sends it the stream published by the fan-out thread of its layer, see the client_queue module.
+/// Clients missing MAX_MISSED_HEARTBEATS heartbeats in a row are evicted, and the streamer can kick or ban them from the roster (see clients_snapshot).
+/// Each feature of the session is described by the module implementing it: transport, ts_chunker, fec, nack, pacing, join_cache, simulcast,
+/// rate_control, multicast, session, crypto, discovery, rtsp and hls. A relay is started with start_relay.
 
-const BUFFER_SIZE: usize = 1024;
+// Bytes read at once from the source of the stream, cut in chunks of whole TS packets by a TsChunker
+const BUFFER_SIZE: usize = 64 * 1024;
 
+// In multicast mode clients have no task sending them the stream, since it is sent once to the group.
 struct Client{
-    tx: std::sync::mpsc::Sender<Vec<u8>>,
+    task: Option<JoinHandle<()>>,
+    queue: Option<Arc<QueueStats>>,
+    fec_group: u8,
+    retransmission: RetransmissionStats,
+    last_seen: Instant,
+    connected_at: DateTime<Local>,
+    bytes_sent: Arc<AtomicU64>,
+    // Viewers served by the client, if it is a relay, with their depth below it
+    downstream: Vec<(String, u32)>,
+    // Pacing delay of the last packet sent, in microseconds
+    pacing_delay_us: Arc<AtomicU64>,
+    // Index of the layer received, always 0 with a single layer
+    layer: usize,
+}
+
+impl Client {
+    // Stop the task sending the stream to the client.
+    fn disconnect(self) {
+        if let Some(task) = self.task {
+            task.abort();
+        }
+    }
+}
+
+// Build the parity packet of the FEC group completed by a data packet, if any.
+fn parity_packet(encoder: &mut FecEncoder, stream_id: u32, data: &[u8]) -> Option<Vec<u8>> {
+    let (header, payload) = PacketHeader::decode(data)?;
+    let (first_sequence, parity) = encoder.push(header.sequence, payload)?;
+    Some(PacketHeader::new(PacketType::Parity, stream_id, first_sequence, header.timestamp).encode(&parity))
+}
+
+// Build the "OK" reply to a "START" request, carrying the session descriptor, the FEC group size and the multicast group, if any.
+// In a simulcast session the reply also carries the layer the client receives.
+fn start_reply(descriptor: &SessionDescriptor, fec_group: u8, multicast_group: Option<SocketAddr>, layer: usize) -> ControlMessage {
+    let reply = descriptor.add_to(ControlMessage::new("OK")).with_param("fec", fec_group);
+    let reply = if descriptor.layers.is_empty() { reply } else { reply.with_param("layer", layer) };
+    match multicast_group {
+        Some(group) => reply.with_param("multicast", group),
+        None => reply,
+    }
+}
+
+// Wait until the pacer lets a packet of the given size go, returning the time waited.
+fn wait_pacing(pacer: &mut Pacer, bytes: usize) -> Duration {
+    let delay = pacer.delay(bytes, Instant::now());
+    if !delay.is_zero() {
+        thread::sleep(delay);
+    }
+    delay
+}
+
+// Datagram enum contains a datagram to send to a client: the data packet shared by all the clients of an open session,
+// or a packet built for the client, encrypted or FEC parity.
+enum Datagram {
+    Shared(Arc<QueuedPacket>),
+    Own(Vec<u8>),
+}
+
+impl Datagram {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Datagram::Shared(data) => &data.packet,
+            Datagram::Own(packet) => packet,
+        }
+    }
+}
+
+// Format the viewers of a "RELAY" message.
+fn format_viewers(viewers: &[(String, u32)]) -> String {
+    viewers.iter().map(|(address, depth)| format!("{address}/{depth}")).collect::<Vec<String>>().join(",")
+}
+
+// Parse the viewers of a "RELAY" message, skipping the invalid entries.
+fn parse_viewers(viewers: &str) -> Vec<(String, u32)> {
+    viewers.split(',')
+        .filter_map(|viewer| viewer.rsplit_once('/'))
+        .filter_map(|(address, depth)| Some((address.parse::<SocketAddr>().ok()?.to_string(), depth.parse::<u32>().ok()?)))
+        .collect()
+}
+
+// StreamSource enum contains where the fan-out thread reads the stream from: the output of the encoder,
+// with the channel the outputs of its restarts are received from, the stream received from the upstream server when relaying,
+// or the socket the encoder sends a layer other than the first one to in a simulcast session.
+enum StreamSource {
+    Encoder(BufReader<ChildStdout>, Receiver<BufReader<ChildStdout>>),
+    Relay(Receiver<Vec<u8>>),
+    Layer(UdpSocket),
+}
+
+impl StreamSource {
+    // Return the next chunk of the stream, None when the stream has ended or the server has been stopped.
+    fn next_chunk(&mut self, buffer: &mut [u8], terminate: &Mutex<bool>) -> Option<Vec<u8>> {
+        match self {
+            StreamSource::Encoder(reader, reader_rx) => loop {
+                let n = reader.read(buffer).unwrap();
+                if n > 0 {
+                    return Some(buffer[..n].to_vec());
+                }
+                // The encoder has terminated: go on with the output of the new one if it has been restarted
+                *reader = reader_rx.try_recv().ok()?;
+            },
+            StreamSource::Relay(upstream) => loop {
+                match upstream.recv_timeout(Duration::from_secs(1)) {
+                    Ok(chunk) => return Some(chunk),
+                    Err(RecvTimeoutError::Timeout) if !*terminate.lock().unwrap() => continue,
+                    Err(_) => return None,
+                }
+            },
+            // The socket has a read timeout, see simulcast::layer_socket
+            StreamSource::Layer(socket) => loop {
+                match socket.recv(buffer) {
+                    Ok(n) => return Some(buffer[..n].to_vec()),
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) && !*terminate.lock().unwrap() => continue,
+                    Err(_) => return None,
+                }
+            },
+        }
+    }
+}
+
+// Encoder struct contains the ffmpeg process encoding the screen and what is needed to restart it with another target.
+struct Encoder {
+    screen_index: usize,
+    crop: Option<CropArea>,
+    process: Mutex<Option<FfmpegChild>>,
+    reader_tx: std::sync::mpsc::Sender<BufReader<ChildStdout>>,
+}
+
+impl Encoder {
+    // Start an ffmpeg process encoding the screen with the given target, returning it with a reader over its output.
+    fn spawn(screen_index: usize, crop: Option<CropArea>, target: EncoderTarget) -> (FfmpegChild, BufReader<ChildStdout>) {
+        Encoder::run(&utils::get_ffmpeg_command(screen_index, crop, target))
+    }
+
+    // Start an ffmpeg process encoding the screen once per layer with the given targets, see the simulcast module,
+    // returning it with a reader over the output of the first layer. The other layers are sent to the given outputs.
+    fn spawn_layers(screen_index: usize, crop: Option<CropArea>, targets: &[EncoderTarget], outputs: &[String]) -> (FfmpegChild, BufReader<ChildStdout>) {
+        let outputs = ["pipe:1".to_string()].into_iter().chain(outputs.iter().cloned()).collect::<Vec<String>>();
+        Encoder::run(&utils::get_simulcast_ffmpeg_command(screen_index, crop, targets, &outputs))
+    }
+
+    // Run an ffmpeg command, returning the process with a reader over its output.
+    fn run(command: &str) -> (FfmpegChild, BufReader<ChildStdout>) {
+        let ffmpeg_command = command.split(" ").collect::<Vec<&str>>();
+        let mut ffmpeg = FfmpegCommand::new().args(&ffmpeg_command).spawn().expect("Failed to start FFmpeg");
+        let reader = BufReader::new(ffmpeg.take_stdout().unwrap());
+        (ffmpeg, reader)
+    }
+
+    // Start a new ffmpeg process with the given target and stop the current one.
+    // The fan-out thread reads the output of the current process up to its end, then goes on with the output of the new one.
+    fn restart(&self, target: EncoderTarget) {
+        let (ffmpeg, reader) = Encoder::spawn(self.screen_index, self.crop, target);
+        let mut process = self.process.lock().unwrap();
+        // The server has been stopped in the meantime
+        if process.is_none() || self.reader_tx.send(reader).is_err() {
+            drop(process);
+            Encoder::quit(ffmpeg);
+            return;
+        }
+        let previous = process.replace(ffmpeg);
+        drop(process);
+        if let Some(previous) = previous {
+            Encoder::quit(previous);
+        }
+    }
+
+    // Stop the current ffmpeg process, no restart is possible afterwards.
+    fn stop(&self) {
+        let process = self.process.lock().unwrap().take();
+        if let Some(process) = process {
+            Encoder::quit(process);
+        }
+    }
+
+    // Send "q" to the stdin of the ffmpeg process, making it flush its output and exit.
+    fn quit(mut process: FfmpegChild) {
+        if let Some(mut stdin) = process.take_stdin() {
+            let _ = writeln!(stdin, "q");
+        }
+        // The session is stopping anyway, e.g. the process may have already been reaped
+        if let Err(e) = process.wait() {
+            println!("Impossibile attendere la terminazione di ffmpeg: {e}");
+        }
+    }
+}
+
+// ServerEvent enum contains the events handled by the control task besides the requests of the clients.
+// - Overflow: the queue of a client overflowed and the policy is to disconnect it
+// - Kick: the streamer kicked a client, the flag tells whether it has also been banned
+enum ServerEvent {
+    Overflow(String),
+    Kick(String, bool),
+    Stop,
+}
+
+// Layer struct contains a quality layer of the session: the stream id of its packets, the history answering the NACKs of its clients,
+// the join cache they subscribe to and, in simulcast, the fixed target of its encoder. A session without simulcast has a single layer.
+struct Layer {
+    stream_id: u32,
+    target: Option<EncoderTarget>,
+    history: Mutex<RetransmissionHistory>,
+    join_cache: JoinCache,
+}
+
+// Session struct contains what the control task needs to serve the clients of a casting session.
+struct Session {
+    stream_id: u32,
+    config: NetworkConfig,
+    transport: Arc<ServerTransport>,
+    cipher: Option<Arc<SessionCipher>>,
+    clients: Arc<Mutex<HashMap<String, Client>>>,
+    // Number of connected clients, read by the fan-out thread in multicast mode
+    viewers: Arc<AtomicUsize>,
+    evicted_clients: Arc<Mutex<Vec<(String, &'static str)>>>,
+    banned_ips: Arc<Mutex<HashSet<IpAddr>>>,
+    rate_controller: Arc<Mutex<RateController>>,
+    layers: Vec<Arc<Layer>>,
+    start: Instant,
+    encoder: Option<Arc<Encoder>>,
+    total_bucket: Option<Arc<Mutex<TokenBucket>>>,
+    multicast_group: Option<SocketAddr>,
+    descriptor: SessionDescriptor,
+    events: UnboundedSender<ServerEvent>,
+}
+
+impl Session {
+    // Run the control task: receive the requests of the clients on every transport and handle them one at a time,
+    // together with the events of the server, until the server is stopped.
+    async fn run(self, mut events: UnboundedReceiver<ServerEvent>) {
+        let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+        let mut eviction = tokio::time::interval(protocol::HEARTBEAT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                received = self.transport.recv_from(&mut buffer) => {
+                    if let Ok((bytes_received, client_address)) = received {
+                        self.handle_request(&buffer[..bytes_received], client_address).await;
+                    }
+                }
+                event = events.recv() => match event {
+                    Some(ServerEvent::Overflow(address)) => self.evict(&address, "coda piena"),
+                    Some(ServerEvent::Kick(address, banned)) => self.kick(&address, banned).await,
+                    Some(ServerEvent::Stop) | None => break,
+                },
+                // Evict the clients that stopped sending heartbeats, e.g. because they crashed or lost the network
+                _ = eviction.tick() => {
+                    let dead_addresses = self.clients.lock().unwrap().iter()
+                        .filter(|(_, client)| client.last_seen.elapsed() > protocol::HEARTBEAT_INTERVAL * protocol::MAX_MISSED_HEARTBEATS)
+                        .map(|(address, _)| address.clone())
+                        .collect::<Vec<String>>();
+                    for address in dead_addresses {
+                        self.evict(&address, "nessun heartbeat");
+                    }
+                }
+            }
+        }
+
+        // Close the TCP connections
+        self.transport.shutdown();
+    }
+
+    // Send a control message to a client, sealed with the session key if the session is protected.
+    async fn reply(&self, message: &ControlMessage, address: SocketAddr) {
+        let _ = self.transport.send_to(&crypto::encode_reply(self.stream_id, message, self.cipher.as_deref()), address).await;
+    }
+
+    // Handle a request received from a client.
+    async fn handle_request(&self, request: &[u8], client_address: SocketAddr) {
+        // A viewer probing the session before connecting is answered in clear, even in a protected session, see the probe module
+        // The salt of the session key is announced in clear as well, the client derives the key with it
+        if crypto::decode_request(request, None).is_ok_and(|message| message.command == "PROBE") {
+            let reply = if self.banned_ips.lock().unwrap().contains(&client_address.ip()) {
+                ControlMessage::new("REFUSED").with_param("reason", "banned")
+            } else {
+                match self.cipher.as_deref() {
+                    Some(cipher) => ControlMessage::new("CASTING").with_param("pin", 1).with_param("salt", crypto::format_salt(cipher.salt())),
+                    None => ControlMessage::new("CASTING").with_param("pin", 0),
+                }
+            };
+            let _ = self.transport.send_to(&protocol::encode_control(self.stream_id, &reply), client_address).await;
+            return;
+        }
+        // If the request can't be opened with the session key the client doesn't know the PIN, refuse it in clear.
+        // A copy of a request already received is dropped silently
+        let message = match crypto::decode_request(request, self.cipher.as_deref()) {
+            Ok(message) => message,
+            Err(Rejection::Replayed) => return,
+            Err(Rejection::Unauthenticated) => {
+                let reply = ControlMessage::new("REFUSED").with_param("reason", "pin");
+                let _ = self.transport.send_to(&protocol::encode_control(self.stream_id, &reply), client_address).await;
+                return;
+            }
+        };
+        // Clients are identified by the address they receive the stream on: the port is reported in the "port" parameter
+        // of START and STOP, since the client may use an ephemeral port and send STOP from another socket
+        let data_port = message.param("port").and_then(|port| port.parse::<u16>().ok()).unwrap_or(client_address.port());
+        // The scope id of a link-local client is kept
+        let mut target = client_address;
+        target.set_port(data_port);
+        let target_address = target.to_string();
+
+        // Any message received from a client proves it is still alive
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&target_address) {
+            client.last_seen = Instant::now();
+        }
+
+        match message.command.as_str() {
+            // If the client IP has been banned by the streamer, refuse the request
+            "START" if self.banned_ips.lock().unwrap().contains(&client_address.ip()) => {
+                self.reply(&ControlMessage::new("REFUSED").with_param("reason", "banned"), target).await;
+            }
+            // A client too old to read the session descriptor can't be served
+            "START" if !session::is_compatible(&message) => {
+                self.reply(&ControlMessage::new("REFUSED").with_param("reason", "version").with_param("version", session::SESSION_VERSION), target).await;
+            }
+            // Add the client to the list of clients and start its task, then send an ACK.
+            // The ACK is sent again to a client already in the list, since the previous one may have been lost
+            "START" => {
+                let existing = self.clients.lock().unwrap().get(&target_address).map(|client| (client.fec_group, client.task.is_none(), client.layer));
+                let (fec_group, multicast, layer) = match existing {
+                    Some(existing) => existing,
+                    None => self.add_client(&message, target),
+                };
+                self.reply(&start_reply(&self.descriptor, fec_group, self.multicast_group.filter(|_| multicast), layer), target).await;
+            }
+            // Send again the requested packets still in the history, unless the deadline has passed
+            "NACK" => self.retransmit(&message, target).await,
+            // Let the rate controller decide whether the encoder target has to change.
+            // A relay has no encoder to adapt, and the layers of a simulcast session have fixed targets
+            "REPORT" => {
+                let connected = self.clients.lock().unwrap().contains_key(&target_address);
+                if let Some(encoder) = self.encoder.as_ref().filter(|_| connected && self.layers.len() == 1) {
+                    let report = ReceiverReport::from_message(&message);
+                    if let Some(encoder_target) = self.rate_controller.lock().unwrap().on_report(&target_address, report, Instant::now()) {
+                        // Restarting the encoder takes a while, do not block the control task
+                        let encoder = encoder.clone();
+                        thread::spawn(move || encoder.restart(encoder_target));
+                    }
+                }
+            }
+            // Move the client to the layer it asked for. The heartbeats repeat the layer, in case the request got lost
+            "LAYER" | "HEARTBEAT" => {
+                if let Some(layer) = message.param("layer").and_then(|layer| layer.parse::<usize>().ok()) {
+                    self.switch_layer(target, layer);
+                }
+            }
+            // Store the viewers served by a relay
+            "RELAY" => {
+                if let Some(client) = self.clients.lock().unwrap().get_mut(&target_address) {
+                    client.downstream = parse_viewers(message.param("viewers").unwrap_or(""));
+                }
+            }
+            // Remove the client from the list of clients and send an ACK, before closing its connection
+            "STOP" => {
+                self.remove_client(&target_address);
+                self.reply(&ControlMessage::new("OK"), client_address).await;
+                self.transport.close(&target_address);
+            }
+            _ => {}
+        }
+    }
+
+    // Add a client to the list of clients and start the task sending it the stream, unless it receives it from the multicast group.
+    // The client receives the layer requested, the worst one if it does not exist, or the first one if it did not ask.
+    // Return the FEC group size negotiated, whether the multicast group is used and the layer received.
+    fn add_client(&self, message: &ControlMessage, target: SocketAddr) -> (u8, bool, usize) {
+        let target_address = target.to_string();
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let pacing_delay_us = Arc::new(AtomicU64::new(0));
+        let layer = message.param("layer").and_then(|layer| layer.parse::<usize>().ok()).unwrap_or(0).min(self.layers.len() - 1);
+        // The multicast group can only be reached with UDP, by IPv4 clients
+        let stream_client = self.transport.is_stream(&target_address);
+        let client_multicast_group = self.multicast_group.filter(|_| !stream_client && target.is_ipv4());
+        let (task, queue, fec_group) = match client_multicast_group {
+            // In multicast mode the fan-out thread sends the stream once to the group, with its own FEC group size
+            Some(_) => (None, None, fec::DEFAULT_FEC_GROUP),
+            None => {
+                // Negotiate the FEC group size requested by the client, parity packets are useless on a TCP connection
+                let fec_group = if stream_client { 0 } else { fec::negotiate_group(message.param("fec")) };
+                let (task, stats) = self.spawn_client_task(target, &self.layers[layer], fec_group, bytes_sent.clone(), pacing_delay_us.clone());
+                (Some(task), Some(stats), fec_group)
+            }
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        clients.insert(target_address, Client{
+            task,
+            queue,
+            fec_group,
+            retransmission: RetransmissionStats::default(),
+            last_seen: Instant::now(),
+            connected_at: Local::now(),
+            bytes_sent,
+            downstream: Vec::new(),
+            pacing_delay_us,
+            layer,
+        });
+        self.viewers.store(clients.len(), Ordering::Relaxed);
+        (fec_group, client_multicast_group.is_some(), layer)
+    }
+
+    // Start the task sending a client the stream of a layer, returning it with the statistics of its queue.
+    fn spawn_client_task(&self, target: SocketAddr, layer: &Arc<Layer>, fec_group: u8, bytes_sent: Arc<AtomicU64>, pacing_delay_us: Arc<AtomicU64>) -> (JoinHandle<()>, Arc<QueueStats>) {
+        // The queue starts with the packets cached since the last keyframe, so that the client can decode the stream at once
+        let mut queue = layer.join_cache.subscribe(self.config.overflow_policy);
+        let stats = queue.stats();
+        // The pacer follows the target of the layer, or the one of the rate controller if the layer has none
+        let layer_target = layer.target;
+        let mut pacer = Pacer::new(self.config.client_limit_kbps, self.total_bucket.clone(), layer_target.unwrap_or_else(|| self.rate_controller.lock().unwrap().target()), Instant::now());
+        let transport = self.transport.clone();
+        let cipher = self.cipher.clone();
+        let rate_controller = self.rate_controller.clone();
+        let events = self.events.clone();
+        let stream_id = layer.stream_id;
+
+        // Start a task to send the data to the client
+        let task = tokio::spawn(async move {
+            let mut encoder = FecEncoder::new(fec_group);
+            let mut batch = Vec::with_capacity(2 * batch_io::MAX_BATCH);
+            let mut ended = false;
+            while !ended {
+                // Wait for the next packet, then take the ones already queued, so that they are sent together
+                let mut next = Some(queue.next().await);
+                let mut packets = 0;
+                while let Some(event) = next {
+                    let data = match event {
+                        QueueEvent::Packet(data) => data,
+                        // The control task disconnects the client
+                        QueueEvent::Overflow => {
+                            let _ = events.send(ServerEvent::Overflow(target.to_string()));
+                            ended = true;
+                            break;
+                        }
+                        QueueEvent::Closed => {
+                            ended = true;
+                            break;
+                        }
+                    };
+                    // The parity is computed over the clear payloads, then both packets are encrypted.
+                    // In an open session the packet shared by all the clients is sent as it is
+                    let parity_packet = parity_packet(&mut encoder, stream_id, &data.packet);
+                    batch.push(match cipher.as_deref() {
+                        Some(cipher) => Datagram::Own(cipher.seal(&data.packet)),
+                        None => Datagram::Shared(data),
+                    });
+                    // Send the parity packet when a FEC group is complete
+                    if let Some(parity_packet) = parity_packet {
+                        batch.push(Datagram::Own(crypto::seal_packet(parity_packet, cipher.as_deref())));
+                    }
+                    packets += 1;
+                    next = if packets < batch_io::MAX_BATCH { queue.try_next() } else { None };
+                }
+                if batch.is_empty() {
+                    continue;
+                }
+                pacer.set_target(layer_target.unwrap_or_else(|| rate_controller.lock().unwrap().target()), Instant::now());
+                let datagrams = batch.iter().map(Datagram::bytes).collect::<Vec<&[u8]>>();
+                let (sent, delay) = pacing::send_paced(transport.as_ref(), target, &datagrams, &mut pacer).await;
+                drop(datagrams);
+                batch.clear();
+                bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+                pacing_delay_us.store(delay.as_micros() as u64, Ordering::Relaxed);
+            }
+        });
+        (task, stats)
+    }
+
+    // Move a connected client to another layer: its task is replaced by one reading the join cache of the new layer,
+    // so that the client receives it from the last keyframe. Nothing is done if the layer does not exist or is already received,
+    // nor for the clients receiving the multicast group, which is only used with a single layer.
+    fn switch_layer(&self, target: SocketAddr, layer: usize) {
+        let target_address = target.to_string();
+        let new_layer = match self.layers.get(layer) {
+            Some(new_layer) => new_layer,
+            None => return,
+        };
+        let (fec_group, bytes_sent, pacing_delay_us) = match self.clients.lock().unwrap().get(&target_address) {
+            Some(client) if client.task.is_some() && client.layer != layer => (client.fec_group, client.bytes_sent.clone(), client.pacing_delay_us.clone()),
+            _ => return,
+        };
+        let (task, stats) = self.spawn_client_task(target, new_layer, fec_group, bytes_sent, pacing_delay_us);
+        match self.clients.lock().unwrap().get_mut(&target_address) {
+            Some(client) => {
+                if let Some(previous) = client.task.replace(task) {
+                    previous.abort();
+                }
+                client.queue = Some(stats);
+                client.layer = layer;
+            }
+            // The client left in the meantime
+            None => task.abort(),
+        }
+    }
+
+    // Answer a "NACK" of a connected client, sending again the requested packets still in the history unless the deadline has passed.
+    async fn retransmit(&self, message: &ControlMessage, target: SocketAddr) {
+        let (packets, bytes_sent) = {
+            let mut clients = self.clients.lock().unwrap();
+            let client = match clients.get_mut(&target.to_string()) {
+                Some(client) => client,
+                None => return,
+            };
+            client.retransmission.nacks += 1;
+            let ranges = nack::parse_ranges(message.param("ranges").unwrap_or(""));
+            let history = self.layers[client.layer].history.lock().unwrap();
+            let mut packets = Vec::new();
+            for sequence in ranges.into_iter().flat_map(|(first, last)| first..=last).take(nack::MAX_NACK_PACKETS) {
+                if nack::is_expired(message.param("deadline"), self.start.elapsed().as_millis() as u32) {
+                    client.retransmission.expired += 1;
+                    continue;
+                }
+                if let Some(packet) = history.get(sequence) {
+                    packets.push(packet.clone());
+                    client.retransmission.retransmitted += 1;
+                }
+            }
+            (packets, client.bytes_sent.clone())
+        };
+
+        for packet in packets {
+            // Retransmissions are not delayed, but count against the upload budget
+            if let Some(total_bucket) = self.total_bucket.as_ref() {
+                total_bucket.lock().unwrap().reserve(packet.len(), Instant::now());
+            }
+            if let Ok(sent) = self.transport.send_to(&crypto::seal_packet(packet, self.cipher.as_deref()), target).await {
+                bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Remove a client from the list of clients, stopping its task. Return false if it was not in the list.
+    fn remove_client(&self, address: &str) -> bool {
+        let client = {
+            let mut clients = self.clients.lock().unwrap();
+            let client = clients.remove(address);
+            self.viewers.store(clients.len(), Ordering::Relaxed);
+            client
+        };
+        match client {
+            Some(client) => {
+                client.disconnect();
+                self.rate_controller.lock().unwrap().forget(address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Disconnect a client the server gave up on, reporting it to the GUI with the reason.
+    fn evict(&self, address: &str, reason: &'static str) {
+        if self.remove_client(address) {
+            self.transport.close(address);
+            self.evicted_clients.lock().unwrap().push((address.to_string(), reason));
+        }
+    }
+
+    // Disconnect a client kicked by the streamer, notifying it with a "KICKED" message.
+    async fn kick(&self, address: &str, banned: bool) {
+        if self.remove_client(address) {
+            if let Ok(target) = address.parse::<SocketAddr>() {
+                let message = ControlMessage::new("KICKED").with_param("banned", banned);
+                // The message is sent a few times, since it may get lost like any other datagram
+                for _ in 0..3 {
+                    self.reply(&message, target).await;
+                }
+            }
+            self.transport.close(address);
+        }
+    }
 }
 
-// StreamingServer struct contains the handle to the ffmpeg process, the list of connected clients, the control variable and the threads.
+// StreamingServer struct contains the encoder, the list of connected clients, the control variable and the threads.
 pub struct StreamingServer {
-    handle: Option<Mutex<FfmpegChild>>,
+    encoder: Option<Arc<Encoder>>,
     list_clients: Arc<Mutex<HashMap<String, Client>>>,
     control: Arc<(Mutex<bool>, Condvar)>,
     threads: Vec<thread::JoinHandle<()>>,
+    evicted_clients: Arc<Mutex<Vec<(String, &'static str)>>>,
+    banned_ips: Arc<Mutex<HashSet<IpAddr>>>,
+    events: Option<UnboundedSender<ServerEvent>>,
+    pin: Option<String>,
+    cipher: Option<Arc<SessionCipher>>,
+    beacon: Option<BeaconSender>,
+    rate_controller: Arc<Mutex<RateController>>,
+    rtsp: Option<Arc<RtspServer>>,
+    http: Option<Arc<HttpServer>>,
+    // Height and target of each layer of a simulcast session, empty with a single layer
+    layers: Vec<(u32, Option<EncoderTarget>)>,
+}
+
+// ClientSnapshot struct contains the information about a connected client shown in the roster of the casting screen.
+#[derive(Debug, Clone)]
+pub struct ClientSnapshot {
+    pub address: String,
+    pub connected_at: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    pub bytes_sent: u64,
+    pub retransmission: RetransmissionStats,
+    pub downstream: Vec<(String, u32)>,
+    pub queue_depth: usize,
+    pub pacing_delay: Duration,
+    pub dropped: u64,
+    // Height of the layer received, in a simulcast session
+    pub layer: Option<u32>,
 }
 
 // CropArea struct contains the width, height, x_offset and y_offset of the crop area.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct CropArea {
     pub width: u32,
     pub height: u32,
@@ -47,31 +672,30 @@ pub struct CropArea {
 impl StreamingServer {
     pub fn new() -> Self {
         StreamingServer {
-            handle: None,
+            encoder: None,
             list_clients: Arc::new(Mutex::new(HashMap::new())),
             control: Arc::new((Mutex::new(false), Condvar::new())), 
             threads: Vec::new(),
+            evicted_clients: Arc::new(Mutex::new(Vec::new())),
+            banned_ips: Arc::new(Mutex::new(HashSet::new())),
+            events: None,
+            pin: None,
+            cipher: None,
+            beacon: None,
+            rate_controller: Arc::new(Mutex::new(RateController::new())),
+            rtsp: None,
+            http: None,
+            layers: Vec::new(),
         }
     }
 
     // Start the screen casting process. Also start a thread to listen for incoming connections and a thread to send the screen casting data to the clients.
     pub fn start(&mut self, screen_index: usize, share_mode: ShareMode) {
 
-        {
-            // Reset the control variable, made up of a mutex and a condition variable
-            let (lock, cvar) = &*self.control;
-            let mut terminate = lock.lock().unwrap();
-            *terminate = false;
-            cvar.notify_all();
-        }
-
-        let command ;
-
-        // Get the FFmpeg command to start the screen casting process based on the screen index and the share mode.
-        if share_mode == ShareMode::CropArea {
+        // Get the crop area to be captured, if the share mode requires it.
+        let crop = if share_mode == ShareMode::CropArea {
             let exe_path = utils::get_project_src_path();
-            let file_path;
-            file_path = exe_path.display().to_string() + r"/config/crop.txt";
+            let file_path = exe_path.display().to_string() + r"/config/crop.txt";
 
             let mut file = File::open(file_path).expect("Impossibile aprire il file");
             let mut content = String::new();
@@ -83,163 +707,374 @@ impl StreamingServer {
                 .map(|res| res.map(|num| num.round() as u32))
                 .collect::<Result<_, _>>().unwrap();
 
-            let crop = CropArea {
+            Some(CropArea {
                 width: fields[2],
                 height: fields[3],
                 x_offset: fields[0],
                 y_offset: fields[1],
-            };
-            command = utils::get_ffmpeg_command(screen_index, Some(crop));
-
+            })
         }
         else {
-            command = utils::get_ffmpeg_command(screen_index, None);
+            None
+        };
+        let resolution = crop.map(|crop| (crop.width, crop.height)).or_else(|| utils::screen_resolution(screen_index));
+
+        self.rate_controller = Arc::new(Mutex::new(RateController::new()));
+        let (reader_tx, reader_rx) = channel::<BufReader<ChildStdout>>();
+        // Encode the configured layers, if the source is tall enough to have at least two of them
+        let layer_targets = utils::read_network_config().simulcast_layers
+            .map(|heights| simulcast::layer_targets(&heights, resolution.map(|(_, height)| height)))
+            .filter(|targets| targets.len() > 1);
+
+        match layer_targets {
+            // Start a single FFmpeg process encoding every layer, the first one is read from its output and the others from the layer sockets
+            Some(targets) => {
+                let layer_sockets = (1..targets.len()).map(|_| simulcast::layer_socket()).collect::<io::Result<Vec<(UdpSocket, String)>>>()
+                    .expect("Failed to bind socket");
+                let outputs = layer_sockets.iter().map(|(_, url)| url.clone()).collect::<Vec<String>>();
+                let (ffmpeg, reader) = Encoder::spawn_layers(screen_index, crop, &targets, &outputs);
+                let encoder = Arc::new(Encoder {
+                    screen_index,
+                    crop,
+                    process: Mutex::new(Some(ffmpeg)),
+                    reader_tx,
+                });
+
+                let mut descriptor = SessionDescriptor::new(utils::host_name(), None, simulcast::LAYER_FRAMERATE);
+                descriptor.layers = targets.iter().filter_map(|target| target.height).collect();
+                descriptor.resolution = resolution.zip(descriptor.layers.first().copied()).map(|(resolution, height)| simulcast::scaled_resolution(resolution, height));
+                let sources = [(StreamSource::Encoder(reader, reader_rx), Some(targets[0]))].into_iter()
+                    .chain(layer_sockets.into_iter().zip(&targets[1..]).map(|((socket, _), target)| (StreamSource::Layer(socket), Some(*target))))
+                    .collect();
+                self.start_session(sources, Some(encoder), descriptor);
+            }
+            // Start the FFmpeg process with the best target, the rate controller lowers it if the viewers can't keep up
+            None => {
+                let target = self.rate_controller.lock().unwrap().target();
+                let (ffmpeg, reader) = Encoder::spawn(screen_index, crop, target);
+                let encoder = Arc::new(Encoder {
+                    screen_index,
+                    crop,
+                    process: Mutex::new(Some(ffmpeg)),
+                    reader_tx,
+                });
+
+                // The encoder keeps the dimensions even, see utils::get_ffmpeg_command
+                let descriptor = SessionDescriptor::new(utils::host_name(), resolution.map(|(width, height)| (width / 2 * 2, height / 2 * 2)), target.framerate);
+                self.start_session(vec![(StreamSource::Encoder(reader, reader_rx), None)], Some(encoder), descriptor);
+            }
         }
+    }
 
-        // Get the local IP address
-        let ip_address: String;
-        match local_ip() {
-            Ok(ip) => ip_address = ip.to_string(),
-            _ => {
-                println!("Impossibile ottenere l'indirizzo IP");
-                panic!()
-            },
+    // Start relaying the stream received from an upstream server, whose chunks are received from the given channels, one per layer.
+    // The stream replaces the encoder and is framed again, then sent to the downstream clients like any other session.
+    // It is described to the downstream clients as the upstream server described it, with the layers relayed.
+    // The relay stops sending when the channels are closed.
+    pub fn start_relay(&mut self, upstreams: Vec<Receiver<Vec<u8>>>, descriptor: SessionDescriptor) {
+        self.rate_controller = Arc::new(Mutex::new(RateController::new()));
+        let sources = upstreams.into_iter().map(|upstream| (StreamSource::Relay(upstream), None)).collect();
+        self.start_session(sources, None, descriptor);
+    }
+
+    // Start the networking core serving the clients and a thread per layer publishing them the stream read from its source,
+    // given with the target of its encoder in simulcast. The encoder, if any, is restarted when the rate controller asks for it.
+    // The features of the descriptor are the ones of this session, whatever the descriptor received, and its layers are kept only with more sources.
+    fn start_session(&mut self, sources: Vec<(StreamSource, Option<EncoderTarget>)>, encoder: Option<Arc<Encoder>>, descriptor: SessionDescriptor) {
+
+        {
+            // Reset the control variable, made up of a mutex and a condition variable
+            let (lock, cvar) = &*self.control;
+            let mut terminate = lock.lock().unwrap();
+            *terminate = false;
+            cvar.notify_all();
+        }
+
+        // Every casting session gets a new stream id, so that clients can drop packets of a previous session
+        let stream_id = protocol::new_stream_id();
+
+        // Bind the socket to the configured address and port (by default all the interfaces, IPv4 and IPv6, and port 8080) to listen for incoming connections.
+        // If IPv6 is not available the server listens on the local IP address.
+        // Clients that can't use UDP connect with TCP to the same port, the server works with UDP only if the TCP port can't be bound.
+        let config = utils::read_network_config();
+        let socket = transport::bind_udp(config.listen_address())
+            .or_else(|_| UdpSocket::bind(SocketAddr::new(config.bind_ip(), config.server_port)))
+            .expect("Failed to bind socket");
+        socket.set_nonblocking(true).expect("Failed to bind socket");
+        let server_address = socket.local_addr().expect("Failed to bind socket");
+        let tcp_listener = transport::bind_tcp(server_address).ok().filter(|listener| listener.set_nonblocking(true).is_ok());
+        let tcp = tcp_listener.is_some();
+
+        // The sockets are registered with the runtime running the networking core, whose tasks are driven by a single thread
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Failed to start the runtime");
+        let transport = {
+            let _runtime_guard = runtime.enter();
+            let socket = tokio::net::UdpSocket::from_std(socket).expect("Failed to bind socket");
+            let tcp_listener = tcp_listener.and_then(|listener| tokio::net::TcpListener::from_std(listener).ok());
+            Arc::new(ServerTransport::start(LossySocket::from_env(socket), tcp_listener))
         };
 
-        // Bind the socket to the local IP address and port 8080 to listen for incoming connections
-        let socket = Arc::new(UdpSocket::bind(format!("{ip_address}:8080")).expect("Failed to bind socket"));
-        let listener_socket = socket.clone();
+        // In multicast mode open the socket sending the stream to the group, falling back to unicast if it can't be opened.
+        // A relay always sends in unicast, since the group is the one its upstream server may be sending to,
+        // and so does a simulcast session, since each client may receive another layer.
+        let simulcast = sources.len() > 1;
+        let multicast = config.multicast_group.filter(|_| encoder.is_some() && !simulcast).and_then(|group| {
+            multicast::sender_socket(config.bind_ip_for(group.ip())).ok().map(|socket| (group, LossySocket::from_env(socket)))
+        });
+        let multicast_group = multicast.as_ref().map(|(group, _)| *group);
 
-        let ffmpeg_command = command.split(" ").collect::<Vec<&str>>();
+        // Announce the session on the LAN, the streaming works anyway if the beacons can't be sent
+        self.beacon = BeaconSender::start(&config, stream_id, descriptor.resolution, self.pin.is_some()).ok();
 
-        // Start the FFmpeg process
-        let mut ffmpeg = FfmpegCommand::new().args(&ffmpeg_command).spawn().expect("Failed to start FFmpeg");
-        let mut reader = BufReader::new(ffmpeg.take_stdout().unwrap());
-        let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        // Serve the stream over RTSP if configured, players can't be asked for the PIN so protected sessions are never served.
+        // Like the server socket, the RTSP and HTTP servers listen dual-stack, or on the local IP address if IPv6 is not available.
+        let listen_ip = config.listen_address().ip();
+        self.rtsp = match (config.rtsp_port, self.pin.as_ref()) {
+            (Some(port), None) => RtspServer::start(SocketAddr::new(listen_ip, port))
+                .or_else(|_| RtspServer::start(SocketAddr::new(config.bind_ip(), port))).ok().map(Arc::new),
+            _ => None,
+        };
+        let rtsp = self.rtsp.clone();
+        self.http = match (config.http_port, self.pin.as_ref()) {
+            (Some(port), None) => HttpServer::start(SocketAddr::new(listen_ip, port))
+                .or_else(|_| HttpServer::start(SocketAddr::new(config.bind_ip(), port))).ok().map(Arc::new),
+            _ => None,
+        };
+        let http = self.http.clone();
+
+        let rate_controller = self.rate_controller.clone();
+        // The upload budget is shared by the tasks sending to the clients and by the retransmissions
+        let total_bucket = config.upload_limit_kbps.map(|limit| Arc::new(Mutex::new(TokenBucket::with_limit(limit, pacing::TOTAL_BURST, Instant::now()))));
+        let multicast_pacer = Pacer::new(config.client_limit_kbps, total_bucket.clone(), rate_controller.lock().unwrap().target(), Instant::now());
 
-        let handle = Mutex::new(ffmpeg);
+        // The history of each layer keeps its sent data packets, it is shared between its fan-out thread and the control task, which answers the NACKs.
+        // Both measure the stream time from the same instant.
+        // Every packet is published once on the fan-out of its layer through the join cache, the task of each client reads it from its own queue.
+        // The packets of each layer carry their own stream id, the control messages the one of the first layer
+        let layers = sources.iter().enumerate().map(|(index, (_, target))| Arc::new(Layer {
+            stream_id: stream_id.wrapping_add(index as u32),
+            target: *target,
+            history: Mutex::new(RetransmissionHistory::new(nack::HISTORY_SIZE)),
+            join_cache: JoinCache::new(client_queue::fanout(config.queue_size), config.join_cache_size),
+        })).collect::<Vec<Arc<Layer>>>();
+        let start = Instant::now();
+        // Derive the session key from the PIN, if the session is protected
+        let cipher = self.pin.as_deref().map(|pin| Arc::new(SessionCipher::new(pin, &crypto::new_salt())));
+        self.cipher = cipher.clone();
 
-        let control = Arc::clone(&self.control);
-        let control_clone = Arc::clone(&self.control);
+        let mtu = config.mtu;
+        let (events, events_rx) = unbounded_channel();
+        self.events = Some(events.clone());
+        let viewers = Arc::new(AtomicUsize::new(0));
+        let viewers_clone = Arc::clone(&viewers);
 
-        // Clone the list of clients to be used in the listener thread
-        let list_tx_clients_clone = Arc::clone(&self.list_clients);
+        // A relay has no encoder to adapt and the layers of a simulcast session have fixed targets, so the reports of their clients are useless
+        let features = [("fec", true), ("nack", true), ("relay", true), ("report", encoder.is_some() && !simulcast), ("tcp", tcp),
+            ("multicast", multicast_group.is_some()), ("pin", cipher.is_some()), ("simulcast", simulcast)];
+        let features = features.iter().filter(|(_, supported)| *supported).map(|(feature, _)| *feature).collect::<Vec<&str>>();
+        let layer_heights = if simulcast { descriptor.layers } else { Vec::new() };
+        let descriptor = SessionDescriptor { version: session::SESSION_VERSION, layers: layer_heights, ..descriptor }.with_features(&features);
+        self.layers = descriptor.layers.iter().copied().zip(layers.iter().map(|layer| layer.target)).collect();
 
-        listener_socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let session = Session {
+            stream_id,
+            config,
+            transport,
+            cipher,
+            clients: Arc::clone(&self.list_clients),
+            viewers,
+            evicted_clients: Arc::clone(&self.evicted_clients),
+            banned_ips: Arc::clone(&self.banned_ips),
+            rate_controller: rate_controller.clone(),
+            layers: layers.clone(),
+            start,
+            encoder: encoder.clone(),
+            total_bucket,
+            multicast_group,
+            descriptor,
+            events,
+        };
 
-        // Start a thread to listen for incoming connections
+        // Start the thread driving the runtime, until the control task is stopped. Dropping the runtime ends the tasks of the clients and closes the sockets
         let h = thread::spawn(move || {
-            let mut buffer = [0; BUFFER_SIZE];
-            let (lock, cvar) = &*control;
+            runtime.block_on(session.run(events_rx));
+        });
 
-            loop{
+        self.threads.push(h);
 
-                if *lock.lock().unwrap() {
-                    break;
-                }
+        // Start a thread per layer to read the screen casting data and publish it to the clients.
+        // The players served over RTSP and HLS and the multicast group, only used with a single layer, receive the first layer
+        let mut multicast = multicast;
+        let mut multicast_pacer = Some(multicast_pacer);
+        for (index, ((mut source, _), layer)) in sources.into_iter().zip(layers).enumerate() {
+            let control_clone = Arc::clone(&self.control);
+            let rtsp = rtsp.clone().filter(|_| index == 0);
+            let http = http.clone().filter(|_| index == 0);
+            let multicast = multicast.take();
+            let multicast_pacer = multicast_pacer.take();
+            let multicast_cipher = self.cipher.clone();
+            let rate_controller = rate_controller.clone();
+            let viewers_clone = Arc::clone(&viewers_clone);
+            let mut buffer = vec![0; BUFFER_SIZE];
+            let mut chunker = TsChunker::new(ts_chunker::chunk_size(mtu));
+            let h = thread::spawn(move || {
+                let (lock, cvar) = &*control_clone;
+                let stream_id = layer.stream_id;
+                let mut sequence: u32 = 0;
+                let mut multicast_encoder = FecEncoder::new(fec::DEFAULT_FEC_GROUP);
+                let mut multicast_pacer = multicast_pacer;
 
-                // Receive the data from the client
-                let (bytes_received, client_address) = match listener_socket.recv_from(&mut buffer) {
-                    Ok(res) => res,
-                    Err(_) => {
-                        continue
+                loop {
+                    // Check the condition variable to stop the thread
+                    if *lock.lock().unwrap() {
+                        break;
                     }
-                };
 
-                let message = String::from_utf8_lossy(&buffer[..bytes_received]);
-                let target_address = format!("{}:{}", client_address.ip(), client_address.port());
-
-                let mut list_guard = list_tx_clients_clone.lock().unwrap();
-                // If the message is "START" and the client is not in the list of clients, add the client to the list and start a thread to send the data to the client
-                if message.trim() == "START"{
-                    if !list_guard.contains_key(&target_address.clone()){
-
-                        let send_socket = listener_socket.clone();
-                        let (tx, rx) = channel::<Vec<u8>>();
-
-                        list_guard.insert(target_address.clone(), Client{ tx, });
-
-                        // Send an ACK to the client
-                        listener_socket.send_to(b"OK", &target_address).unwrap();
-                        
-                        // Start a thread to send the data to the client
-                        thread::spawn(move || {
-                            loop {
-                                // When the client is closed, drop the client from the list of clients
-                                match rx.recv(){
-                                    Ok(data) => {send_socket.send_to(&data, &target_address).unwrap();},
-                                    Err(_) => {
-                                        break;
+                    // Break the loop when the encoder has terminated without being restarted, or the upstream server of a relay is gone
+                    let bytes = match source.next_chunk(&mut buffer, lock) {
+                        Some(bytes) => bytes,
+                        None => break,
+                    };
+                    if let Some(rtsp) = rtsp.as_ref() {
+                        rtsp.push(&bytes);
+                    }
+                    if let Some(http) = http.as_ref() {
+                        http.push(&bytes);
+                    }
+
+                    // Cut the bytes read in chunks of whole TS packets. The last TS packets read are sent in a shorter chunk instead of waiting for the next read
+                    let chunks = chunker.push(&bytes);
+                    for chunk in chunks.into_iter().chain(chunker.flush()) {
+                        // Frame the chunk with the header, the timestamp is the number of milliseconds since the stream started
+                        let header = PacketHeader::new(PacketType::Data, stream_id, sequence, start.elapsed().as_millis() as u32);
+                        let packet = header.encode(&chunk);
+                        layer.history.lock().unwrap().push(sequence, packet.clone());
+                        sequence = sequence.wrapping_add(1);
+
+                        match (multicast.as_ref(), multicast_pacer.as_mut()) {
+                            // Send the data once to the group, as long as someone is watching
+                            (Some((group, socket)), Some(multicast_pacer)) => {
+                                let parity_packet = parity_packet(&mut multicast_encoder, stream_id, &packet);
+                                if viewers_clone.load(Ordering::Relaxed) > 0 {
+                                    multicast_pacer.set_target(rate_controller.lock().unwrap().target(), Instant::now());
+                                    let packet = crypto::seal_packet(packet, multicast_cipher.as_deref());
+                                    wait_pacing(multicast_pacer, packet.len());
+                                    let _ = socket.send_to(&packet, group);
+                                    if let Some(parity_packet) = parity_packet {
+                                        let parity_packet = crypto::seal_packet(parity_packet, multicast_cipher.as_deref());
+                                        wait_pacing(multicast_pacer, parity_packet.len());
+                                        let _ = socket.send_to(&parity_packet, group);
                                     }
                                 }
                             }
-                        });
-                    }else{
-                        // Send an ACK to the client if the client is already in the list of clients
-                        listener_socket.send_to(b"OK", &target_address).unwrap();
+                            // Publish the data to all the clients of the layer, their tasks pace it. Sending fails only when nobody is watching
+                            _ => {
+                                let keyframe = rtp::contains_keyframe(&chunk);
+                                let pat = rtp::contains_pat(&chunk);
+                                let _ = layer.join_cache.publish(Arc::new(QueuedPacket { packet, keyframe, pat }));
+                            }
+                        }
                     }
                 }
-                // If the message is "STOP" and the client is in the list of clients remove the client from the list of clients
-                if message.trim().starts_with("STOP"){
-                    let message = message.split("\n").collect::<Vec<&str>>();
-                    let ip = message[1];
-                    list_guard.remove(ip);
-                    // Send an ACK to the client
-                    listener_socket.send_to(b"OK", &client_address).unwrap();
-                }
-                drop(list_guard);
-            }
-            // Notify all the threads that the listener thread is terminated
-            cvar.notify_all(); 
-        });
 
-        self.threads.push(h);
+                // Notify all the threads that the fan-out thread is terminated
+                cvar.notify_all();
+            });
 
-        // Start a thread to send the screen casting data to the clients
-        let list_tx_clients_clone2 = Arc::clone(&self.list_clients);
-        let h = thread::spawn(move || {
-            let (lock, cvar) = &*control_clone;
+            self.threads.push(h);
+        }
 
-            loop {
-                // Check the condition variable to stop the thread
-                if *lock.lock().unwrap() {
-                    break;
-                }
+        self.encoder = encoder;
 
-                let n = reader.read(&mut buffer).unwrap();
-                // If the data is empty, break the loop
-                if n == 0 {
-                    break;
-                }
-                let clients = list_tx_clients_clone2.lock().unwrap();
-                // Send the data to all the clients
-                for client in clients.values() {
-                    client.tx.send(buffer[..n].to_vec()).unwrap();
-                }
-            }
+    }
 
-            // Notify all the threads that the sender thread is terminated
-            cvar.notify_all(); 
-        });
+    // Return a snapshot of the connected clients sorted by connection time, including their retransmission counters to spot the viewers on bad links.
+    pub fn clients_snapshot(&self) -> Vec<ClientSnapshot> {
+        let clients = self.list_clients.lock().unwrap();
+        let now = Local::now();
+        let mut snapshot = clients.iter().map(|(address, client)| ClientSnapshot {
+            address: address.clone(),
+            connected_at: client.connected_at,
+            last_seen: now - chrono::Duration::from_std(client.last_seen.elapsed()).unwrap_or_else(|_| chrono::Duration::zero()),
+            bytes_sent: client.bytes_sent.load(Ordering::Relaxed),
+            retransmission: client.retransmission,
+            downstream: client.downstream.clone(),
+            queue_depth: client.queue.as_ref().map_or(0, |queue| queue.len()),
+            pacing_delay: Duration::from_micros(client.pacing_delay_us.load(Ordering::Relaxed)),
+            dropped: client.queue.as_ref().map_or(0, |queue| queue.dropped()),
+            layer: self.layers.get(client.layer).map(|(height, _)| *height),
+        }).collect::<Vec<ClientSnapshot>>();
+        snapshot.sort_by_key(|client| client.connected_at);
+        snapshot
+    }
 
-        self.threads.push(h);
+    // Return the "RELAY" message listing the viewers served by this server, with their depth below it, sent upstream when relaying
+    // so that the host can show the whole tree in its roster. Viewers are listed in preorder, here the second one watches through the first:
+    //
+    // RELAY
+    // viewers=192.168.1.20:50100/1,192.168.1.21:50200/2
+    pub fn relay_message(&self) -> ControlMessage {
+        let clients = self.list_clients.lock().unwrap();
+        let mut viewers = Vec::new();
+        for (address, client) in clients.iter() {
+            viewers.push((address.clone(), 1));
+            viewers.extend(client.downstream.iter().map(|(viewer, depth)| (viewer.clone(), depth + 1)));
+        }
+        ControlMessage::new("RELAY").with_param("viewers", format_viewers(&viewers))
+    }
 
-        self.handle = Some(handle);
+    // Return the addresses of the clients evicted during the session, because they stopped sending heartbeats or their queue overflowed, with the reason.
+    pub fn evicted_clients(&self) -> Vec<(String, &'static str)> {
+        self.evicted_clients.lock().unwrap().clone()
+    }
 
+    // Return the current target of the encoder and its most recent changes with their reason, the newest first.
+    pub fn rate_status(&self) -> (EncoderTarget, Vec<RateChange>) {
+        let rate_controller = self.rate_controller.lock().unwrap();
+        (rate_controller.target(), rate_controller.changes())
     }
 
-    // Stop the screen casting process. Notify all the connected clients and terminate the threads.
-    pub fn stop (&mut self) {
-        if let Some(ref process) = self.handle {
-            let mut guard = process.lock().unwrap();
+    // Return the height and the target of each layer of a simulcast session, from the best to the worst, empty with a single layer.
+    pub fn simulcast_layers(&self) -> Vec<(u32, Option<EncoderTarget>)> {
+        self.layers.clone()
+    }
 
-            // Send "q" to the stdin of the FFmpeg process to stop the process
-            if let Some(mut stdin) = (*guard).take_stdin() {
-                writeln!(stdin, "q").unwrap();
+    // Return the URL the stream is served on over RTSP, if any.
+    pub fn rtsp_url(&self) -> Option<String> {
+        self.rtsp.as_ref().map(|rtsp| rtsp.url())
+    }
+
+    // Return the URL of the browser viewer, if any.
+    pub fn http_url(&self) -> Option<String> {
+        self.http.as_ref().map(|http| http.url())
+    }
+
+    // Set the PIN viewers must know to connect, None to leave the session open to everyone. Used before starting the server.
+    // The PIN is also used to derive the key encrypting the stream.
+    pub fn set_pin(&mut self, pin: Option<String>) {
+        self.pin = pin;
+    }
+
+    // Disconnect a client, notifying it with a "KICKED" message. If ban is true, its IP is refused for the rest of the session.
+    pub fn kick_client(&self, address: &str, ban: bool) {
+        if ban {
+            if let Ok(socket_address) = address.parse::<SocketAddr>() {
+                self.banned_ips.lock().unwrap().insert(socket_address.ip());
             }
+        }
 
-            guard.wait().expect("Failed to stop FFmpeg process");
+        if let Some(events) = self.events.as_ref() {
+            let _ = events.send(ServerEvent::Kick(address.to_string(), ban));
+        }
+    }
 
+    // Stop the screen casting process. Notify all the connected clients and terminate the threads.
+    pub fn stop (&mut self) {
+        // Stop the FFmpeg process, a relay has none
+        if let Some(encoder) = self.encoder.take() {
+            encoder.stop();
+        }
+
+        if !self.threads.is_empty() {
             {
                 // Set the condition variable to true to stop the threads
                 let (lock, cvar) = &*self.control;
@@ -248,10 +1083,34 @@ impl StreamingServer {
                 cvar.notify_all();
             }
 
+            // Stop the control task, which closes the TCP connections
+            if let Some(events) = self.events.take() {
+                let _ = events.send(ServerEvent::Stop);
+            }
+
             // Wait for the threads to terminate
             for h in self.threads.drain(..) {
                 h.join().unwrap();
             }
+
+            // Stop serving the RTSP players and the browsers
+            if let Some(rtsp) = self.rtsp.take() {
+                rtsp.stop();
+            }
+            if let Some(http) = self.http.take() {
+                http.stop();
+            }
+
+            // Stop announcing the session
+            if let Some(beacon) = self.beacon.take() {
+                beacon.stop();
+            }
+
+            // Forget the clients still connected, their tasks have ended with the runtime
+            let clients = self.list_clients.lock().unwrap().drain().collect::<Vec<(String, Client)>>();
+            for (_, client) in clients {
+                client.disconnect();
+            }
         }
     }
 }
\ No newline at end of file
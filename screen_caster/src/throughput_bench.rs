@@ -0,0 +1,171 @@
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use crate::batch_io::{self, RecvBatch};
+use crate::fanout_bench;
+use crate::protocol::{self, PacketHeader, PacketType};
+use crate::rtp::{TS_PACKET_SIZE, TS_SYNC_BYTE};
+use crate::ts_chunker::{self, TsChunker};
+
+/// This module contains a benchmark of the packetization of the stream over loopback, run with "screen_caster --bench-throughput [seconds] [MB/s]".
+/// A synthetic MPEG-TS stream is sent from a socket to another one at a fixed rate (BENCH_RATE by default), for BENCH_SECONDS each way:
+/// - in chunks of 1024 bytes, which cut the TS packets, sending and receiving one datagram per system call
+/// - in chunks of whole TS packets sized for the default MTU, one datagram per system call
+/// - in the same chunks, sending and receiving a batch of datagrams per system call (on Linux only, see the batch_io module)
+///
+/// For each way the throughput received, the CPU time spent per MB received, the datagrams lost on the way
+/// and the TS packets cut between two datagrams are printed. The rate should be one the receiver can keep up with,
+/// otherwise the datagrams dropped by the kernel make the CPU time per MB meaningless.
+
+pub const BENCH_FLAG: &str = "--bench-throughput";
+const BENCH_SECONDS: u64 = 3;
+// MB of stream sent per second
+const BENCH_RATE: u64 = 50;
+// The sender sends the datagrams of each tick together, then waits for the next one
+const TICK: Duration = Duration::from_millis(1);
+// Chunk size used before the stream was cut on the TS packets
+const UNALIGNED_CHUNK_SIZE: usize = 1024;
+// Length of the synthetic stream the chunks are cut from, sent again and again
+const STREAM_PACKETS: usize = 7 * 1000;
+
+// Result of sending the stream one way.
+struct Throughput {
+    sent: u64,
+    received: u64,
+    bytes: u64,
+    elapsed: Duration,
+    cpu: f64,
+}
+
+/// Run the benchmark with the command line arguments following BENCH_FLAG.
+pub fn run(args: &[String]) {
+    let seconds = args.first().and_then(|arg| arg.parse().ok()).filter(|seconds| *seconds > 0).unwrap_or(BENCH_SECONDS);
+    let duration = Duration::from_secs(seconds);
+    let rate = args.get(1).and_then(|arg| arg.parse().ok()).filter(|rate| *rate > 0).unwrap_or(BENCH_RATE) * 1_000_000;
+    let stream = synthetic_stream();
+
+    let unaligned = stream.chunks(UNALIGNED_CHUNK_SIZE).map(<[u8]>::to_vec).collect::<Vec<Vec<u8>>>();
+    let mut chunker = TsChunker::new(ts_chunker::chunk_size(ts_chunker::DEFAULT_MTU));
+    let aligned = chunker.push(&stream).into_iter().chain(chunker.flush()).collect::<Vec<Vec<u8>>>();
+
+    report(&format!("Chunk da {UNALIGNED_CHUNK_SIZE} byte, un datagramma per chiamata"), &unaligned, blast(&packets(&unaligned), duration, rate, false));
+    let aligned_packets = packets(&aligned);
+    report(&format!("Chunk da {} byte allineati, un datagramma per chiamata", aligned[0].len()), &aligned, blast(&aligned_packets, duration, rate, false));
+    #[cfg(target_os = "linux")]
+    report(&format!("Chunk da {} byte allineati, {} datagrammi per chiamata", aligned[0].len(), batch_io::MAX_BATCH), &aligned, blast(&aligned_packets, duration, rate, true));
+}
+
+// Build a stream of TS packets, each with the sync byte and a continuity counter.
+fn synthetic_stream() -> Vec<u8> {
+    let mut stream = Vec::with_capacity(STREAM_PACKETS * TS_PACKET_SIZE);
+    for n in 0..STREAM_PACKETS {
+        let mut packet = [0xA5; TS_PACKET_SIZE];
+        packet[..4].copy_from_slice(&[TS_SYNC_BYTE, 0x01, 0x00, 0x10 | (n % 16) as u8]);
+        stream.extend_from_slice(&packet);
+    }
+    stream
+}
+
+// Frame the chunks with the header of the protocol, as the StreamingServer does.
+fn packets(chunks: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    chunks.iter().enumerate()
+        .map(|(sequence, chunk)| PacketHeader::new(PacketType::Data, 0, sequence as u32, 0).encode(chunk))
+        .collect()
+}
+
+// Send the packets over loopback for the given time at the given rate, in bytes per second, returning what has been received.
+fn blast(packets: &[Vec<u8>], duration: Duration, rate: u64, batched: bool) -> Throughput {
+    let receiver = UdpSocket::bind("127.0.0.1:0").expect("Impossibile aprire il socket del benchmark");
+    let _ = receiver.set_read_timeout(Some(Duration::from_millis(100)));
+    let address = receiver.local_addr().expect("Impossibile aprire il socket del benchmark");
+    let sender = UdpSocket::bind("127.0.0.1:0").expect("Impossibile aprire il socket del benchmark");
+    let sending = Arc::new(AtomicBool::new(true));
+    let sending_clone = Arc::clone(&sending);
+
+    let receiving = thread::spawn(move || receive(&receiver, &sending_clone, batched));
+
+    let (cpu_before, _) = fanout_bench::process_usage();
+    let start = Instant::now();
+    let mut sent = 0u64;
+    let mut bytes_due = 0u64;
+    let mut next = 0;
+    let datagrams = packets.iter().map(Vec::as_slice).collect::<Vec<&[u8]>>();
+    while start.elapsed() < duration {
+        // Take the datagrams due by the end of this tick, going back to the start of the stream when it ends
+        bytes_due += rate * TICK.as_micros() as u64 / 1_000_000;
+        let mut tick = Vec::new();
+        while bytes_due >= datagrams[next].len() as u64 {
+            bytes_due -= datagrams[next].len() as u64;
+            tick.push(datagrams[next]);
+            next = (next + 1) % datagrams.len();
+        }
+        #[cfg(target_os = "linux")]
+        if batched {
+            for batch in tick.chunks(batch_io::MAX_BATCH) {
+                sent += batch_io::send_batch(&sender, batch, address).unwrap_or(0) as u64;
+            }
+        }
+        if !batched {
+            for datagram in &tick {
+                if sender.send_to(datagram, address).is_ok() {
+                    sent += 1;
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        let tick_end = TICK * (elapsed.as_micros() / TICK.as_micros() + 1) as u32;
+        thread::sleep(tick_end - elapsed);
+    }
+    let elapsed = start.elapsed();
+    sending.store(false, Ordering::Relaxed);
+    let (received, bytes) = receiving.join().unwrap_or((0, 0));
+    let cpu = fanout_bench::process_usage().0 - cpu_before;
+    Throughput { sent, received, bytes, elapsed, cpu }
+}
+
+// Receive until the sender has finished and the socket is drained, returning the datagrams and the bytes received.
+fn receive(socket: &UdpSocket, sending: &AtomicBool, batched: bool) -> (u64, u64) {
+    let (mut received, mut bytes) = (0u64, 0u64);
+    #[cfg(target_os = "linux")]
+    if batched {
+        let mut batch = RecvBatch::new(batch_io::MAX_BATCH);
+        loop {
+            match batch.recv_from_socket(socket) {
+                Ok(_) => batch.datagrams().for_each(|datagram| {
+                    received += 1;
+                    bytes += datagram.len() as u64;
+                }),
+                Err(_) if !sending.load(Ordering::Relaxed) => return (received, bytes),
+                Err(_) => {}
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = batched;
+    let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv(&mut buffer) {
+            Ok(n) => {
+                received += 1;
+                bytes += n as u64;
+            }
+            Err(_) if !sending.load(Ordering::Relaxed) => return (received, bytes),
+            Err(_) => {}
+        }
+    }
+}
+
+// Print the result of a way, with the share of TS packets cut between two datagrams by its chunks.
+fn report(title: &str, chunks: &[Vec<u8>], throughput: Throughput) {
+    let ts_packets = chunks.iter().map(|chunk| chunk.len()).sum::<usize>() / TS_PACKET_SIZE;
+    let cut = chunks.iter().filter(|chunk| chunk.len() % TS_PACKET_SIZE != 0).count();
+    let seconds = throughput.elapsed.as_secs_f64();
+    println!("{title}");
+    println!("  Ricevuti: {:.1} MB/s, {:.0} datagrammi/s", throughput.bytes as f64 / seconds / 1_000_000.0, throughput.received as f64 / seconds);
+    println!("  Tempo CPU: {:.1} ms per MB ricevuto", throughput.cpu * 1000.0 / (throughput.bytes as f64 / 1_000_000.0).max(f64::MIN_POSITIVE));
+    println!("  Persi: {:.2}% dei datagrammi inviati", throughput.sent.saturating_sub(throughput.received) as f64 * 100.0 / throughput.sent.max(1) as f64);
+    println!("  Pacchetti TS divisi tra due datagrammi: {:.1}%", cut as f64 * 100.0 / ts_packets.max(1) as f64);
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use socket2::{Domain, Socket, Type};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::AbortHandle;
+use crate::lossy_socket::LossySocket;
+use crate::batch_io::RecvBatch;
+use crate::protocol;
+
+/// This module contains the transports carrying the datagrams of the protocol between the StreamingServer and the StreamingClient.
+/// - Udp: every datagram is sent as it is in a UDP datagram, the default
+/// - Tcp: datagrams are sent over a TCP connection to the same port of the server, each one preceded by its length
+///   (2 bytes, big endian). Used when UDP is dropped or blocked by the network, at the cost of a higher latency.
+///
+/// The client picks a transport, by default trying UDP first and falling back to TCP when the "START" handshake times out.
+/// Clients connected with TCP use neither FEC nor multicast.
+/// The server accepts both at the same time through a ServerTransport, which replies to each client on the transport it used.
+/// The server listens dual-stack when bound to the unspecified IPv6 address, see bind_udp and bind_tcp: IPv4 clients are seen by the
+/// ServerTransport with their IPv4 address, not the IPv4-mapped IPv6 address the socket reports.
+
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Frames queued for a TCP connection of the server before the client is considered unable to keep up
+const TCP_WRITE_QUEUE: usize = 256;
+// Connections waiting to be accepted by the TCP listener of the server
+const TCP_BACKLOG: i32 = 128;
+
+/// Bind the UDP socket of the server. A socket bound to the unspecified IPv6 address is dual-stack, so that IPv4 clients reach it too.
+pub fn bind_udp(address: SocketAddr) -> io::Result<UdpSocket> {
+    Ok(bind_socket(address, Type::DGRAM)?.into())
+}
+
+/// Bind the TCP listener of the server, dual-stack like the UDP socket.
+pub fn bind_tcp(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = bind_socket(address, Type::STREAM)?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(socket.into())
+}
+
+fn bind_socket(address: SocketAddr, socket_type: Type) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), socket_type, None)?;
+    // The default depends on the system, dual-stack on Linux but not on Windows
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    // Like the listeners of the standard library, so that the port can be bound again right after a session
+    #[cfg(not(target_os = "windows"))]
+    if socket_type == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
+
+// Return the address a client is known by: on a dual-stack socket IPv4 clients are seen with an IPv4-mapped IPv6 address.
+fn canonical(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => address,
+        },
+        SocketAddr::V4(_) => address,
+    }
+}
+
+/// TransportKind enum used to pick the transport used by a client.
+/// - Auto: UDP, falling back to TCP if the server does not answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Auto,
+    Udp,
+    Tcp,
+}
+
+impl TransportKind {
+    pub const ALL: [TransportKind; 3] = [TransportKind::Auto, TransportKind::Udp, TransportKind::Tcp];
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportKind::Auto => write!(f, "Automatico"),
+            TransportKind::Udp => write!(f, "UDP"),
+            TransportKind::Tcp => write!(f, "TCP"),
+        }
+    }
+}
+
+/// Build the frame carrying a datagram on a TCP stream: the datagram preceded by its length.
+fn encode_frame(datagram: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(2 + datagram.len());
+    frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    frame.extend_from_slice(datagram);
+    frame
+}
+
+/// Write a datagram on a TCP stream, preceded by its length.
+fn write_frame(stream: &mut TcpStream, datagram: &[u8]) -> io::Result<()> {
+    stream.write_all(&encode_frame(datagram))
+}
+
+/// The FrameReader splits the bytes read from a TCP stream into datagrams.
+/// Bytes are kept across reads, so that a read timeout in the middle of a frame does not break the framing.
+struct FrameReader {
+    pending: Vec<u8>,
+}
+
+impl FrameReader {
+    fn new() -> Self {
+        FrameReader {
+            pending: Vec::new(),
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.pending.len() < 2 {
+            return None;
+        }
+        let length = u16::from_be_bytes([self.pending[0], self.pending[1]]) as usize;
+        if self.pending.len() < 2 + length {
+            return None;
+        }
+        let frame = self.pending[2..2 + length].to_vec();
+        self.pending.drain(..2 + length);
+        Some(frame)
+    }
+
+    /// Read the next datagram, waiting at most for the read timeout of the stream. A closed stream is reported as an error.
+    fn read(&mut self, stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+        let mut buffer = [0; protocol::MAX_DATAGRAM_SIZE];
+        loop {
+            if let Some(frame) = self.next_frame() {
+                return Ok(frame);
+            }
+            let n = stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"));
+            }
+            self.pending.extend_from_slice(&buffer[..n]);
+        }
+    }
+}
+
+/// The Transport trait is implemented by the transports used by the client to talk with the server.
+pub trait Transport: Send + Sync {
+    fn kind(&self) -> TransportKind;
+    /// Send a datagram to the server.
+    fn send(&self, datagram: &[u8]) -> io::Result<()>;
+    /// Receive a datagram, waiting at most for the read timeout.
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    /// Receive the datagrams waiting, at least one, waiting at most for the read timeout. Return how many have been received.
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        batch.recv_one(|buffer| self.recv(buffer))
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Return the local port, reported to the server in the "port" parameter.
+    fn local_port(&self) -> u16;
+    /// Release the transport, once the server has been told we are leaving.
+    fn close(&self) {}
+}
+
+/// UdpTransport struct contains a UDP socket and the address of the server.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    server: String,
+}
+
+impl UdpTransport {
+    pub fn new(socket: UdpSocket, server: String) -> Self {
+        UdpTransport { socket, server }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Udp
+    }
+
+    fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        self.socket.send_to(datagram, &self.server).map(|_| ())
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buffer)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        batch.recv_from_socket(&self.socket)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn local_port(&self) -> u16 {
+        self.socket.local_addr().map(|address| address.port()).unwrap_or(0)
+    }
+}
+
+/// TcpTransport struct contains a TCP connection to the server, with a stream for each direction.
+pub struct TcpTransport {
+    reader: Mutex<(TcpStream, FrameReader)>,
+    writer: Mutex<TcpStream>,
+    local_port: u16,
+}
+
+impl TcpTransport {
+    pub fn connect(server: &str) -> io::Result<Self> {
+        let address = server.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid server address"))?;
+        let stream = TcpStream::connect_timeout(&address, TCP_CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        let local_port = stream.local_addr()?.port();
+        Ok(TcpTransport {
+            reader: Mutex::new((stream.try_clone()?, FrameReader::new())),
+            writer: Mutex::new(stream),
+            local_port,
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Tcp
+    }
+
+    fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.writer.lock().unwrap(), datagram)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        let (stream, frames) = &mut *reader;
+        let frame = frames.read(stream)?;
+        let n = frame.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&frame[..n]);
+        Ok(n)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.writer.lock().unwrap().set_read_timeout(timeout)
+    }
+
+    fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    fn close(&self) {
+        let _ = self.writer.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+/// The ServerTransport receives the datagrams of the clients from the UDP socket of the server and from the TCP connections
+/// accepted on the same port, and sends each datagram to a client on the transport the client is connected with.
+/// It runs on the runtime of the StreamingServer: every TCP connection has a task reading its frames, forwarded to the task
+/// receiving the datagrams, and a task writing the frames queued for it.
+pub struct ServerTransport {
+    udp: LossySocket<tokio::net::UdpSocket>,
+    connections: Arc<Mutex<HashMap<SocketAddr, Connection>>>,
+    incoming: AsyncMutex<UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+    // Whether the UDP socket is an IPv6 one, reaching IPv4 clients through their IPv4-mapped address
+    ipv6: bool,
+}
+
+// Connection struct contains the frames queued for a TCP connection and the task reading from it.
+struct Connection {
+    frames: mpsc::Sender<Vec<u8>>,
+    reader: AbortHandle,
+}
+
+impl Connection {
+    fn close(self) {
+        self.reader.abort();
+    }
+}
+
+impl ServerTransport {
+    /// Start accepting TCP connections from the listener, if any. Must be called from the runtime of the server.
+    pub fn start(udp: LossySocket<tokio::net::UdpSocket>, tcp: Option<tokio::net::TcpListener>) -> Self {
+        let (tx, incoming) = mpsc::unbounded_channel();
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+
+        if let Some(listener) = tcp {
+            let connections_clone = connections.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, address)) = listener.accept().await {
+                    ServerTransport::serve(stream, canonical(address), &connections_clone, tx.clone());
+                }
+            });
+        }
+
+        let ipv6 = udp.local_addr().is_ok_and(|address| address.is_ipv6());
+        ServerTransport {
+            udp,
+            connections,
+            incoming: AsyncMutex::new(incoming),
+            ipv6,
+        }
+    }
+
+    // Return the address to send the UDP datagrams of a client to.
+    fn udp_address(&self, address: SocketAddr) -> SocketAddr {
+        match address {
+            SocketAddr::V4(v4) if self.ipv6 => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            _ => address,
+        }
+    }
+
+    // Register an accepted connection and start the tasks reading and writing its frames, until the connection is closed.
+    fn serve(stream: tokio::net::TcpStream, address: SocketAddr, connections: &Arc<Mutex<HashMap<SocketAddr, Connection>>>, tx: UnboundedSender<(Vec<u8>, SocketAddr)>) {
+        let _ = stream.set_nodelay(true);
+        let (mut reader, mut writer) = stream.into_split();
+        let (frames, mut frames_rx) = mpsc::channel::<Vec<u8>>(TCP_WRITE_QUEUE);
+
+        tokio::spawn(async move {
+            while let Some(frame) = frames_rx.recv().await {
+                if writer.write_all(&encode_frame(&frame)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let connections_clone = connections.clone();
+        let reader = tokio::spawn(async move {
+            while let Ok(length) = reader.read_u16().await {
+                let mut frame = vec![0; length as usize];
+                if reader.read_exact(&mut frame).await.is_err() || tx.send((frame, address)).is_err() {
+                    break;
+                }
+            }
+            connections_clone.lock().unwrap().remove(&address);
+        });
+        connections.lock().unwrap().insert(address, Connection { frames, reader: reader.abort_handle() });
+    }
+
+    /// Receive the next datagram of a client. Cancelling the wait loses no datagram.
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut incoming = self.incoming.lock().await;
+        tokio::select! {
+            received = self.udp.recv_from(buffer) => received.map(|(n, address)| (n, canonical(address))),
+            Some((datagram, address)) = incoming.recv() => {
+                let n = datagram.len().min(buffer.len());
+                buffer[..n].copy_from_slice(&datagram[..n]);
+                Ok((n, address))
+            }
+        }
+    }
+
+    /// Send a datagram to a client, on its TCP connection if it has one, otherwise as a UDP datagram.
+    pub async fn send_to(&self, datagram: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let frames = self.connections.lock().unwrap().get(&address).map(|connection| connection.frames.clone());
+        match frames {
+            Some(frames) => match frames.try_send(datagram.to_vec()) {
+                Ok(_) => Ok(datagram.len()),
+                Err(_) => {
+                    // A client that can't keep up or went away is disconnected
+                    if let Some(connection) = self.connections.lock().unwrap().remove(&address) {
+                        connection.close();
+                    }
+                    Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed"))
+                }
+            },
+            None => self.udp.send_to(datagram, self.udp_address(address)).await,
+        }
+    }
+
+    /// Tell whether a client is connected with TCP.
+    pub fn is_stream(&self, address: &str) -> bool {
+        match address.parse::<SocketAddr>() {
+            Ok(address) => self.connections.lock().unwrap().contains_key(&address),
+            Err(_) => false,
+        }
+    }
+
+    /// Close the TCP connection of a client, if any, once the frames already queued have been written.
+    pub fn close(&self, address: &str) {
+        if let Ok(address) = address.parse::<SocketAddr>() {
+            if let Some(connection) = self.connections.lock().unwrap().remove(&address) {
+                connection.close();
+            }
+        }
+    }
+
+    /// Close all the TCP connections.
+    pub fn shutdown(&self) {
+        for (_, connection) in self.connections.lock().unwrap().drain() {
+            connection.close();
+        }
+    }
+}
+
+/// BatchSender trait is implemented by the sockets the server sends batches of datagrams to its clients on, see pacing::send_paced.
+pub trait BatchSender {
+    /// Send a batch of datagrams to a client, returning the bytes sent.
+    async fn send_batch_to(&self, datagrams: &[&[u8]], address: SocketAddr) -> io::Result<usize>;
+}
+
+impl BatchSender for ServerTransport {
+    // On UDP the batch is sent with a single system call where possible
+    async fn send_batch_to(&self, datagrams: &[&[u8]], address: SocketAddr) -> io::Result<usize> {
+        let connected = self.connections.lock().unwrap().contains_key(&address);
+        if !connected {
+            return self.udp.send_batch(datagrams, self.udp_address(address)).await;
+        }
+        let mut sent = 0;
+        for datagram in datagrams {
+            sent += self.send_to(datagram, address).await?;
+        }
+        Ok(sent)
+    }
+}
@@ -0,0 +1,92 @@
+use crate::crypto;
+use crate::fec;
+use crate::protocol::{HEADER_SIZE, MAX_DATAGRAM_SIZE};
+use crate::rtp::{TS_PACKET_SIZE, TS_SYNC_BYTE};
+
+/// This module cuts the MPEG-TS stream read from the encoder in the chunks carried by the data packets of the StreamingServer.
+/// Chunks are made of whole TS packets, so that a lost datagram damages only the TS packets it carries instead of cutting two of them in half.
+/// Each chunk carries as many TS packets as fit in a datagram of the configured MTU, once the IP and UDP headers, the header of the protocol,
+/// the encryption overhead and the header of the FEC parity payloads are accounted for: with the default MTU of 1500 bytes this is 7 TS packets, 1316 bytes.
+/// Whatever the MTU, the datagrams never exceed protocol::MAX_DATAGRAM_SIZE, the size of the buffers they are received in.
+/// The size does not depend on the PIN, so that a relay cuts the stream like its upstream server.
+
+pub const DEFAULT_MTU: u16 = 1500;
+/// Smallest MTU accepted in the configuration, the one every IPv4 host must accept.
+pub const MIN_MTU: u16 = 576;
+// Headers of IPv6 (40 bytes, larger than the ones of IPv4) and UDP (8 bytes)
+const IP_UDP_OVERHEAD: usize = 48;
+// Bytes of a data or parity packet besides its chunk
+const PACKET_OVERHEAD: usize = HEADER_SIZE + crypto::SEAL_OVERHEAD + fec::PARITY_HEADER_SIZE;
+
+/// Return the size of the chunks fitting in a datagram of the given MTU, always at least one TS packet.
+/// Larger MTUs (jumbo frames) are capped, so that the packets fit in MAX_DATAGRAM_SIZE.
+pub fn chunk_size(mtu: u16) -> usize {
+    let room = (mtu as usize).saturating_sub(IP_UDP_OVERHEAD + PACKET_OVERHEAD).min(MAX_DATAGRAM_SIZE - PACKET_OVERHEAD);
+    (room / TS_PACKET_SIZE).max(1) * TS_PACKET_SIZE
+}
+
+/// The TsChunker collects the bytes of the stream and returns them in chunks of whole TS packets.
+/// The bytes that don't start a TS packet are skipped, so that the chunks are aligned even if the stream is not.
+pub struct TsChunker {
+    pending: Vec<u8>,
+    chunk: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl TsChunker {
+    pub fn new(chunk_size: usize) -> Self {
+        TsChunker {
+            pending: Vec::new(),
+            chunk: Vec::with_capacity(chunk_size),
+            chunk_size,
+        }
+    }
+
+    /// Append the bytes read from the stream, returning the chunks completed.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(bytes);
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while self.pending.len() - offset >= TS_PACKET_SIZE {
+            // Resynchronize on the next sync byte
+            if self.pending[offset] != TS_SYNC_BYTE {
+                offset = self.pending[offset..].iter().position(|byte| *byte == TS_SYNC_BYTE).map_or(self.pending.len(), |position| offset + position);
+                continue;
+            }
+            self.chunk.extend_from_slice(&self.pending[offset..offset + TS_PACKET_SIZE]);
+            offset += TS_PACKET_SIZE;
+            if self.chunk.len() >= self.chunk_size {
+                chunks.push(std::mem::replace(&mut self.chunk, Vec::with_capacity(self.chunk_size)));
+            }
+        }
+        self.pending.drain(..offset);
+        chunks
+    }
+
+    /// Return the TS packets collected so far as a shorter chunk, if any, so that they don't wait for the following bytes of the stream.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.chunk.is_empty() {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.chunk, Vec::with_capacity(self.chunk_size)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_fit_the_mtu_and_the_receive_buffers() {
+        assert_eq!(chunk_size(MIN_MTU), 2 * TS_PACKET_SIZE);
+        assert_eq!(chunk_size(DEFAULT_MTU), 7 * TS_PACKET_SIZE);
+        assert_eq!(chunk_size(9000), 10 * TS_PACKET_SIZE);
+        for mtu in [MIN_MTU, DEFAULT_MTU] {
+            assert!(IP_UDP_OVERHEAD + PACKET_OVERHEAD + chunk_size(mtu) <= mtu as usize);
+        }
+        // A parity packet carries the largest payload, a chunk plus the header of the parity payload
+        for mtu in [2140, 9000, u16::MAX] {
+            assert!(PACKET_OVERHEAD + chunk_size(mtu) <= MAX_DATAGRAM_SIZE);
+        }
+    }
+}
@@ -1,14 +1,13 @@
 #[cfg(not(target_os = "windows"))]
 use pnet::datalink;
-#[cfg(not(target_os = "windows"))]
-use ipnetwork::IpNetwork;
 #[cfg(target_os = "windows")]
 use ipconfig::{get_adapters, OperStatus};
 #[cfg(target_os = "windows")]
 use if_addrs::{IfAddr, get_if_addrs};
 #[cfg(target_os = "windows")]
 use ipnet::Ipv4Net;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use ipnetwork::IpNetwork;
 
 #[cfg(target_os = "windows")]
 use std::ptr::null_mut;
@@ -30,36 +29,80 @@ use std::io::{BufRead, BufReader, Write};
 use std::env;
 use std::path::{Path, PathBuf};
 use crate::streaming_server::CropArea;
+use crate::rate_control::{EncoderTarget, TARGET_LADDER};
+use crate::client_queue::{OverflowPolicy, DEFAULT_QUEUE_SIZE};
+use crate::ts_chunker::{DEFAULT_MTU, MIN_MTU};
+use crate::join_cache::{self, DEFAULT_JOIN_CACHE_SIZE};
 use dirs::download_dir;
 use screenshots::Screen;
 use crate::error_banner::InputError;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
 
 pub const HOTKEYS_CONFIG_PATH : &str = "../config/hotkeys.txt";
 pub const SAVE_DIRECTORY_CONFIG_PATH : &str = "../config/save_path.txt";
+pub const NETWORK_CONFIG_PATH : &str = "../config/network.txt";
+
+pub const DEFAULT_SERVER_PORT: u16 = 8080;
+pub const DEFAULT_CLIENT_PORT: u16 = 0;
+pub const DEFAULT_DISCOVERY_PORT: u16 = 8081;
+
+// Parse an IP address typed by the user or stored in the list of streamers, IPv4 or IPv6, optionally in brackets.
+// IPv6 link-local addresses can carry the scope id, the interface they are reached through, as "fe80::1%eth0" or "fe80::1%2".
+// Return the address with its scope id, 0 if there is none.
+pub fn parse_ip(text: &str) -> Option<(IpAddr, u32)> {
+    let text = text.trim();
+    let text = text.strip_prefix('[').and_then(|text| text.strip_suffix(']')).unwrap_or(text);
+    let (ip, scope) = match text.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (text, None),
+    };
+    let ip = ip.parse::<IpAddr>().ok()?;
+    let scope_id = match (ip, scope) {
+        (_, None) => 0,
+        (IpAddr::V6(_), Some(scope)) => scope.parse().ok().or_else(|| interface_index(scope))?,
+        (IpAddr::V4(_), Some(_)) => return None,
+    };
+    Some((ip, scope_id))
+}
 
-// Check if the IP address is in the same LAN as the local machine
-pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
-    let target_ip: Ipv4Addr;
-    match ip_to_check.parse(){
-        Ok(ip) => {
-            target_ip = ip;
-        },
-        Err(_) => {
-            return Err(InputError::NotAnIp);
-        }
-    }
+// Return the address of the socket with the given IP, parsed with parse_ip, and port.
+pub fn socket_address(ip: &str, port: u16) -> Option<SocketAddr> {
+    let (ip, scope_id) = parse_ip(ip)?;
+    Some(match ip {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)),
+    })
+}
+
+// Return the index of the network interface with the given name, used as scope id.
+#[cfg(not(target_os = "windows"))]
+fn interface_index(name: &str) -> Option<u32> {
+    datalink::interfaces().into_iter().find(|interface| interface.name == name).map(|interface| interface.index)
+}
+
+// On Windows scope ids are given as numbers.
+#[cfg(target_os = "windows")]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+// Check if the IP address, IPv4 or IPv6, is in the same LAN as the local machine.
+// A link-local IPv6 address with a scope id must be on the subnet of that interface.
+fn is_ip_in_lan(target_ip: IpAddr, scope_id: u32) -> bool {
 
     #[cfg(not(target_os = "windows"))]
     {
         let interfaces = datalink::interfaces();
 
         for interface in interfaces {
+            if scope_id != 0 && interface.index != scope_id {
+                continue;
+            }
             for ip in interface.ips {
-                // Controlla solo gli indirizzi IPv4
-                if let IpNetwork::V4(network) = ip {
-                    if network.contains(target_ip) {
-                        return Ok(()); // L'indirizzo appartiene alla subnet
-                    }
+                // The networks of the other family never contain the address
+                if ip.contains(target_ip) {
+                    return true; // L'indirizzo appartiene alla subnet
                 }
             }
         }
@@ -83,7 +126,7 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
             );
     
             if ret_code != ERROR_BUFFER_OVERFLOW {
-                return Err(InputError::NotInSameLan);
+                return false;
             }
     
             // Alloca il buffer necessario
@@ -100,7 +143,7 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
             );
     
             if ret_code != 0 {
-                return Err(InputError::NotInSameLan);
+                return false;
             }
     
             // Itera sugli adattatori di rete
@@ -113,17 +156,30 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
                 while !current_address.is_null() {
                     let address = &*current_address;
                     let sockaddr = &*address.Address.lpSockaddr;
-                    if sockaddr.sa_family == winapi::shared::ws2def::AF_INET as u16 {
-                        let ip_bytes = (*(&sockaddr.sa_data[2] as *const _ as *const [u8; 4])).clone();
-                        let local_ip = Ipv4Addr::from(ip_bytes);
-                    
-                        let prefix_length = address.OnLinkPrefixLength;
-                        let netmask = prefix_length_to_netmask(prefix_length);
-    
-                        let (network_start, network_end) = calculate_subnet_range(local_ip, netmask);
-                        if network_start <= target_ip && target_ip <= network_end {
-                            return Ok(()); // L'indirizzo appartiene alla stessa subnet
+                    match target_ip {
+                        IpAddr::V4(target_ip) if sockaddr.sa_family == winapi::shared::ws2def::AF_INET as u16 => {
+                            let ip_bytes = (*(&sockaddr.sa_data[2] as *const _ as *const [u8; 4])).clone();
+                            let local_ip = Ipv4Addr::from(ip_bytes);
+
+                            let prefix_length = address.OnLinkPrefixLength;
+                            let netmask = prefix_length_to_netmask(prefix_length);
+
+                            let (network_start, network_end) = calculate_subnet_range(local_ip, netmask);
+                            if network_start <= target_ip && target_ip <= network_end {
+                                return true; // L'indirizzo appartiene alla stessa subnet
+                            }
                         }
+                        IpAddr::V6(target_ip) if sockaddr.sa_family == winapi::shared::ws2def::AF_INET6 as u16
+                            && (scope_id == 0 || scope_id == adapter.Ipv6IfIndex) => {
+                            // The address of a SOCKADDR_IN6 follows the family, the port and the flow info
+                            let ip_bytes = *((address.Address.lpSockaddr as *const u8).add(8) as *const [u8; 16]);
+                            let prefix_length = (address.OnLinkPrefixLength as u32).min(128);
+                            let netmask = u128::MAX.checked_shl(128 - prefix_length).unwrap_or(0);
+                            if u128::from_be_bytes(ip_bytes) & netmask == u128::from(target_ip) & netmask {
+                                return true; // L'indirizzo appartiene alla stessa subnet
+                            }
+                        }
+                        _ => {}
                     }
                     current_address = address.Next;
                 }
@@ -137,7 +193,7 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
                 ifaces
             }
             Err(_) => {
-                return Err(InputError::NotInSameLan);
+                return false;
             }
         };
 
@@ -149,7 +205,7 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
                 let network = calculate_subnet_range(local_ip, netmask);
 
                 if network.0 <= target_ip && target_ip <= network.1 {
-                    return Ok(());
+                    return true;
                 } 
             }
         }
@@ -168,7 +224,7 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
                             let subnet = Ipv4Net::new(*gateway_ip, 16).expect("Subnet non valida");
 
                             if subnet.contains(&target_ip) {
-                                return Ok(());
+                                return true;
                             }
                         }
                     }
@@ -177,7 +233,34 @@ pub fn is_ip_in_lan(ip_to_check: &str) -> Result<(), InputError> {
         }
         */
     }
-    Err(InputError::NotInSameLan)
+    false
+}
+
+// Check that a server can be connected to: any address if no allowlist of routed subnets is configured,
+// otherwise only the addresses in the LAN of the local machine or in one of the allowed subnets.
+pub fn check_allowed_address(address: SocketAddr, config: &NetworkConfig) -> Result<(), InputError> {
+    let subnets = match config.allowed_subnets.as_ref() {
+        Some(subnets) => subnets,
+        None => return Ok(()),
+    };
+    let scope_id = match address {
+        SocketAddr::V6(address) => address.scope_id(),
+        SocketAddr::V4(_) => 0,
+    };
+    if is_ip_in_lan(address.ip(), scope_id) || subnets.iter().any(|subnet| subnet.contains(address.ip())) {
+        Ok(())
+    } else {
+        Err(InputError::NotInAllowedSubnet)
+    }
+}
+
+// Check that a value is an IP address accepted by parse_ip or a well formed host name, without resolving it.
+pub fn is_valid_host(text: &str) -> bool {
+    let text = text.trim();
+    parse_ip(text).is_some() || (text.len() <= 253 && text.trim_end_matches('.').split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }))
 }
 
 // Compute the range of IP addresses in the same subnet
@@ -192,6 +275,20 @@ fn calculate_subnet_range(ip: Ipv4Addr, netmask: Ipv4Addr) -> (Ipv4Addr, Ipv4Add
     (Ipv4Addr::from(network_start), Ipv4Addr::from(broadcast))
 }
 
+// Generate a random 6 digits PIN used to protect a casting session
+pub fn generate_pin() -> String {
+    format!("{:06}", uuid::Uuid::new_v4().as_u128() % 1_000_000)
+}
+
+// Characters of a session key: digits and upper case letters, without the ones easily mistaken for others (0, 1, I, O)
+const KEY_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+// Generate a session key of 20 random characters in groups of 4, e.g. "7KQ2-M9XD-...", about 100 bits instead of the 20 of a PIN
+pub fn generate_key() -> String {
+    let characters = (0..20).map(|_| KEY_ALPHABET[OsRng.next_u32() as usize % KEY_ALPHABET.len()] as char).collect::<Vec<char>>();
+    characters.chunks(4).map(|group| group.iter().collect::<String>()).collect::<Vec<String>>().join("-")
+}
+
 // Get the path of the project's source directory
 pub fn get_project_src_path() -> PathBuf {
     let exe_path = env::current_exe().expect("Failed to get current executable path");
@@ -229,18 +326,77 @@ pub fn count_screens() -> usize {
     screens.len()
 }
 
-// Return the correct FFmpeg command based on the operating system, screen index, and crop area
-pub fn get_ffmpeg_command(screen_index:usize, crop: Option<CropArea>) -> String {
+// Returns the width and height of the screen with the given 1-based index, if connected
+pub fn screen_resolution(index: usize) -> Option<(u32, u32)> {
+    let screens = Screen::all().ok()?;
+    let screen = screens.get(index.checked_sub(1)?)?;
+    Some((screen.display_info.width, screen.display_info.height))
+}
+
+// Returns the name of the machine, used to announce the casting sessions on the LAN
+pub fn host_name() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+// Return the correct FFmpeg command based on the operating system, screen index, crop area and encoder target.
+// The video is encoded with H.264 at the bitrate and framerate of the target, scaled down to its height if lower than the captured one.
+pub fn get_ffmpeg_command(screen_index:usize, crop: Option<CropArea>, target: EncoderTarget) -> String {
+    let (input, crop_filter) = capture_input(screen_index, crop, target.framerate);
+    format!("{input} -vf {crop_filter}{} {} pipe:1", scale_filter(target, source_height(screen_index, crop)), encoder_options(target))
+}
+
+// Return the FFmpeg command encoding the screen once per layer of a simulcast session, see the simulcast module.
+// The captured video is split in a copy per layer, each one scaled and encoded with the target of its layer and written to its output:
+// the filter graph is written without spaces, since the command is split on them.
+pub fn get_simulcast_ffmpeg_command(screen_index: usize, crop: Option<CropArea>, targets: &[EncoderTarget], outputs: &[String]) -> String {
+    let framerate = targets.iter().map(|target| target.framerate).max().unwrap_or(TARGET_LADDER[0].framerate);
+    let (input, crop_filter) = capture_input(screen_index, crop, framerate);
+    let source_height = source_height(screen_index, crop);
+    let splits = (0..targets.len()).map(|i| format!("[s{i}]")).collect::<String>();
+    let scales = targets.iter().enumerate().map(|(i, target)| format!(";[s{i}]{}[v{i}]", scale_filter(*target, source_height))).collect::<String>();
+    let encoders = targets.iter().zip(outputs).enumerate().map(|(i, (target, output))| {
+        let rate = if target.framerate < framerate { format!(" -r {}", target.framerate) } else { String::new() };
+        format!(" -map [v{i}]{rate} {} {output}", encoder_options(*target))
+    }).collect::<String>();
+    format!("{input} -filter_complex [0:v]{crop_filter}split={}{splits}{scales}{encoders}", targets.len())
+}
+
+// Return the height of the captured video, if known.
+fn source_height(screen_index: usize, crop: Option<CropArea>) -> Option<u32> {
+    match crop {
+        Some(crop) => Some(crop.height),
+        None => screen_resolution(screen_index).map(|(_, height)| height),
+    }
+}
+
+// Return the filter scaling the video to the height of the target.
+fn scale_filter(target: EncoderTarget, source_height: Option<u32>) -> String {
+    // libx264 needs even dimensions, which are also kept when the video is not scaled
+    match (target.height, source_height) {
+        (Some(height), Some(source_height)) if height < source_height => format!("scale=-2:{height}"),
+        _ => "scale=trunc(iw/2)*2:trunc(ih/2)*2".to_string(),
+    }
+}
+
+// Return the options encoding the video with the target, muxed in MPEG-TS.
+fn encoder_options(target: EncoderTarget) -> String {
+    format!("-codec:v libx264 -preset veryfast -tune zerolatency -pix_fmt yuv420p -b:v {0}k -maxrate {0}k -bufsize {0}k -g {1} -f mpegts",
+        target.bitrate_kbps, target.framerate)
+}
+
+// Return the options capturing the screen at the given framerate, based on the operating system,
+// with the filter cropping the captured video followed by a comma, empty if the capture is already cropped.
+fn capture_input(screen_index: usize, crop: Option<CropArea>, framerate: u32) -> (String, String) {
 
     #[cfg(target_os = "macos")]
     {
+        let input = format!("-f avfoundation -re -video_size 1280x720 -framerate {} -capture_cursor 1 -i {}:", framerate, screen_index);
         match crop {
             Some(crop) => {
-                format!("-f avfoundation -re -video_size 1280x720 -capture_cursor 1 -i {}: -vf crop={}:{}:{}:{} -tune zerolatency -f mpegts -codec:v libx264 -preset slow -crf 28 -pix_fmt yuv420p pipe:1", screen_index, crop.width, crop.height, crop.x_offset, crop.y_offset)
-
+                (input, format!("crop={}:{}:{}:{},", crop.width, crop.height, crop.x_offset, crop.y_offset))
             }
             None => {
-                format!("-f avfoundation -re -video_size 1280x720 -capture_cursor 1 -i {}: -tune zerolatency -f mpegts -codec:v libx264 -preset slow -crf 28 -pix_fmt yuv420p pipe:1", screen_index)
+                (input, String::new())
             }
         }
     }
@@ -250,10 +406,10 @@ pub fn get_ffmpeg_command(screen_index:usize, crop: Option<CropArea>) -> String
         let (width, height, top_x, top_y) = compute_window_size(screen_index).unwrap();
         match crop {
             Some(crop) => {
-                format!("-f gdigrab -framerate 30 -offset_x {} -offset_y {} -video_size {}x{} -i desktop -f mpegts pipe:1", crop.x_offset, crop.y_offset, crop.width, crop.height)
+                (format!("-f gdigrab -framerate {} -offset_x {} -offset_y {} -video_size {}x{} -i desktop", framerate, crop.x_offset, crop.y_offset, crop.width, crop.height), String::new())
             }
             None => {
-                format!("-f gdigrab -framerate 30 -offset_x {} -offset_y {} -video_size {}x{} -i desktop -f mpegts pipe:1", top_x, top_y, width, height)
+                (format!("-f gdigrab -framerate {} -offset_x {} -offset_y {} -video_size {}x{} -i desktop", framerate, top_x, top_y, width, height), String::new())
             }
         }
     }
@@ -263,10 +419,10 @@ pub fn get_ffmpeg_command(screen_index:usize, crop: Option<CropArea>) -> String
         let (width, height, top_x, top_y) = compute_window_size(screen_index).unwrap();
         match crop {
             Some(crop) => {
-                format!("-f x11grab -framerate 30 -video_size {}x{} -i :0.0+{},{} -f mpegts pipe:1", crop.width, crop.height, crop.x_offset, crop.y_offset)
+                (format!("-f x11grab -framerate {} -video_size {}x{} -i :0.0+{},{}", framerate, crop.width, crop.height, crop.x_offset, crop.y_offset), String::new())
             }
             None => {
-                format!("-f x11grab -framerate 30 -video_size {}x{} -i :0.0+{},{} -f mpegts pipe:1", width, height, top_x, top_y)
+                (format!("-f x11grab -framerate {} -video_size {}x{} -i :0.0+{},{}", framerate, width, height, top_x, top_y), String::new())
             }
         }
     }
@@ -405,4 +561,156 @@ pub fn save_directory(new_directory: &str) -> io::Result<()> {
     file.write_all(b"\n")?;
 
     Ok(())
-}
\ No newline at end of file
+}
+/// NetworkConfig struct contains the address and the ports the StreamingServer and the StreamingClient bind to.
+/// - bind_address: the address of the interface to use, None to listen on all the interfaces (IPv4 and IPv6)
+///   and to reach the servers from the local IP of the machine
+/// - server_port: the port the server listens on and the client connects to
+/// - client_port: the port the client receives the stream on, 0 to let the OS pick a free one
+/// - discovery_port: the port the beacons announcing the casting sessions are sent to
+/// - multicast_group: the group address and port the stream is sent to in multicast mode, None to send it to each client
+/// - rtsp_port: the port the stream is also served on over RTSP, None to disable it
+/// - http_port: the port the browser viewer is served on, None to disable it
+/// - upload_limit_kbps: the total upload budget of the server, None for no limit
+/// - client_limit_kbps: the rate each client is sent at most, None for no limit
+/// - queue_size: the number of packets queued for each client before the overflow_policy is applied
+/// - overflow_policy: what to do when the queue of a client is full
+/// - mtu: the MTU of the network, the data packets are sized to fit in it up to protocol::MAX_DATAGRAM_SIZE (see ts_chunker::chunk_size)
+/// - join_cache_size: the number of packets cached for the clients joining the session, 0 to disable the cache,
+///   at most three quarters of queue_size (see join_cache::capacity)
+/// - allowed_subnets: the subnets, besides the LAN, the servers can be connected to (e.g. "10.1.0.0/16,fd00:1::/48"),
+///   None to allow any address
+/// - simulcast_layers: the heights of the quality layers the stream is encoded in (e.g. "1080,720,360"), see the simulcast module,
+///   None to encode a single layer adapted by the rate controller
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub bind_address: Option<IpAddr>,
+    pub server_port: u16,
+    pub client_port: u16,
+    pub discovery_port: u16,
+    pub multicast_group: Option<SocketAddr>,
+    pub rtsp_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub upload_limit_kbps: Option<u32>,
+    pub client_limit_kbps: Option<u32>,
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub mtu: u16,
+    pub join_cache_size: usize,
+    pub allowed_subnets: Option<Vec<IpNetwork>>,
+    pub simulcast_layers: Option<Vec<u32>>,
+}
+
+impl NetworkConfig {
+    /// Return the address to bind the sockets to, the local IP of the machine (see local_ip) if none has been configured.
+    pub fn bind_ip(&self) -> IpAddr {
+        self.bind_address.unwrap_or_else(local_ip)
+    }
+
+    /// Return the address the server listens on: the configured one, or the unspecified IPv6 address,
+    /// which the server binds dual-stack to be reached by IPv4 and IPv6 clients alike.
+    pub fn listen_address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)), self.server_port)
+    }
+
+    /// Return the address to bind the sockets reaching the given destination to: the configured one if it is of the same family,
+    /// otherwise the local IPv4 address of the machine (the unspecified one if it has none) for IPv4 destinations and the unspecified IPv6 address
+    /// for IPv6 ones, letting the OS pick the source address and, for link-local destinations, the interface of their scope id.
+    pub fn bind_ip_for(&self, destination: IpAddr) -> IpAddr {
+        match self.bind_address {
+            Some(ip) if ip.is_ipv4() == destination.is_ipv4() => ip,
+            _ if destination.is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        }
+    }
+}
+
+/// Return the local IP address of the machine: its IPv4 address, the IPv6 one on hosts without IPv4,
+/// or the unspecified IPv6 address if none can be found.
+pub fn local_ip() -> IpAddr {
+    local_ip_address::local_ip()
+        .or_else(|_| local_ip_address::local_ipv6())
+        .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+}
+
+/// Return the address a server listening on the given one can be reached at: the local IP of the machine if it listens on all the interfaces.
+pub fn advertised_address(address: SocketAddr) -> SocketAddr {
+    match address.ip().is_unspecified() {
+        true => SocketAddr::new(local_ip(), address.port()),
+        false => address,
+    }
+}
+
+/// Read the network configuration from the configuration file, made up of "key=value" lines
+/// (bind_address, server_port, client_port, discovery_port, multicast_group, rtsp_port, http_port,
+/// upload_limit_kbps, client_limit_kbps, queue_size, overflow_policy, mtu, join_cache_size, allowed_subnets, simulcast_layers). Missing or invalid values are replaced by the defaults.
+/// Each value can be overridden with an environment variable (SCREEN_CASTER_BIND, SCREEN_CASTER_SERVER_PORT,
+/// SCREEN_CASTER_CLIENT_PORT, SCREEN_CASTER_DISCOVERY_PORT, SCREEN_CASTER_MULTICAST, SCREEN_CASTER_RTSP_PORT,
+/// SCREEN_CASTER_HTTP_PORT, SCREEN_CASTER_UPLOAD_LIMIT, SCREEN_CASTER_CLIENT_LIMIT,
+/// SCREEN_CASTER_QUEUE_SIZE, SCREEN_CASTER_OVERFLOW_POLICY, SCREEN_CASTER_MTU,
+/// SCREEN_CASTER_JOIN_CACHE, SCREEN_CASTER_ALLOWED_SUBNETS, SCREEN_CASTER_SIMULCAST), which makes it possible to run more instances on the same machine.
+pub fn read_network_config() -> NetworkConfig {
+    let mut config = NetworkConfig {
+        bind_address: None,
+        server_port: DEFAULT_SERVER_PORT,
+        client_port: DEFAULT_CLIENT_PORT,
+        discovery_port: DEFAULT_DISCOVERY_PORT,
+        multicast_group: None,
+        rtsp_port: None,
+        http_port: None,
+        upload_limit_kbps: None,
+        client_limit_kbps: None,
+        queue_size: DEFAULT_QUEUE_SIZE,
+        overflow_policy: OverflowPolicy::DropUntilKeyframe,
+        mtu: DEFAULT_MTU,
+        join_cache_size: DEFAULT_JOIN_CACHE_SIZE,
+        allowed_subnets: None,
+        simulcast_layers: None,
+    };
+
+    let mut values = Vec::new();
+    if let Ok(file) = File::open(NETWORK_CONFIG_PATH) {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            if let Some((key, value)) = line.split_once('=') {
+                values.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+    }
+    for (key, variable) in [("bind_address", "SCREEN_CASTER_BIND"), ("server_port", "SCREEN_CASTER_SERVER_PORT"), ("client_port", "SCREEN_CASTER_CLIENT_PORT"), ("discovery_port", "SCREEN_CASTER_DISCOVERY_PORT"), ("multicast_group", "SCREEN_CASTER_MULTICAST"), ("rtsp_port", "SCREEN_CASTER_RTSP_PORT"), ("http_port", "SCREEN_CASTER_HTTP_PORT"),
+        ("upload_limit_kbps", "SCREEN_CASTER_UPLOAD_LIMIT"), ("client_limit_kbps", "SCREEN_CASTER_CLIENT_LIMIT"),
+        ("queue_size", "SCREEN_CASTER_QUEUE_SIZE"), ("overflow_policy", "SCREEN_CASTER_OVERFLOW_POLICY"), ("mtu", "SCREEN_CASTER_MTU"),
+        ("join_cache_size", "SCREEN_CASTER_JOIN_CACHE"), ("allowed_subnets", "SCREEN_CASTER_ALLOWED_SUBNETS"),
+        ("simulcast_layers", "SCREEN_CASTER_SIMULCAST")] {
+        if let Ok(value) = env::var(variable) {
+            values.push((key.to_string(), value.trim().to_string()));
+        }
+    }
+
+    for (key, value) in values {
+        match key.as_str() {
+            "bind_address" => config.bind_address = value.parse().ok(),
+            "server_port" => config.server_port = value.parse().unwrap_or(DEFAULT_SERVER_PORT),
+            "client_port" => config.client_port = value.parse().unwrap_or(DEFAULT_CLIENT_PORT),
+            "discovery_port" => config.discovery_port = value.parse().unwrap_or(DEFAULT_DISCOVERY_PORT),
+            "multicast_group" => config.multicast_group = value.parse::<SocketAddr>().ok().filter(|group| group.ip().is_multicast()),
+            "rtsp_port" => config.rtsp_port = value.parse().ok(),
+            "http_port" => config.http_port = value.parse().ok(),
+            "upload_limit_kbps" => config.upload_limit_kbps = value.parse().ok().filter(|limit| *limit > 0),
+            "client_limit_kbps" => config.client_limit_kbps = value.parse().ok().filter(|limit| *limit > 0),
+            "queue_size" => config.queue_size = value.parse().ok().filter(|size| *size > 0).unwrap_or(DEFAULT_QUEUE_SIZE),
+            "overflow_policy" => config.overflow_policy = value.parse().unwrap_or(OverflowPolicy::DropUntilKeyframe),
+            "mtu" => config.mtu = value.parse().ok().filter(|mtu| *mtu >= MIN_MTU).unwrap_or(DEFAULT_MTU),
+            "join_cache_size" => config.join_cache_size = value.parse().unwrap_or(DEFAULT_JOIN_CACHE_SIZE),
+            // An empty value leaves any address allowed, the invalid subnets are skipped
+            "allowed_subnets" => config.allowed_subnets = Some(value.split(',').filter_map(|subnet| subnet.trim().parse().ok()).collect::<Vec<IpNetwork>>())
+                .filter(|subnets| !subnets.is_empty()),
+            // The same goes for the layers, the invalid heights are skipped
+            "simulcast_layers" => config.simulcast_layers = Some(value.split(',').filter_map(|height| height.trim().parse().ok()).filter(|height| *height > 0).collect::<Vec<u32>>())
+                .filter(|heights| !heights.is_empty()),
+            _ => {}
+        }
+    }
+    // The cached packets are queued to a new client on top of the ones published meanwhile, so the cache is validated against the queue
+    config.join_cache_size = join_cache::capacity(config.join_cache_size, config.queue_size);
+    config
+}
@@ -1,4 +1,4 @@
-use std::{env, path::PathBuf, process::{Command, Stdio}};
+use std::{env, fs::File, io, path::PathBuf, process::{Command, Stdio}};
 use ffmpeg_sidecar::{
     download::{check_latest_version, download_ffmpeg_package, unpack_ffmpeg},
     version::ffmpeg_version_with_path,
@@ -46,6 +46,26 @@ pub fn check_ffmpeg() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Pinned release of hls.js, which plays the HLS output of screen_caster in the browsers without native support.
+const HLS_JS_URL: &str = "https://cdn.jsdelivr.net/npm/hls.js@1.5.17/dist/hls.min.js";
+
+/// Download hls.js next to the viewer page, where the HTTP server of screen_caster serves it from.
+pub fn check_hls_js() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Checking hls.js...");
+    let path = assets_dir()?.join("hls.min.js");
+    if path.exists() {
+        println!("hls.js is already installed!");
+    } else {
+        println!("Downloading from: {:?}", HLS_JS_URL);
+        let response = ureq::get(HLS_JS_URL).call()?;
+        let mut file = File::create(&path)?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+    }
+
+    println!("Done!");
+    Ok(())
+}
+
 fn ffmpeg_download_url_custom() -> Result<&'static str, &'static str> {
     if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
         Ok("https://www.gyan.dev/ffmpeg/builds/ffmpeg-release-essentials.zip")
@@ -87,6 +107,21 @@ pub fn ffmpeg_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
     Ok(temp_path.join("screen_caster/target/release/"))
 }
 
+/// The assets directory of the project, holding the viewer page served by screen_caster.
+pub fn assets_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let current_exe = env::current_exe().map_err(|err| {
+        format!("Failed to get the path of the current executable: {}", err)
+    })?;
+
+    let temp_path = current_exe
+        .parent().ok_or("Failed to navigate to parent directory.")?
+        .parent().ok_or("Failed to navigate to parent directory.")?
+        .parent().ok_or("Failed to navigate to parent directory.")?
+        .parent().ok_or("Failed to navigate to parent directory.")?;
+
+    Ok(temp_path.join("assets"))
+}
+
 /// The (expected) path to an FFmpeg binary adjacent to the screen_caster binary.
 pub fn ffmpeg_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
     let mut path = ffmpeg_dir()?.join("ffmpeg");
@@ -104,4 +139,7 @@ fn main() {
     if let Err(err) = check_ffmpeg() {
         eprintln!("Error: {}", err);
     }
+    if let Err(err) = check_hls_js() {
+        eprintln!("Error: {}", err);
+    }
 }